@@ -0,0 +1,14 @@
+CREATE TABLE login_attempts (
+    key VARCHAR PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure TIMESTAMP WITH TIME ZONE NOT NULL,
+    locked_until TIMESTAMP WITH TIME ZONE
+);
+
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    event VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    detail VARCHAR NOT NULL DEFAULT '',
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
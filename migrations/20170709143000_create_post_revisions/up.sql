@@ -0,0 +1,15 @@
+CREATE TABLE post_revisions (
+    id SERIAL PRIMARY KEY,
+    post_id INTEGER REFERENCES posts (id) ON DELETE CASCADE NOT NULL,
+    author_id INTEGER REFERENCES users (id) NOT NULL,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    title VARCHAR NOT NULL,
+    markdown_content VARCHAR NOT NULL,
+    tags TEXT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX post_revisions_post_id_idx ON post_revisions (post_id);
+
+-- Seed the history with the current state of every post.
+INSERT INTO post_revisions (post_id, author_id, created_on, title, markdown_content, tags)
+    SELECT id, owner_id, created_on, title, markdown_content, tags FROM posts;
@@ -0,0 +1 @@
+DROP TABLE post_drafts;
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE post_drafts (
+    id SERIAL PRIMARY KEY,
+    post_id INTEGER REFERENCES posts (id) ON DELETE CASCADE,
+    owner_id INTEGER REFERENCES users (id) NOT NULL,
+    title VARCHAR NOT NULL,
+    markdown_content VARCHAR NOT NULL,
+    tags VARCHAR NOT NULL DEFAULT '',
+    updated_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- One draft per post and author, and one for a not yet created post.
+CREATE UNIQUE INDEX post_drafts_owner_post_idx ON post_drafts (owner_id, COALESCE(post_id, 0));
@@ -0,0 +1,3 @@
+ALTER TABLE posts ADD COLUMN publish_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX posts_scheduled_idx ON posts (publish_at) WHERE NOT published;
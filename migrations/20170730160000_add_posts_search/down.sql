@@ -0,0 +1,5 @@
+DROP INDEX posts_search_vector_idx;
+DROP TRIGGER posts_search_vector_trigger ON posts;
+DROP FUNCTION posts_search_vector_update();
+ALTER TABLE posts DROP COLUMN search_vector;
+ALTER TABLE posts DROP COLUMN language;
@@ -0,0 +1,23 @@
+-- The text search configuration used for the post, e.g. 'english' or 'german'.
+ALTER TABLE posts ADD COLUMN language VARCHAR NOT NULL DEFAULT 'english'
+    CHECK (language::regconfig IS NOT NULL);
+ALTER TABLE posts ADD COLUMN search_vector TSVECTOR;
+
+CREATE FUNCTION posts_search_vector_update() RETURNS trigger AS $$
+BEGIN
+    NEW.search_vector :=
+        setweight(to_tsvector(NEW.language::regconfig, NEW.title), 'A') ||
+        setweight(to_tsvector(NEW.language::regconfig, array_to_string(NEW.tags, ' ')), 'B') ||
+        setweight(to_tsvector(NEW.language::regconfig, NEW.markdown_content), 'C');
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER posts_search_vector_trigger
+    BEFORE INSERT OR UPDATE OF title, markdown_content, tags, language ON posts
+    FOR EACH ROW EXECUTE PROCEDURE posts_search_vector_update();
+
+-- Fire the trigger once for existing posts.
+UPDATE posts SET title = title;
+
+CREATE INDEX posts_search_vector_idx ON posts USING GIN (search_vector);
@@ -0,0 +1,14 @@
+-- Lowercase and trim all tags, dropping empty and duplicate entries but
+-- keeping the original order.
+UPDATE posts SET tags = ARRAY(
+    SELECT t FROM (
+        SELECT lower(trim(tag)) AS t, min(i) AS i
+        FROM unnest(tags) WITH ORDINALITY AS u(tag, i)
+        WHERE trim(tag) <> ''
+        GROUP BY 1
+    ) normalized ORDER BY i
+);
+
+CREATE INDEX posts_tags_idx ON posts USING GIN (tags);
+
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,14 @@
+CREATE TABLE comments (
+    id SERIAL PRIMARY KEY,
+    post_id INTEGER REFERENCES posts (id) ON DELETE CASCADE NOT NULL,
+    parent_id INTEGER REFERENCES comments (id) ON DELETE CASCADE,
+    user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    author_name VARCHAR NOT NULL,
+    author_email VARCHAR,
+    markdown_content VARCHAR NOT NULL,
+    content VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'spam')),
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX comments_post_id_idx ON comments (post_id, status);
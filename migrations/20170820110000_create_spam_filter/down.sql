@@ -0,0 +1,4 @@
+ALTER TABLE comments DROP COLUMN trained_as;
+ALTER TABLE comments DROP COLUMN spam_score;
+DROP TABLE spam_corpus;
+DROP TABLE spam_tokens;
@@ -0,0 +1,18 @@
+-- Token counts for the naive Bayes spam classifier.
+CREATE TABLE spam_tokens (
+    token VARCHAR PRIMARY KEY,
+    spam_count INTEGER NOT NULL DEFAULT 0,
+    ham_count INTEGER NOT NULL DEFAULT 0
+);
+
+-- Number of trained documents per label.
+CREATE TABLE spam_corpus (
+    label VARCHAR PRIMARY KEY CHECK (label IN ('spam', 'ham')),
+    documents INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO spam_corpus (label, documents) VALUES ('spam', 0), ('ham', 0);
+
+ALTER TABLE comments ADD COLUMN spam_score REAL;
+-- The label the comment was last used to train the classifier with, if any.
+ALTER TABLE comments ADD COLUMN trained_as VARCHAR CHECK (trained_as IN ('spam', 'ham'));
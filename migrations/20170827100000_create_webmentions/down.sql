@@ -0,0 +1,2 @@
+DROP TABLE outgoing_webmentions;
+DROP TABLE webmentions;
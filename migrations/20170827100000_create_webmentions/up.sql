@@ -0,0 +1,27 @@
+-- Mentions of our posts received from other sites.
+CREATE TABLE webmentions (
+    id SERIAL PRIMARY KEY,
+    post_id INTEGER REFERENCES posts (id) ON DELETE CASCADE NOT NULL,
+    source VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'verified', 'rejected')),
+    title VARCHAR,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    verified_on TIMESTAMP WITH TIME ZONE,
+    UNIQUE (source, target)
+);
+
+-- Mentions of other sites that our posts link to, waiting to be sent.
+CREATE TABLE outgoing_webmentions (
+    id SERIAL PRIMARY KEY,
+    post_id INTEGER REFERENCES posts (id) ON DELETE CASCADE NOT NULL,
+    source VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'unsupported', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error VARCHAR,
+    next_attempt_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX outgoing_webmentions_pending_idx ON outgoing_webmentions (next_attempt_on) WHERE status = 'pending';
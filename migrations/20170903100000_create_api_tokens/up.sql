@@ -0,0 +1,11 @@
+-- Bearer tokens for API clients such as Micropub apps. Only a hash of the
+-- token is stored, the token itself is shown once when it is created.
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    scope VARCHAR NOT NULL,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_used_on TIMESTAMP WITH TIME ZONE
+);
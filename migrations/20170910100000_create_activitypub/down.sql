@@ -0,0 +1,5 @@
+ALTER TABLE comments DROP COLUMN author_url;
+ALTER TABLE comments DROP COLUMN remote_id;
+DROP TABLE deliveries;
+DROP TABLE followers;
+DROP TABLE actor_keys;
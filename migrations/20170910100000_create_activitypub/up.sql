@@ -0,0 +1,36 @@
+-- Key pairs users sign their ActivityPub deliveries with.
+CREATE TABLE actor_keys (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    public_key_pem TEXT NOT NULL,
+    private_key_pem TEXT NOT NULL,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- Remote actors following a user.
+CREATE TABLE followers (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    actor VARCHAR NOT NULL,
+    inbox VARCHAR NOT NULL,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    UNIQUE (user_id, actor)
+);
+
+-- Activities waiting to be delivered to remote inboxes.
+CREATE TABLE deliveries (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    inbox VARCHAR NOT NULL,
+    activity TEXT NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error VARCHAR,
+    next_attempt_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX deliveries_pending_idx ON deliveries (next_attempt_on) WHERE status = 'pending';
+
+-- Replies from remote servers are stored as comments.
+ALTER TABLE comments ADD COLUMN remote_id VARCHAR UNIQUE;
+ALTER TABLE comments ADD COLUMN author_url VARCHAR;
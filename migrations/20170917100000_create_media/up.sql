@@ -0,0 +1,16 @@
+-- The media library. The files themselves are kept in the media storage
+-- under `name`, which is derived from their checksum, so a file that is
+-- uploaded twice is only stored once.
+CREATE TABLE media (
+    id SERIAL PRIMARY KEY,
+    owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR NOT NULL,
+    original_name VARCHAR,
+    content_type VARCHAR NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    checksum VARCHAR(64) NOT NULL,
+    created_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    UNIQUE (owner_id, checksum)
+);
+
+CREATE INDEX media_name_idx ON media (name);
@@ -0,0 +1,16 @@
+-- Image dimensions, and the resized copies generated for responsive
+-- images. Variants belong to the stored file rather than to a library
+-- entry, as several users can upload the same file.
+ALTER TABLE media ADD COLUMN width INTEGER;
+ALTER TABLE media ADD COLUMN height INTEGER;
+
+CREATE TABLE media_variants (
+    name VARCHAR PRIMARY KEY,
+    original VARCHAR NOT NULL,
+    content_type VARCHAR NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    size_bytes BIGINT NOT NULL
+);
+
+CREATE INDEX media_variants_original_idx ON media_variants (original);
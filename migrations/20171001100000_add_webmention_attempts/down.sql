@@ -0,0 +1,3 @@
+ALTER TABLE webmentions DROP COLUMN next_attempt_on;
+ALTER TABLE webmentions DROP COLUMN last_error;
+ALTER TABLE webmentions DROP COLUMN attempts;
@@ -0,0 +1,5 @@
+-- Verifying a received webmention is retried with backoff when the source
+-- can't be fetched, instead of rejecting the mention right away.
+ALTER TABLE webmentions ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE webmentions ADD COLUMN last_error VARCHAR;
+ALTER TABLE webmentions ADD COLUMN next_attempt_on TIMESTAMPTZ NOT NULL DEFAULT now();
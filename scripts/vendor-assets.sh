@@ -0,0 +1,34 @@
+#!/bin/sh
+# Downloads the front-end libraries into static/vendor/ and checks them
+# against the pinned SRI hashes. Run it on a machine with internet access
+# and commit the files, the server itself never fetches them. It only has
+# to be run again when a version changes.
+set -eu
+
+mkdir -p "$(dirname "$0")/../static/vendor"
+cd "$(dirname "$0")/../static/vendor"
+
+fetch() {
+    name=$1
+    url=$2
+    integrity=$3
+    curl -fsSL -o "$name.tmp" "$url"
+    actual="sha384-$(openssl dgst -sha384 -binary "$name.tmp" | openssl base64 -A)"
+    if [ "$actual" != "$integrity" ]; then
+        rm -f "$name.tmp"
+        echo "$name: expected $integrity, got $actual" >&2
+        exit 1
+    fi
+    mv "$name.tmp" "$name"
+    echo "$name"
+}
+
+fetch jquery-3.1.1.slim.min.js \
+    https://code.jquery.com/jquery-3.1.1.slim.min.js \
+    sha384-A7FZj7v+d/sdmMqp/nOQwliLvUsJfDHW+k9Omg/a/EheAdgtzNs3hpfag6Ed950n
+fetch tether-1.4.0.min.js \
+    https://cdnjs.cloudflare.com/ajax/libs/tether/1.4.0/js/tether.min.js \
+    sha384-DztdAPBWPRXSA/3eYEEUWrWCy7G5KFbe8fFjk5JAIxUYHKkDx6Qin1DkWx51bBrb
+fetch bootstrap-4.0.0-alpha.6.min.js \
+    https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.6/js/bootstrap.min.js \
+    sha384-vBWWzlZJ8ea9aCX4pEW3rVHjgjt7zpkNpZk+02D9phzyeVkE+jo0ieGizqPLForn
@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use ammonia;
+use chrono::UTC;
+use diesel::pg::PgConnection;
+use reqwest::Url;
+use reqwest::header::Headers;
+use rocket::{Data, Outcome, State};
+use rocket::http::{ContentType, Status};
+use rocket::http::uri::URI;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content::Content;
+use rocket::response::status;
+use rocket_contrib::Json;
+use serde_json::{self, Value};
+
+use config;
+use db_util::{Connection, Pool};
+use errors::*;
+use http_client::HttpClient;
+use http_signature::{self, Signature};
+use model::{CommentStatus, Delivery, NewComment, NewFollower, Post, User, WebfingerQuery};
+use service;
+use spam::{SpamFilter, Submission, SubmissionKind};
+use util;
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// A delivery is given up after this many failed attempts.
+const MAX_ATTEMPTS: i32 = 8;
+
+/// Larger activities and remote documents are rejected.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// How many deliveries are sent per run.
+const BATCH_SIZE: i64 = 20;
+
+/// How many posts the outbox lists.
+const OUTBOX_SIZE: usize = 20;
+
+type ActivityJson = Content<Json<Value>>;
+
+fn activity_json(value: Value) -> ActivityJson {
+    Content(ContentType::new("application", "activity+json"), Json(value))
+}
+
+pub fn actor_url(name: &str) -> String {
+    format!("{}/users/{}", config::base_url(), URI::percent_encode(name))
+}
+
+fn key_id(name: &str) -> String {
+    format!("{}#main-key", actor_url(name))
+}
+
+/// The `id` of an object that may be given inline or as a plain URL.
+fn object_id(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+/// The host and port of the blog, as used in `acct:` URIs.
+fn domain() -> String {
+    let base = config::base_url();
+    match Url::parse(&base) {
+        Ok(url) => {
+            match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_string(),
+                _ => base.clone(),
+            }
+        }
+        Err(_) => base.clone(),
+    }
+}
+
+fn article(post: &Post, author: &User) -> Value {
+    let actor = actor_url(&author.name);
+    let tags: Vec<Value> = post.tags
+        .iter()
+        .map(|tag| {
+                 json!({
+                "type": "Hashtag",
+                "name": format!("#{}", tag),
+                "href": format!("{}/tag/{}", config::base_url(), URI::percent_encode(tag)),
+            })
+             })
+        .collect();
+    json!({
+        "id": util::post_url(post.id),
+        "type": "Article",
+        "attributedTo": actor,
+        "name": post.title,
+        "content": post.content,
+        "url": util::post_url(post.id),
+        "published": post.created_on.to_rfc3339(),
+        "to": [PUBLIC],
+        "cc": [format!("{}/followers", actor)],
+        "tag": tags,
+    })
+}
+
+/// Wraps an object in an activity of type `kind` by `author`.
+fn activity(kind: &str, id: String, object: Value, author: &User) -> Value {
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": kind,
+        "actor": actor_url(&author.name),
+        "to": object["to"].clone(),
+        "cc": object["cc"].clone(),
+        "object": object,
+    })
+}
+
+fn find_user(name: &str, conn: &PgConnection) -> Result<Option<User>> {
+    service::user::find_by_name(name, conn)
+}
+
+#[get("/.well-known/webfinger?<query>")]
+pub fn webfinger(query: WebfingerQuery, conn: Connection) -> Result<Option<Content<Json<Value>>>> {
+    let resource = query.resource.trim();
+    let name = if resource.starts_with("acct:") {
+        let acct = &resource["acct:".len()..];
+        match acct.rfind('@') {
+            Some(at) if acct[at + 1..].eq_ignore_ascii_case(&domain()) => acct[..at].to_string(),
+            _ => return Ok(None),
+        }
+    } else if resource.starts_with(&format!("{}/users/", config::base_url())) {
+        let encoded = &resource[config::base_url().len() + "/users/".len()..];
+        match URI::percent_decode(encoded.as_bytes()) {
+            Ok(name) => name.into_owned(),
+            Err(_) => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+    let user = match find_user(&name, &conn)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let document = json!({
+        "subject": format!("acct:{}@{}", user.name, domain()),
+        "aliases": [actor_url(&user.name)],
+        "links": [
+            {
+                "rel": "self",
+                "type": "application/activity+json",
+                "href": actor_url(&user.name),
+            },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": format!("{}/user/{}", config::base_url(), user.id),
+            },
+        ],
+    });
+    Ok(Some(Content(ContentType::new("application", "jrd+json"), Json(document))))
+}
+
+#[get("/users/<name>")]
+pub fn actor(name: String, conn: Connection) -> Result<Option<ActivityJson>> {
+    let user = match find_user(&name, &conn)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let key = service::activitypub::keys(user.id, &conn)?;
+    let id = actor_url(&user.name);
+    Ok(Some(activity_json(json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+        "id": id,
+        "type": "Person",
+        "preferredUsername": user.name,
+        "name": user.name,
+        "url": format!("{}/user/{}", config::base_url(), user.id),
+        "inbox": format!("{}/inbox", id),
+        "outbox": format!("{}/outbox", id),
+        "followers": format!("{}/followers", id),
+        "publicKey": {
+            "id": key_id(&user.name),
+            "owner": id,
+            "publicKeyPem": key.public_key_pem,
+        },
+    }))))
+}
+
+/// The newest published posts of a user, as `Create` activities.
+#[get("/users/<name>/outbox")]
+pub fn outbox(name: String, conn: Connection) -> Result<Option<ActivityJson>> {
+    let user = match find_user(&name, &conn)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let posts: Vec<Post> = service::post::find_all_by_owner(user.id, &conn)?
+        .into_iter()
+        .filter(|p| p.published)
+        .collect();
+    let items: Vec<Value> = posts
+        .iter()
+        .take(OUTBOX_SIZE)
+        .map(|post| {
+                 let id = format!("{}#create", util::post_url(post.id));
+                 activity("Create", id, article(post, &user), &user)
+             })
+        .collect();
+    Ok(Some(activity_json(json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/outbox", actor_url(&user.name)),
+        "type": "OrderedCollection",
+        "totalItems": posts.len(),
+        "orderedItems": items,
+    }))))
+}
+
+/// Only the number of followers is public, not who they are.
+#[get("/users/<name>/followers")]
+pub fn followers(name: String, conn: Connection) -> Result<Option<ActivityJson>> {
+    let user = match find_user(&name, &conn)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    Ok(Some(activity_json(json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/followers", actor_url(&user.name)),
+        "type": "OrderedCollection",
+        "totalItems": service::activitypub::count_followers(user.id, &conn)?,
+    }))))
+}
+
+/// Fetches an ActivityPub document from another server.
+fn fetch(client: &HttpClient, url: &str) -> Result<Value> {
+    let mut headers = Headers::new();
+    headers.set_raw("Accept", vec![b"application/activity+json".to_vec()]);
+    let (_, mut res) = client.get(url, |req| req.headers(headers.clone()))?;
+    if !res.status().is_success() {
+        return Err(format!("{} answered with {}", url, res.status()).into());
+    }
+    let mut body = vec![];
+    res.by_ref().take(MAX_BODY_SIZE).read_to_end(&mut body)?;
+    serde_json::from_slice(&body).map_err(From::from)
+}
+
+/// The headers of a request that an HTTP signature can cover, with
+/// lowercase names, plus the `(request-target)` pseudo-header.
+pub struct SignedRequest(HashMap<String, String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for SignedRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<SignedRequest, ()> {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for header in request.headers().iter() {
+            let value = headers.entry(header.name().to_lowercase()).or_insert_with(String::new);
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            value.push_str(header.value());
+        }
+        headers.insert("(request-target)".into(),
+                       format!("{} {}", request.method().as_str().to_lowercase(), request.uri().as_str()));
+        Outcome::Success(SignedRequest(headers))
+    }
+}
+
+/// Checks the HTTP signature of an inbox request and returns the actor
+/// that signed it. The key must belong to the actor document it is served
+/// with, as is the case for Mastodon and most other servers.
+fn verify_signer(client: &HttpClient, request: &SignedRequest, body: &[u8]) -> Result<Option<Value>> {
+    let signature = match request.0.get("signature").and_then(|s| Signature::parse(s)) {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let actor_id = signature.key_id.split('#').next().unwrap_or("").to_string();
+    let actor = fetch(client, &actor_id)?;
+    if actor["id"].as_str() != Some(actor_id.as_str()) ||
+       actor["publicKey"]["id"].as_str() != Some(signature.key_id.as_str()) {
+        return Ok(None);
+    }
+    let pem = match actor["publicKey"]["publicKeyPem"].as_str() {
+        Some(pem) => pem,
+        None => return Ok(None),
+    };
+    if signature.verify(&request.0, body, pem)? {
+        Ok(Some(actor))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Turns a reply to one of `user`'s posts into a comment, which goes
+/// through the spam filter and the moderation queue like any other.
+fn receive_reply(user: &User,
+                 actor: &Value,
+                 note: &Value,
+                 spam_filter: &SpamFilter,
+                 conn: &PgConnection)
+                 -> Result<()> {
+    let post = match note["inReplyTo"].as_str().and_then(util::post_id_from_url) {
+        Some(post_id) => service::post::find_one(post_id, conn)?,
+        None => None,
+    };
+    let post = match post {
+        Some(post) => post,
+        None => return Ok(()),
+    };
+    let remote_id = match note["id"].as_str() {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    if post.owner_id != user.id || !post.published ||
+       service::comment::find_by_remote_id(remote_id, conn)?.is_some() {
+        return Ok(());
+    }
+    let author_url = actor["id"].as_str().unwrap_or("").to_string();
+    let author_name = match actor["name"].as_str().map(|s| s.trim()) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => {
+            let host = Url::parse(&author_url)
+                .ok()
+                .and_then(|u| u.host_str().map(String::from))
+                .unwrap_or_default();
+            format!("{}@{}", actor["preferredUsername"].as_str().unwrap_or("unknown"), host)
+        }
+    };
+    let html = note["content"].as_str().unwrap_or("");
+    let verdict = {
+        let submission = Submission {
+            kind: SubmissionKind::Comment,
+            author: &author_name,
+            email: None,
+            body: html,
+            honeypot: None,
+            rendered_at: None,
+        };
+        spam_filter.check(&submission, conn)
+    };
+    let status = if verdict.is_spam() { CommentStatus::Spam } else { CommentStatus::Pending };
+    let comment = NewComment {
+        post_id: post.id,
+        parent_id: None,
+        user_id: None,
+        author_name,
+        author_email: None,
+        markdown_content: html.to_string(),
+        content: ammonia::clean(html),
+        status: status.as_str().into(),
+        spam_score: Some(verdict.score() as f32),
+        remote_id: Some(remote_id.to_string()),
+        author_url: Some(author_url),
+    };
+    service::comment::insert(&comment, conn)?;
+    info!("Received a reply to post {} from {}", post.id, comment.author_name);
+    Ok(())
+}
+
+/// Whether `activity` undoes a follow of `own_id` by `actor_id`. Undoing
+/// someone else's follow, or a follow of another actor, is ignored.
+fn undoes_follow(activity: &Value, actor_id: &str, own_id: &str) -> bool {
+    let follow = &activity["object"];
+    follow["type"].as_str() == Some("Follow") && object_id(&follow["actor"]) == Some(actor_id) &&
+    object_id(&follow["object"]) == Some(own_id)
+}
+
+fn accepted() -> Result<Option<status::Custom<&'static str>>> {
+    Ok(Some(status::Custom(Status::Accepted, "Accepted")))
+}
+
+#[post("/users/<name>/inbox", data = "<data>")]
+pub fn inbox(name: String,
+             data: Data,
+             request: SignedRequest,
+             spam_filter: State<SpamFilter>,
+             conn: Connection)
+             -> Result<Option<status::Custom<&'static str>>> {
+    let user = match find_user(&name, &conn)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let mut body = vec![];
+    data.open().take(MAX_BODY_SIZE).read_to_end(&mut body)?;
+    let activity: Value = match serde_json::from_slice(&body) {
+        Ok(activity) => activity,
+        Err(_) => return Ok(Some(status::Custom(Status::BadRequest, "Invalid JSON"))),
+    };
+    let client = HttpClient::new()?;
+    let actor = match verify_signer(&client, &request, &body) {
+        Ok(Some(ref actor)) if object_id(&activity["actor"]) == actor["id"].as_str() => actor.clone(),
+        Ok(_) => return Ok(Some(status::Custom(Status::Unauthorized, "Invalid signature"))),
+        Err(e) => {
+            warn!("Could not verify the signature of an inbox request: {}", e);
+            return Ok(Some(status::Custom(Status::Unauthorized, "Invalid signature")));
+        }
+    };
+    let actor_id = actor["id"].as_str().unwrap_or("").to_string();
+    let own_id = actor_url(&user.name);
+
+    match activity["type"].as_str().unwrap_or("") {
+        "Follow" if object_id(&activity["object"]) == Some(own_id.as_str()) => {
+            let inbox = actor["endpoints"]["sharedInbox"]
+                .as_str()
+                .or_else(|| actor["inbox"].as_str());
+            let inbox = match inbox {
+                Some(inbox) => inbox.to_string(),
+                None => return Ok(Some(status::Custom(Status::BadRequest, "The actor has no inbox"))),
+            };
+            let follower = NewFollower {
+                user_id: user.id,
+                actor: actor_id.clone(),
+                inbox: inbox.clone(),
+            };
+            service::activitypub::add_follower(&follower, &conn)?;
+            let accept_id = format!("{}#accept-{}", own_id, UTC::now().timestamp());
+            let accept = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": accept_id,
+                "type": "Accept",
+                "actor": own_id,
+                "object": activity,
+            });
+            service::activitypub::enqueue(user.id, &[inbox], &accept.to_string(), &conn)?;
+            info!("{} now follows {}", actor_id, user.name);
+        }
+        "Undo" if undoes_follow(&activity, &actor_id, &own_id) => {
+            service::activitypub::remove_follower(user.id, &actor_id, &conn)?;
+            info!("{} no longer follows {}", actor_id, user.name);
+        }
+        "Create" if activity["object"]["type"].as_str() == Some("Note") => {
+            receive_reply(&user, &actor, &activity["object"], &spam_filter, &conn)?;
+        }
+        "Delete" => {
+            if let Some(id) = object_id(&activity["object"]) {
+                service::comment::delete_remote(id, &actor_id, &conn)?;
+            }
+        }
+        other => debug!("Ignoring {} activity from {}", other, actor_id),
+    }
+    accepted()
+}
+
+/// Queues an activity by `author` for all their followers. Followers on
+/// the same server share one delivery if the server has a shared inbox.
+fn deliver_to_followers(author: &User, activity: &Value, conn: &PgConnection) -> Result<()> {
+    let mut inboxes: Vec<String> = vec![];
+    for follower in service::activitypub::find_followers(author.id, conn)? {
+        if !inboxes.contains(&follower.inbox) {
+            inboxes.push(follower.inbox);
+        }
+    }
+    let queued = service::activitypub::enqueue(author.id, &inboxes, &activity.to_string(), conn)?;
+    if queued > 0 {
+        info!("Queued {} {} activities for delivery",
+              queued,
+              activity["type"].as_str().unwrap_or(""));
+    }
+    Ok(())
+}
+
+/// Sends a published post to the author's followers, as a `Create` or, if
+/// the post was already published before, an `Update`.
+pub fn queue_post(post: &Post, was_published: bool, conn: &PgConnection) -> Result<()> {
+    if !post.published {
+        return Ok(());
+    }
+    let author = service::user::find_by_id(post.owner_id, conn)?;
+    let (kind, id) = if was_published {
+        ("Update", format!("{}#update-{}", util::post_url(post.id), UTC::now().timestamp()))
+    } else {
+        ("Create", format!("{}#create", util::post_url(post.id)))
+    };
+    deliver_to_followers(&author, &activity(kind, id, article(post, &author), &author), conn)
+}
+
+/// Tells the author's followers that a post was deleted.
+pub fn queue_delete(post: &Post, conn: &PgConnection) -> Result<()> {
+    if !post.published {
+        return Ok(());
+    }
+    let author = service::user::find_by_id(post.owner_id, conn)?;
+    let url = util::post_url(post.id);
+    let tombstone = json!({
+        "id": url,
+        "type": "Tombstone",
+        "to": [PUBLIC],
+        "cc": [format!("{}/followers", actor_url(&author.name))],
+    });
+    deliver_to_followers(&author, &activity("Delete", format!("{}#delete", url), tombstone, &author), conn)
+}
+
+/// Posts a delivery to its inbox, signed with the author's key.
+fn deliver(client: &HttpClient, delivery: &Delivery, conn: &PgConnection) -> Result<()> {
+    let author = service::user::find_by_id(delivery.user_id, conn)?;
+    let key = service::activitypub::keys(author.id, conn)?;
+    let url = Url::parse(&delivery.inbox).map_err(|e| Error::from(e.to_string()))?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        _ => return Err(format!("{} has no host", delivery.inbox).into()),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut headers = Headers::new();
+    let signed = http_signature::sign_post(&path,
+                                           &host,
+                                           delivery.activity.as_bytes(),
+                                           &key_id(&author.name),
+                                           &key.private_key_pem)?;
+    for (name, value) in signed {
+        headers.set_raw(name, vec![value.into_bytes()]);
+    }
+    headers.set_raw("Content-Type", vec![b"application/activity+json".to_vec()]);
+    let res = client.post(url.as_str(),
+                          |req| req.headers(headers).body(delivery.activity.clone()))?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} answered with {}", delivery.inbox, res.status()).into())
+    }
+}
+
+fn process(conn: &PgConnection) -> Result<()> {
+    let client = HttpClient::new()?;
+    for delivery in service::activitypub::find_due(BATCH_SIZE, conn)? {
+        match deliver(&client, &delivery, conn) {
+            Ok(()) => {
+                debug!("Delivered activity to {}", delivery.inbox);
+                service::activitypub::mark_delivered(delivery.id, conn)?;
+            }
+            Err(e) => {
+                warn!("Error delivering activity to {}: {}", delivery.inbox, e);
+                service::activitypub::mark_failed(&delivery, &e.to_string(), MAX_ATTEMPTS, conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts a background thread that sends queued activities every
+/// `interval`, retrying failed deliveries with backoff.
+pub fn start(pool: Pool, interval: Duration) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("activitypub".into())
+        .spawn(move || loop {
+                   match pool.get() {
+                       Ok(conn) => {
+                           if let Err(e) = process(&conn) {
+                               warn!("Error delivering activities: {}", e);
+                           }
+                       }
+                       Err(e) => warn!("ActivityPub worker could not get a database connection: {}", e),
+                   }
+                   thread::sleep(interval);
+               })
+        .expect("Failed to start the ActivityPub thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{Canned, TestServer};
+
+    /// Serves the actor document of `name` on a stand-in instance, with
+    /// the given public key, and returns the actor's id.
+    fn serve_actor(server: &TestServer, name: &str, key_id: &str, public_key: &str) -> String {
+        let id = server.url(&format!("/users/{}", name));
+        let actor = json!({
+            "id": id,
+            "type": "Person",
+            "inbox": format!("{}/inbox", id),
+            "publicKey": {
+                "id": key_id,
+                "owner": id,
+                "publicKeyPem": public_key,
+            },
+        });
+        server.route(&format!("/users/{}", name),
+                     Canned::new("200 OK", &actor.to_string()).header("Content-Type: application/activity+json"));
+        id
+    }
+
+    /// Serves an actor document with a fresh key on a stand-in instance
+    /// and returns the actor's id and private key.
+    fn remote_actor(server: &TestServer) -> (String, String) {
+        let (public, private) = http_signature::generate_keypair().unwrap();
+        let id = serve_actor(server, "alice", &format!("{}#main-key", server.url("/users/alice")), &public);
+        (id, private)
+    }
+
+    /// The request as our inbox would see it, signed by `key_id`.
+    fn signed_request(body: &[u8], key_id: &str, private_key: &str) -> SignedRequest {
+        let mut values = HashMap::new();
+        values.insert("(request-target)".to_string(), "post /users/bob/inbox".to_string());
+        values.insert("host".to_string(), "blog.example.com".to_string());
+        for (name, value) in http_signature::sign_post("/users/bob/inbox", "blog.example.com", body, key_id, private_key)
+                .unwrap() {
+            values.insert(name.to_lowercase(), value);
+        }
+        SignedRequest(values)
+    }
+
+    #[test]
+    fn signer_is_fetched_and_verified() {
+        let server = TestServer::start();
+        let (id, private) = remote_actor(&server);
+        let body = br#"{"type":"Follow"}"#;
+        let request = signed_request(body, &format!("{}#main-key", id), &private);
+        let client = HttpClient::allowing_private().unwrap();
+        let actor = verify_signer(&client, &request, body).unwrap().unwrap();
+        assert_eq!(actor["id"].as_str(), Some(id.as_str()));
+    }
+
+    #[test]
+    fn tampered_body_is_rejected() {
+        let server = TestServer::start();
+        let (id, private) = remote_actor(&server);
+        let request = signed_request(br#"{"type":"Follow"}"#, &format!("{}#main-key", id), &private);
+        let client = HttpClient::allowing_private().unwrap();
+        assert!(verify_signer(&client, &request, br#"{"type":"Delete"}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn key_of_another_actor_is_rejected() {
+        let server = TestServer::start();
+        let (public, private) = http_signature::generate_keypair().unwrap();
+        let alice_key = format!("{}#main-key", server.url("/users/alice"));
+        serve_actor(&server, "alice", &alice_key, &public);
+        // Mallory's document claims Alice's key, which would let Alice's
+        // key sign for Mallory.
+        let mallory = serve_actor(&server, "mallory", &alice_key, &public);
+        let body = br#"{"type":"Follow"}"#;
+        let request = signed_request(body, &format!("{}#main-key", mallory), &private);
+        let client = HttpClient::allowing_private().unwrap();
+        assert!(verify_signer(&client, &request, body).unwrap().is_none());
+        assert!(server.received().iter().any(|r| r.path == "/users/mallory"));
+    }
+
+    #[test]
+    fn signer_on_private_address_is_not_fetched() {
+        let server = TestServer::start();
+        let (id, private) = remote_actor(&server);
+        let body = br#"{"type":"Follow"}"#;
+        let request = signed_request(body, &format!("{}#main-key", id), &private);
+        let client = HttpClient::new().unwrap();
+        assert!(verify_signer(&client, &request, body).is_err());
+        assert!(server.received().is_empty());
+    }
+
+    #[test]
+    fn signature_without_request_target_is_rejected() {
+        let server = TestServer::start();
+        let (id, private) = remote_actor(&server);
+        let body = br#"{"type":"Follow"}"#;
+        let mut request = signed_request(body, &format!("{}#main-key", id), &private);
+        // The same signature, claiming to cover fewer headers.
+        let header = request.0["signature"].replace("(request-target) host ", "");
+        request.0.insert("signature".to_string(), header);
+        let client = HttpClient::allowing_private().unwrap();
+        assert!(verify_signer(&client, &request, body).unwrap().is_none());
+    }
+
+    #[test]
+    fn undo_must_target_own_actor() {
+        let own = "https://blog.example.com/users/bob";
+        let alice = "https://remote.example/users/alice";
+        let undo = |actor: &str, object: &str| {
+            json!({
+                "type": "Undo",
+                "actor": alice,
+                "object": {"type": "Follow", "actor": actor, "object": object},
+            })
+        };
+        assert!(undoes_follow(&undo(alice, own), alice, own));
+        assert!(!undoes_follow(&undo(alice, "https://blog.example.com/users/carol"), alice, own));
+        assert!(!undoes_follow(&undo("https://remote.example/users/eve", own), alice, own));
+    }
+}
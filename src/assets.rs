@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Values;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64;
+use rocket_contrib::handlebars::{Handlebars, Helper, JsonRender, RenderContext, RenderError};
+use sha2::{Digest, Sha384};
+
+use errors::*;
+use util;
+
+/// The assets templates can refer to, by logical name, with their path
+/// below the static directory. The vendored libraries are downloaded by
+/// `scripts/vendor-assets.sh` and committed.
+const MANIFEST: &[(&str, &str)] = &[("bootstrap.css", "bootstrap.min.css"),
+                                    ("syntaxhighlight.css", "syntaxhighlight.css"),
+                                    ("override.css", "override.css"),
+                                    ("jquery.js", "vendor/jquery-3.1.1.slim.min.js"),
+                                    ("tether.js", "vendor/tether-1.4.0.min.js"),
+                                    ("bootstrap.js", "vendor/bootstrap-4.0.0-alpha.6.min.js")];
+
+/// The length of the fingerprint in file names, in hex digits.
+const FINGERPRINT_LEN: usize = 16;
+
+/// An asset with its content-addressed name.
+#[derive(Debug, Clone)]
+pub struct Asset {
+    /// The file's path below the static directory.
+    pub path: PathBuf,
+    /// `path` with the fingerprint before the extension, as in
+    /// `vendor/jquery-3.1.1.slim.min.0123456789abcdef.js`.
+    pub fingerprinted: PathBuf,
+    /// The Subresource Integrity hash, as in `sha384-...`.
+    pub integrity: String,
+}
+
+impl Asset {
+    pub fn url(&self) -> String {
+        format!("/static/{}", self.fingerprinted.display())
+    }
+
+    /// A `<script>` or stylesheet `<link>` element for the asset.
+    fn tag(&self) -> String {
+        let is_css = self.path.extension().map(|e| e == "css").unwrap_or(false);
+        if is_css {
+            format!("<link rel=\"stylesheet\" href=\"{}\" integrity=\"{}\">",
+                    self.url(),
+                    self.integrity)
+        } else {
+            format!("<script src=\"{}\" integrity=\"{}\"></script>",
+                    self.url(),
+                    self.integrity)
+        }
+    }
+}
+
+fn fingerprinted_path(path: &Path, fingerprint: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, fingerprint, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, fingerprint),
+    };
+    path.with_file_name(name)
+}
+
+/// The fingerprinted assets, computed once at startup.
+#[derive(Debug)]
+pub struct Assets {
+    by_name: HashMap<String, Asset>,
+}
+
+impl Assets {
+    /// Hashes the files of the manifest below `root`. A missing file is an
+    /// error, the pages would not work without it.
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Assets> {
+        let mut by_name = HashMap::new();
+        for &(name, path) in MANIFEST {
+            let path = PathBuf::from(path);
+            let full = root.as_ref().join(&path);
+            let mut data = vec![];
+            File::open(&full)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .chain_err(|| format!("Could not read asset {}, run scripts/vendor-assets.sh", full.display()))?;
+            let mut hasher = Sha384::default();
+            hasher.input(&data);
+            let digest = hasher.result();
+            let fingerprint = &util::hex(&digest)[..FINGERPRINT_LEN];
+            by_name.insert(name.to_string(),
+                           Asset {
+                               fingerprinted: fingerprinted_path(&path, fingerprint),
+                               integrity: format!("sha384-{}", base64::encode(&digest)),
+                               path,
+                           });
+        }
+        Ok(Assets { by_name })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Asset> {
+        self.by_name.get(name)
+    }
+
+    pub fn iter(&self) -> Values<String, Asset> {
+        self.by_name.values()
+    }
+}
+
+/// `{{asset "jquery.js"}}` renders the element that loads an asset, with
+/// its fingerprinted URL and integrity hash. `{{asset "jquery.js" "url"}}`
+/// and `{{asset "jquery.js" "integrity"}}` render only that part.
+fn asset_helper(assets: &Assets, h: &Helper, rc: &mut RenderContext) -> ::std::result::Result<(), RenderError> {
+    let name = h.param(0)
+        .map(|p| p.value().render())
+        .ok_or_else(|| RenderError::new("asset: missing name"))?;
+    let asset = assets
+        .get(&name)
+        .ok_or_else(|| RenderError::new(format!("asset: unknown asset {}", name)))?;
+    let output = match h.param(1).map(|p| p.value().render()) {
+        Some(ref part) if part == "url" => asset.url(),
+        Some(ref part) if part == "integrity" => asset.integrity.clone(),
+        Some(part) => return Err(RenderError::new(format!("asset: unknown part {}", part))),
+        None => asset.tag(),
+    };
+    rc.writer.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+/// Registers the `asset` helper. Called from the template fairing, again
+/// whenever the templates are reloaded.
+pub fn register_helper(handlebars: &mut Handlebars, assets: Arc<Assets>) {
+    handlebars.register_helper("asset",
+                               Box::new(move |h: &Helper, _: &Handlebars, rc: &mut RenderContext| {
+                                            asset_helper(&assets, h, rc)
+                                        }));
+}
@@ -1,20 +1,21 @@
-use rocket::{Outcome, State};
+use rocket::Outcome;
 use rocket::request::{self, FromRequest, Request};
 
 use model::User;
 use service::user;
-use db_util::Pool;
+use db_util::Connection;
 
 impl<'a, 'r> FromRequest<'a, 'r> for User {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<User, ()> {
-        let pool = match <State<Pool> as FromRequest>::from_request(request) {
-            Outcome::Success(pool) => pool,
+        // Fails with 503 while no connection can be had, like any route
+        // that takes a `Connection`.
+        let connection = match Connection::from_request(request) {
+            Outcome::Success(connection) => connection,
             Outcome::Failure(e) => return Outcome::Failure(e),
             Outcome::Forward(_) => return Outcome::Forward(()),
         };
-        let connection = pool.get().unwrap();
         let id: Option<i32> = request
             .cookies()
             .get_private("user_id")
@@ -26,3 +27,42 @@ impl<'a, 'r> FromRequest<'a, 'r> for User {
         }
     }
 }
+
+/// A logged in user with the `is_admin` flag. Forwards for everyone else.
+pub struct Admin(pub User);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        match User::from_request(request) {
+            Outcome::Success(ref user) if user.is_admin => Outcome::Success(Admin(user.clone())),
+            Outcome::Failure(e) => Outcome::Failure(e),
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+/// The token from an `Authorization: Bearer` header, if any. Never fails,
+/// handlers decide whether they need a token and which scope it must have.
+pub struct BearerToken(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BearerToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BearerToken, ()> {
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| {
+                let mut parts = value.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        Some(token.trim().to_string())
+                    }
+                    _ => None,
+                }
+            });
+        Outcome::Success(BearerToken(token))
+    }
+}
@@ -0,0 +1,151 @@
+use std::cmp;
+use std::io::{Cursor, Write};
+
+use brotli2::write::BrotliEncoder;
+use flate2;
+use flate2::write::GzEncoder;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::{Request, Response};
+
+use config::CompressionSettings;
+use errors::*;
+use util;
+
+/// Bodies smaller than this are sent as they are, compressing them saves
+/// less than the headers cost.
+const MIN_SIZE: usize = 1024;
+
+/// Types whose content is compressed already, compressing it again only
+/// costs time.
+const COMPRESSED_TYPES: &[&str] = &["image/", "audio/", "video/", "font/woff", "application/font-woff",
+                                    "application/zip", "application/gzip", "application/x-gzip",
+                                    "application/pdf", "application/octet-stream"];
+
+/// Compresses response bodies with brotli or gzip, whichever the client
+/// accepts, preferring brotli.
+pub struct Compression {
+    /// From 1 (fastest) to 9 (smallest), used for both encodings.
+    level: u32,
+    /// Bodies are buffered to be compressed, so larger ones are skipped.
+    max_size: u64,
+}
+
+impl Compression {
+    pub fn new(settings: &CompressionSettings) -> Compression {
+        Compression {
+            level: cmp::min(cmp::max(settings.level, 1), 9),
+            max_size: settings.max_size,
+        }
+    }
+
+    fn compress(&self, encoding: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = if encoding == "br" {
+            let mut encoder = BrotliEncoder::new(vec![], self.level);
+            encoder.write_all(data)?;
+            encoder.finish()?
+        } else {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::new(self.level));
+            encoder.write_all(data)?;
+            encoder.finish()?
+        };
+        Ok(compressed)
+    }
+
+    /// Whether the body's size is known and at most `max_size`. Streamed
+    /// bodies have no size, a Content-Length set by the handler is trusted.
+    fn fits(&self, response: &mut Response) -> bool {
+        let length = response
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|l| l.parse::<u64>().ok());
+        let size = match length {
+            Some(length) => Some(length),
+            None => response.body().and_then(|b| b.size()),
+        };
+        size.map(|size| size <= self.max_size).unwrap_or(false)
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    if essence == "image/svg+xml" {
+        return true;
+    }
+    !essence.is_empty() && !COMPRESSED_TYPES.iter().any(|t| essence.starts_with(t))
+}
+
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // Static files served precompressed have a Content-Encoding already.
+        if response.status() != Status::Ok || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        let compressible = response
+            .headers()
+            .get_one("Content-Type")
+            .map(is_compressible)
+            .unwrap_or(false);
+        if !compressible {
+            return;
+        }
+        // The response depends on the header even if it is sent as it is.
+        let varies = response
+            .headers()
+            .get("Vary")
+            .any(|v| v.to_lowercase().contains("accept-encoding"));
+        if !varies {
+            response.adjoin_raw_header("Vary", "Accept-Encoding");
+        }
+        let encoding = match request.headers().get_one("Accept-Encoding") {
+            Some(header) => {
+                match ["br", "gzip"].iter().cloned().find(|e| util::accepts_encoding(header, e)) {
+                    Some(encoding) => encoding,
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        if !self.fits(response) {
+            return;
+        }
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+        if body.len() < MIN_SIZE {
+            response.set_sized_body(Cursor::new(body));
+            return;
+        }
+        match self.compress(encoding, &body) {
+            Ok(compressed) => {
+                response.set_raw_header("Content-Encoding", encoding);
+                // The compressed body is a different representation, but
+                // means the same, which is what a weak ETag says.
+                let etag = response.headers().get_one("ETag").map(String::from);
+                if let Some(etag) = etag {
+                    if !etag.starts_with("W/") {
+                        response.set_raw_header("ETag", format!("W/{}", etag));
+                    }
+                }
+                response.set_sized_body(Cursor::new(compressed));
+            }
+            Err(e) => {
+                warn!("Error compressing response to {}: {}", request.uri(), e);
+                response.set_sized_body(Cursor::new(body));
+            }
+        }
+    }
+}
@@ -1,15 +1,33 @@
 use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::sync::{Arc, RwLock};
 use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
 
 use dotenv;
 use log;
+use reqwest::Url;
 use time;
+use toml;
 use env_logger;
 
+use errors::*;
+use media::DEFAULT_UPLOAD_DIR;
+use password::HashParams;
+use security_headers;
+use throttle::ThrottleStore;
+
 static LOGGER_INITIALIZED: AtomicBool = ATOMIC_BOOL_INIT;
 
+/// The settings file used if `BLOG_CONFIG` is not set. It is optional.
+const DEFAULT_CONFIG_FILE: &str = "blog.toml";
+
+/// Loads `.env`, if there is one, and sets up the logger with the filters
+/// from `RUST_LOG`, logging `info` and above if it is not set.
 pub fn configure_logger() {
-    dotenv::dotenv().unwrap();
+    dotenv::dotenv().ok();
     let format = |record: &log::LogRecord| {
         let cur_time = time::now();
         format!("{} [{}] {}: {}",
@@ -20,7 +38,405 @@ pub fn configure_logger() {
     };
     let mut builder = env_logger::LogBuilder::new();
     builder.format(format);
-    builder.parse(&env::var("RUST_LOG").unwrap());
-    builder.init().unwrap();
-    LOGGER_INITIALIZED.store(true, Ordering::SeqCst);
+    match env::var("RUST_LOG") {
+        Ok(filters) => {
+            builder.parse(&filters);
+        }
+        Err(_) => {
+            builder.filter(None, log::LogLevelFilter::Info);
+        }
+    }
+    if builder.init().is_ok() {
+        LOGGER_INITIALIZED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// How Markdown is rendered to HTML.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkdownBackend {
+    /// GitHub's Markdown API, falling back to `Plain` if it fails.
+    Github,
+    Plain,
+}
+
+impl FromStr for MarkdownBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MarkdownBackend> {
+        match s {
+            "github" => Ok(MarkdownBackend::Github),
+            "plain" => Ok(MarkdownBackend::Plain),
+            other => Err(format!("Unknown markdown backend: {}", other).into()),
+        }
+    }
+}
+
+/// Whether visitors can create accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+    Open,
+    Closed,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RegistrationMode> {
+        match s {
+            "open" => Ok(RegistrationMode::Open),
+            "closed" => Ok(RegistrationMode::Closed),
+            other => Err(format!("Unknown registration mode: {}", other).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SiteSettings {
+    pub title: String,
+    /// The public URL of the blog, without a trailing slash.
+    pub base_url: String,
+    pub markdown: MarkdownBackend,
+    pub registration: RegistrationMode,
+}
+
+impl Default for SiteSettings {
+    fn default() -> SiteSettings {
+        SiteSettings {
+            title: "Blog".into(),
+            base_url: "http://localhost:8000".into(),
+            markdown: MarkdownBackend::Github,
+            registration: RegistrationMode::Open,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    pub url: String,
+    pub pool_size: u32,
+    /// How long a request waits for a free connection.
+    pub connection_timeout_secs: u64,
+    /// How long an unused connection is kept open, `0` keeps it forever.
+    pub idle_timeout_secs: u64,
+    /// Whether connections are checked before they are handed out.
+    pub test_on_checkout: bool,
+    /// How often connecting is retried at startup before giving up.
+    pub connect_retries: u32,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> DatabaseSettings {
+        DatabaseSettings {
+            url: String::new(),
+            pool_size: 10,
+            connection_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            test_on_checkout: true,
+            connect_retries: 5,
+        }
+    }
+}
+
+/// The SMTP server mail is sent through. Mail is disabled without a host.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct MailSettings {
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The sender address.
+    pub from: Option<String>,
+}
+
+impl Default for MailSettings {
+    fn default() -> MailSettings {
+        MailSettings {
+            smtp_host: None,
+            smtp_port: 587,
+            username: None,
+            password: None,
+            from: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WorkerSettings {
+    pub publisher_interval_secs: u64,
+    pub webmention_interval_secs: u64,
+    pub activitypub_interval_secs: u64,
+}
+
+impl Default for WorkerSettings {
+    fn default() -> WorkerSettings {
+        WorkerSettings {
+            publisher_interval_secs: 30,
+            webmention_interval_secs: 60,
+            activitypub_interval_secs: 30,
+        }
+    }
+}
+
+/// The security headers added to every response, see `SecurityHeaders`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecuritySettings {
+    /// The `Content-Security-Policy`. `{nonce}` is replaced with the nonce
+    /// of each response.
+    pub csp_policy: String,
+    /// Only reports violations instead of blocking them.
+    pub csp_report_only: bool,
+    /// `0` disables `Strict-Transport-Security`.
+    pub hsts_max_age: u64,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+impl Default for SecuritySettings {
+    fn default() -> SecuritySettings {
+        SecuritySettings {
+            csp_policy: security_headers::DEFAULT_POLICY.into(),
+            csp_report_only: false,
+            hsts_max_age: security_headers::DEFAULT_HSTS_MAX_AGE,
+            referrer_policy: security_headers::DEFAULT_REFERRER_POLICY.into(),
+            permissions_policy: security_headers::DEFAULT_PERMISSIONS_POLICY.into(),
+        }
+    }
+}
+
+/// The values `referrer_policy` may have.
+const REFERRER_POLICIES: &[&str] = &["no-referrer", "no-referrer-when-downgrade", "origin",
+                                     "origin-when-cross-origin", "same-origin", "strict-origin",
+                                     "strict-origin-when-cross-origin", "unsafe-url"];
+
+/// Response compression, see `Compression`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionSettings {
+    /// From 1 (fastest) to 9 (smallest).
+    pub level: u32,
+    /// Larger bodies, and streamed ones of unknown size, are sent as they
+    /// are rather than buffered in memory, in bytes.
+    pub max_size: u64,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> CompressionSettings {
+        CompressionSettings {
+            level: 6,
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// All settings, read from a TOML file and overridden by environment
+/// variables. Managed as Rocket state.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub site: SiteSettings,
+    pub database: DatabaseSettings,
+    pub mail: MailSettings,
+    pub password: HashParams,
+    pub login_throttle_store: ThrottleStore,
+    pub spam_threshold: f64,
+    pub upload_dir: String,
+    pub workers: WorkerSettings,
+    pub security: SecuritySettings,
+    pub compression: CompressionSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            site: SiteSettings::default(),
+            database: DatabaseSettings::default(),
+            mail: MailSettings::default(),
+            password: HashParams::default(),
+            login_throttle_store: ThrottleStore::Memory,
+            spam_threshold: 0.9,
+            upload_dir: DEFAULT_UPLOAD_DIR.into(),
+            workers: WorkerSettings::default(),
+            security: SecuritySettings::default(),
+            compression: CompressionSettings::default(),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, value: &mut T) -> Result<()> {
+    if let Ok(s) = env::var(key) {
+        *value = s.parse()
+            .map_err(|_| Error::from(format!("Invalid value for {}: {}", key, s)))?;
+    }
+    Ok(())
+}
+
+fn env_opt(key: &str, value: &mut Option<String>) {
+    if let Ok(s) = env::var(key) {
+        *value = if s.is_empty() { None } else { Some(s) };
+    }
+}
+
+impl Settings {
+    /// Reads the file named by `BLOG_CONFIG`, or `blog.toml` if it exists,
+    /// applies the environment overrides and validates the result. `.env`
+    /// has to be loaded before, see `configure_logger`.
+    pub fn load() -> Result<Settings> {
+        let mut settings = match env::var("BLOG_CONFIG") {
+            Ok(path) => Settings::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Settings::from_file(DEFAULT_CONFIG_FILE)?,
+            Err(_) => Settings::default(),
+        };
+        settings.apply_env()?;
+        settings.site.base_url = settings.site.base_url.trim_right_matches('/').to_string();
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<Settings> {
+        let path = path.as_ref();
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .chain_err(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&content).chain_err(|| format!("Invalid settings in {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_or("SITE_TITLE", &mut self.site.title)?;
+        env_or("BASE_URL", &mut self.site.base_url)?;
+        env_or("MARKDOWN_BACKEND", &mut self.site.markdown)?;
+        env_or("REGISTRATION", &mut self.site.registration)?;
+        env_or("DATABASE_URL", &mut self.database.url)?;
+        env_or("DATABASE_POOL_SIZE", &mut self.database.pool_size)?;
+        env_or("DATABASE_CONNECTION_TIMEOUT_SECS", &mut self.database.connection_timeout_secs)?;
+        env_or("DATABASE_IDLE_TIMEOUT_SECS", &mut self.database.idle_timeout_secs)?;
+        env_or("DATABASE_TEST_ON_CHECKOUT", &mut self.database.test_on_checkout)?;
+        env_or("DATABASE_CONNECT_RETRIES", &mut self.database.connect_retries)?;
+        env_opt("SMTP_HOST", &mut self.mail.smtp_host);
+        env_or("SMTP_PORT", &mut self.mail.smtp_port)?;
+        env_opt("SMTP_USERNAME", &mut self.mail.username);
+        env_opt("SMTP_PASSWORD", &mut self.mail.password);
+        env_opt("MAIL_FROM", &mut self.mail.from);
+        env_or("PASSWORD_HASH_ALGORITHM", &mut self.password.algorithm)?;
+        env_or("SCRYPT_LOG_N", &mut self.password.scrypt_log_n)?;
+        env_or("SCRYPT_R", &mut self.password.scrypt_r)?;
+        env_or("SCRYPT_P", &mut self.password.scrypt_p)?;
+        env_or("ARGON2_MEM_COST", &mut self.password.argon2_mem_cost)?;
+        env_or("ARGON2_TIME_COST", &mut self.password.argon2_time_cost)?;
+        env_or("ARGON2_LANES", &mut self.password.argon2_lanes)?;
+        env_or("LOGIN_THROTTLE_STORE", &mut self.login_throttle_store)?;
+        env_or("SPAM_THRESHOLD", &mut self.spam_threshold)?;
+        env_or("UPLOAD_DIR", &mut self.upload_dir)?;
+        env_or("PUBLISHER_INTERVAL_SECS", &mut self.workers.publisher_interval_secs)?;
+        env_or("WEBMENTION_INTERVAL_SECS", &mut self.workers.webmention_interval_secs)?;
+        env_or("ACTIVITYPUB_INTERVAL_SECS", &mut self.workers.activitypub_interval_secs)?;
+        env_or("CSP_POLICY", &mut self.security.csp_policy)?;
+        env_or("CSP_REPORT_ONLY", &mut self.security.csp_report_only)?;
+        env_or("HSTS_MAX_AGE", &mut self.security.hsts_max_age)?;
+        env_or("REFERRER_POLICY", &mut self.security.referrer_policy)?;
+        env_or("PERMISSIONS_POLICY", &mut self.security.permissions_policy)?;
+        env_or("COMPRESSION_LEVEL", &mut self.compression.level)?;
+        env_or("COMPRESSION_MAX_SIZE", &mut self.compression.max_size)?;
+        Ok(())
+    }
+
+    /// Checks the settings that can't be checked by their type, and lists
+    /// every problem at once.
+    fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        if self.site.title.trim().is_empty() {
+            problems.push("site.title must not be empty".to_string());
+        }
+        match Url::parse(&self.site.base_url) {
+            Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => problems.push(format!("site.base_url must be an http or https URL, not {:?}", self.site.base_url)),
+        }
+        if self.database.url.is_empty() {
+            problems.push("database.url must be set, or DATABASE_URL".to_string());
+        }
+        if self.database.pool_size == 0 {
+            problems.push("database.pool_size must be at least 1".to_string());
+        }
+        if self.database.connection_timeout_secs == 0 {
+            problems.push("database.connection_timeout_secs must be at least 1".to_string());
+        }
+        if self.mail.smtp_host.is_some() {
+            match self.mail.from {
+                Some(ref from) if from.contains('@') => {}
+                _ => problems.push("mail.from must be an email address if mail.smtp_host is set".to_string()),
+            }
+            if self.mail.username.is_some() != self.mail.password.is_some() {
+                problems.push("mail.username and mail.password must be set together".to_string());
+            }
+        }
+        if self.spam_threshold < 0.0 || self.spam_threshold > 1.0 {
+            problems.push(format!("spam_threshold must be between 0 and 1, not {}", self.spam_threshold));
+        }
+        if self.upload_dir.is_empty() {
+            problems.push("upload_dir must not be empty".to_string());
+        }
+        let intervals = [("publisher", self.workers.publisher_interval_secs),
+                         ("webmention", self.workers.webmention_interval_secs),
+                         ("activitypub", self.workers.activitypub_interval_secs)];
+        for &(name, secs) in &intervals {
+            if secs == 0 {
+                problems.push(format!("workers.{}_interval_secs must be at least 1", name));
+            }
+        }
+        problems.extend(self.password.problems());
+        if self.security.csp_policy.trim().is_empty() {
+            problems.push("security.csp_policy must not be empty".to_string());
+        }
+        let headers = [("csp_policy", &self.security.csp_policy),
+                       ("permissions_policy", &self.security.permissions_policy)];
+        for &(name, value) in &headers {
+            if value.chars().any(char::is_control) {
+                problems.push(format!("security.{} must not contain control characters", name));
+            }
+        }
+        if !REFERRER_POLICIES.contains(&self.security.referrer_policy.as_str()) {
+            problems.push(format!("security.referrer_policy must be one of {}, not {:?}",
+                                  REFERRER_POLICIES.join(", "),
+                                  self.security.referrer_policy));
+        }
+        if self.compression.level < 1 || self.compression.level > 9 {
+            problems.push(format!("compression.level must be between 1 and 9, not {}", self.compression.level));
+        }
+        if self.compression.max_size == 0 {
+            problems.push("compression.max_size must be at least 1".to_string());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  {}", problems.join("\n  ")).into())
+        }
+    }
+}
+
+lazy_static! {
+    static ref CURRENT: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
+}
+
+/// Makes `settings` the ones returned by `current`. Called once at startup.
+pub fn install(settings: Settings) {
+    *CURRENT.write().unwrap() = Arc::new(settings);
+}
+
+/// The installed settings, for code that runs outside of request handlers
+/// and has no access to the managed state.
+pub fn current() -> Arc<Settings> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// The public URL of the blog without a trailing slash. Used wherever
+/// absolute links to the blog are needed.
+pub fn base_url() -> String {
+    current().site.base_url.clone()
 }
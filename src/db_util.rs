@@ -1,13 +1,31 @@
+use std::cmp;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use diesel::pg::PgConnection;
 
 use rocket::http::Status;
 use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
 use rocket::{Request, State, Outcome};
 use r2d2;
 use r2d2_diesel::ConnectionManager;
 
+use config::DatabaseSettings;
+use errors::*;
+
+/// Sent with `503 Service Unavailable` when no connection can be had.
+pub const RETRY_AFTER_SECS: u64 = 30;
+
+/// The longest wait between two connection attempts at startup.
+const MAX_BACKOFF_SECS: u64 = 30;
+
+/// Number of requests that got no connection before the timeout, since
+/// startup.
+static CHECKOUT_TIMEOUTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
 pub struct Connection(r2d2::PooledConnection<ConnectionManager<PgConnection>>);
@@ -32,13 +50,155 @@ impl<'a, 'r> FromRequest<'a, 'r> for Connection {
 
         match pool.get() {
             Ok(conn) => Outcome::Success(Connection(conn)),
-            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+            Err(e) => {
+                CHECKOUT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                warn!("No database connection for {}: {}", request.uri(), e);
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            }
         }
     }
 }
 
-pub fn init_pool(db_url: &str) -> Pool {
-    let config = r2d2::Config::default();
-    let manager = ConnectionManager::new(db_url);
-    r2d2::Pool::new(config, manager).unwrap()
+/// Quotes a string as a Postgres escape string literal (`E'...'`), for the
+/// queries diesel can't express. Everything else binds its parameters.
+/// Escape strings treat backslashes the same regardless of
+/// `standard_conforming_strings`. Postgres strings can't hold NUL, so a
+/// string with one is an error rather than quietly becoming another string.
+pub fn quote(input: &str) -> Result<String> {
+    let mut quoted = String::with_capacity(input.len() + 3);
+    quoted.push_str("E'");
+    for c in input.chars() {
+        match c {
+            '\'' => quoted.push_str("''"),
+            '\\' => quoted.push_str("\\\\"),
+            '\0' => return Err("Strings must not contain NUL characters".into()),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    Ok(quoted)
+}
+
+/// A connection to the database named by `DATABASE_URL` inside a
+/// transaction that is never committed, or `None` if the variable is not
+/// set, in which case tests that need a database are skipped.
+#[cfg(test)]
+pub fn test_connection() -> Option<PgConnection> {
+    use diesel::Connection as DieselConnection;
+
+    ::dotenv::dotenv().ok();
+    let url = match ::std::env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("DATABASE_URL is not set, skipping database test");
+            return None;
+        }
+    };
+    let conn = PgConnection::establish(&url).expect("Could not connect to the test database");
+    conn.begin_test_transaction().expect("Could not start the test transaction");
+    Some(conn)
+}
+
+/// Creates the pool. If the database can't be reached, connecting is
+/// retried `connect_retries` times, waiting twice as long after every
+/// attempt, so that the blog can start together with its database.
+pub fn init_pool(settings: &DatabaseSettings) -> Result<Pool> {
+    let idle_timeout = match settings.idle_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let config = r2d2::Config::builder()
+            .pool_size(settings.pool_size)
+            .connection_timeout(Duration::from_secs(settings.connection_timeout_secs))
+            .idle_timeout(idle_timeout)
+            .test_on_check_out(settings.test_on_checkout)
+            .build();
+        let manager = ConnectionManager::new(settings.url.as_str());
+        match r2d2::Pool::new(config, manager) {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt < settings.connect_retries => {
+                attempt += 1;
+                warn!("Could not connect to the database: {}, retrying in {}s ({} of {})",
+                      e,
+                      delay.as_secs(),
+                      attempt,
+                      settings.connect_retries);
+                thread::sleep(delay);
+                delay = cmp::min(delay * 2, Duration::from_secs(MAX_BACKOFF_SECS));
+            }
+            Err(e) => return Err(format!("Could not connect to the database: {}", e).into()),
+        }
+    }
+}
+
+/// A snapshot of the pool, for monitoring.
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use: u32,
+    pub checkout_timeouts: usize,
+}
+
+pub fn pool_stats(pool: &Pool, settings: &DatabaseSettings) -> PoolStats {
+    let state = pool.state();
+    PoolStats {
+        max_size: settings.pool_size,
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        in_use: state.connections - state.idle_connections,
+        checkout_timeouts: CHECKOUT_TIMEOUTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Answers with `503 Service Unavailable` and a `Retry-After` header.
+pub struct RetryLater<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for RetryLater<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(request)?)
+            .status(Status::ServiceUnavailable)
+            .raw_header("Retry-After", RETRY_AFTER_SECS.to_string())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::expression::dsl::sql;
+    use diesel::prelude::*;
+    use diesel::types::Text;
+
+    use super::*;
+
+    #[test]
+    fn quote_escapes_quotes_and_backslashes() {
+        assert_eq!(quote("plain").unwrap(), "E'plain'");
+        assert_eq!(quote("it's").unwrap(), "E'it''s'");
+        assert_eq!(quote(r"a\'b").unwrap(), r"E'a\\''b'");
+        assert_eq!(quote("").unwrap(), "E''");
+    }
+
+    #[test]
+    fn quote_rejects_nul() {
+        assert!(quote("key\0suffix").is_err());
+    }
+
+    #[test]
+    fn quoted_strings_round_trip() {
+        let conn = match test_connection() {
+            Some(conn) => conn,
+            None => return,
+        };
+        for input in &["it's", r"back\slash", r"\'; DROP TABLE posts; --", "ünïcödé \u{2060}", "$$ $tag$"] {
+            let output = sql::<Text>(&format!("SELECT {}", quote(input).unwrap()))
+                .get_result::<String>(&conn)
+                .unwrap();
+            assert_eq!(&output, input);
+        }
+    }
 }
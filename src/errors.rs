@@ -13,6 +13,14 @@ error_chain! {
         Reqwest(::reqwest::Error);
         Diesel(diesel::result::Error);
         SerdeJson(::serde_json::Error);
+        OpenSsl(::openssl::error::ErrorStack);
+    }
+
+    errors {
+        ForbiddenAddress(url: String) {
+            description("refusing to fetch a non-public address")
+            display("refusing to fetch {}, it is not a public address", url)
+        }
     }
 }
 
@@ -0,0 +1,246 @@
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use reqwest::{Client, ClientBuilder, Method, RedirectPolicy, RequestBuilder, Response, Url};
+use reqwest::header::{Host, Location};
+
+use errors::*;
+
+/// How long connecting to another site may take.
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// How long reading from or writing to another site may stall.
+const READ_TIMEOUT_SECS: u64 = 15;
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Returns false for loopback, private, link-local and other addresses
+/// that don't belong to the public internet.
+pub fn is_public(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() ||
+              ip.is_documentation() || ip.is_unspecified() || ip.is_multicast() ||
+              octets[0] == 0 ||
+              // Shared address space for carrier-grade NAT, 100.64.0.0/10.
+              (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4() {
+                return is_public(&IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+              // Unique local, fc00::/7, and link-local, fe80::/10.
+              (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves the host of `url` and checks that all of its addresses are
+/// public, unless `allow_private` is set.
+fn resolve(url: &Url, allow_private: bool) -> Result<Vec<SocketAddr>> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ErrorKind::ForbiddenAddress(url.to_string()).into());
+    }
+    let host = url.host_str()
+        .ok_or_else(|| Error::from(format!("{} has no host", url)))?
+        .trim_left_matches('[')
+        .trim_right_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (host, port).to_socket_addrs()?.collect(),
+    };
+    if addrs.is_empty() {
+        return Err(format!("{} does not resolve", host).into());
+    }
+    if !allow_private && addrs.iter().any(|a| !is_public(&a.ip())) {
+        return Err(ErrorKind::ForbiddenAddress(url.to_string()).into());
+    }
+    Ok(addrs)
+}
+
+/// A client for URLs other sites hand us, such as webmention sources and
+/// ActivityPub key ids. Requests to addresses that aren't public are
+/// refused, also after redirects, so that nobody can make the blog probe
+/// its own network. All requests time out.
+///
+/// The host is resolved once per request and the checked address is the
+/// one connected to, so a name that resolves to another address by the
+/// time of the request can't get around the check. Plain HTTP requests are
+/// sent to that address with the original `Host` header. HTTPS requests
+/// keep the name, reqwest needs it to verify the certificate, and a server
+/// the name was rebound to can't present a valid certificate for it.
+pub struct HttpClient {
+    client: Client,
+    allow_private: bool,
+}
+
+impl HttpClient {
+    pub fn new() -> Result<HttpClient> {
+        HttpClient::build(false)
+    }
+
+    /// A client that also talks to loopback and private addresses. Only
+    /// meant for tests against a local server.
+    pub fn allowing_private() -> Result<HttpClient> {
+        HttpClient::build(true)
+    }
+
+    fn build(allow_private: bool) -> Result<HttpClient> {
+        let mut builder = ClientBuilder::new()?;
+        builder.timeout(Duration::from_secs(READ_TIMEOUT_SECS));
+        // Redirects are followed by `get`, which checks every target.
+        builder.redirect(RedirectPolicy::none());
+        Ok(HttpClient {
+               client: builder.build()?,
+               allow_private,
+           })
+    }
+
+    /// Resolves and checks the host of `url` and returns the first address
+    /// that accepts a connection. reqwest can't bound the time it takes to
+    /// connect, so it is tried here first, with a timeout.
+    fn connect(&self, url: &Url) -> Result<SocketAddr> {
+        let addrs = resolve(url, self.allow_private)?;
+        let mut last_error = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS)) {
+                Ok(_) => return Ok(addr),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+                Some(e) => Error::with_chain(e, format!("Could not connect to {}", url)),
+                None => format!("Could not connect to {}", url).into(),
+            })
+    }
+
+    /// Sends a single request to `url`, see the type's documentation for
+    /// which address it goes to. `build` adds headers and a body.
+    fn send<F>(&self, method: Method, url: &Url, build: F) -> Result<Response>
+        where F: FnOnce(RequestBuilder) -> RequestBuilder
+    {
+        let addr = self.connect(url)?;
+        if url.scheme() == "https" {
+            return Ok(build(self.client.request(method, url.clone())).send()?);
+        }
+        let mut pinned = url.clone();
+        pinned
+            .set_ip_host(addr.ip())
+            .map_err(|_| Error::from(format!("Could not connect to {}", url)))?;
+        let host = Host {
+            hostname: url.host_str().unwrap_or_default().to_string(),
+            port: url.port(),
+        };
+        Ok(build(self.client.request(method, pinned)).header(host).send()?)
+    }
+
+    /// Fetches `url`, following redirects, and returns the URL the response
+    /// finally came from along with it. `build` is applied to the request
+    /// for each URL.
+    pub fn get<F>(&self, url: &str, build: F) -> Result<(Url, Response)>
+        where F: Fn(RequestBuilder) -> RequestBuilder
+    {
+        let mut url = parse(url)?;
+        for _ in 0..MAX_REDIRECTS + 1 {
+            let res = self.send(Method::Get, &url, &build)?;
+            if !res.status().is_redirection() {
+                return Ok((url, res));
+            }
+            let location = match res.headers().get::<Location>() {
+                Some(location) => location.to_string(),
+                None => return Ok((url, res)),
+            };
+            url = url.join(&location)
+                .chain_err(|| format!("Invalid redirect from {} to {}", url, location))?;
+        }
+        Err(format!("Too many redirects, the last one to {}", url).into())
+    }
+
+    /// Posts to `url`. Redirects are not followed.
+    pub fn post<F>(&self, url: &str, build: F) -> Result<Response>
+        where F: FnOnce(RequestBuilder) -> RequestBuilder
+    {
+        self.send(Method::Post, &parse(url)?, build)
+    }
+}
+
+fn parse(url: &str) -> Result<Url> {
+    Url::parse(url).chain_err(|| format!("Invalid URL {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{Canned, TestServer};
+
+    fn public(ip: &str) -> bool {
+        is_public(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+                    "100.64.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!public(ip), "{} is not public", ip);
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_public() {
+        for ip in &["93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(public(ip), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn only_http_is_fetched() {
+        let client = HttpClient::allowing_private().unwrap();
+        match client.get("file:///etc/passwd", |req| req) {
+            Err(Error(ErrorKind::ForbiddenAddress(_), _)) => {}
+            other => panic!("expected ForbiddenAddress, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn private_addresses_are_refused() {
+        let server = TestServer::start();
+        server.route("/", Canned::new("200 OK", "secret"));
+        let client = HttpClient::new().unwrap();
+        match client.get(&server.url("/"), |req| req) {
+            Err(Error(ErrorKind::ForbiddenAddress(_), _)) => {}
+            other => panic!("expected ForbiddenAddress, got {:?}", other.map(|(url, _)| url)),
+        }
+        assert!(server.received().is_empty());
+    }
+
+    #[test]
+    fn requests_keep_the_host_name() {
+        let server = TestServer::start();
+        server.route("/", Canned::new("200 OK", "hello"));
+        let client = HttpClient::allowing_private().unwrap();
+        let url = format!("http://localhost:{}/", server.port);
+        let (_, res) = client.get(&url, |req| req).unwrap();
+        assert!(res.status().is_success());
+        let received = server.received();
+        let host = received[0].headers.iter().find(|&&(ref name, _)| name == "host").map(|&(_, ref v)| v.clone());
+        assert_eq!(host, Some(format!("localhost:{}", server.port)));
+    }
+
+    #[test]
+    fn redirects_are_followed_and_checked() {
+        let server = TestServer::start();
+        server.route("/old", Canned::new("301 Moved Permanently", "").header("Location: /new"));
+        server.route("/new", Canned::new("200 OK", "moved"));
+        server.route("/loop", Canned::new("302 Found", "").header("Location: /loop"));
+        let client = HttpClient::allowing_private().unwrap();
+        let (url, res) = client.get(&server.url("/old"), |req| req).unwrap();
+        assert_eq!(url.as_str(), server.url("/new"));
+        assert!(res.status().is_success());
+        assert!(client.get(&server.url("/loop"), |req| req).is_err());
+        assert_eq!(server.received().iter().filter(|r| r.path == "/loop").count(), MAX_REDIRECTS + 1);
+    }
+}
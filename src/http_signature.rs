@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use base64;
+use chrono::{DateTime, UTC};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use regex::Regex;
+
+use errors::*;
+use util;
+
+/// The headers covered by the signatures we send.
+const SIGNED_HEADERS: &str = "(request-target) host date digest";
+
+/// The headers a signature we accept must cover. Without the request
+/// target and host, a signed request could be replayed to another inbox.
+const REQUIRED_HEADERS: &[&str] = &["(request-target)", "host", "date", "digest"];
+
+/// How far the `Date` of a signed request may be off.
+const MAX_CLOCK_SKEW_HOURS: i64 = 12;
+
+/// Generates an RSA key pair and returns it as PEM `(public, private)`.
+pub fn generate_keypair() -> Result<(String, String)> {
+    let rsa = Rsa::generate(2048)?;
+    let public = String::from_utf8_lossy(&rsa.public_key_to_pem()?).into_owned();
+    let private = String::from_utf8_lossy(&rsa.private_key_to_pem()?).into_owned();
+    Ok((public, private))
+}
+
+/// The value of a `Digest` header for `body`.
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", base64::encode(&util::sha256(body)))
+}
+
+/// Joins the signed headers into the string that is actually signed.
+/// `values` maps lowercase header names to their values.
+fn signing_string(headers: &[String], values: &HashMap<String, String>) -> Result<String> {
+    let mut lines = Vec::with_capacity(headers.len());
+    for name in headers {
+        match values.get(name) {
+            Some(value) => lines.push(format!("{}: {}", name, value)),
+            None => return Err(format!("The signed header {} is missing", name).into()),
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Signs a POST of `body` to `path` on `host`, which must be the `Host`
+/// header the client sends. Returns the headers to send along.
+pub fn sign_post(path: &str,
+                 host: &str,
+                 body: &[u8],
+                 key_id: &str,
+                 private_key_pem: &str)
+                 -> Result<Vec<(&'static str, String)>> {
+    let date = UTC::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let digest = digest(body);
+    let mut values = HashMap::new();
+    values.insert("(request-target)".to_string(), format!("post {}", path));
+    values.insert("host".to_string(), host.to_string());
+    values.insert("date".to_string(), date.clone());
+    values.insert("digest".to_string(), digest.clone());
+    let headers: Vec<String> = SIGNED_HEADERS.split(' ').map(String::from).collect();
+
+    let key = PKey::from_rsa(Rsa::private_key_from_pem(private_key_pem.as_bytes())?)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(signing_string(&headers, &values)?.as_bytes())?;
+    let signature = base64::encode(&signer.finish()?);
+    let header = format!("keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+                         key_id,
+                         SIGNED_HEADERS,
+                         signature);
+    Ok(vec![("Date", date), ("Digest", digest), ("Signature", header)])
+}
+
+/// A parsed `Signature` header.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub key_id: String,
+    /// Lowercase names of the signed headers, in signing order.
+    pub headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    pub fn parse(header: &str) -> Option<Signature> {
+        lazy_static! {
+            static ref PARAM_REGEX: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+        }
+        let params: HashMap<String, String> = PARAM_REGEX
+            .captures_iter(header)
+            .map(|caps| (caps[1].to_string(), caps[2].to_string()))
+            .collect();
+        let headers = params
+            .get("headers")
+            .map(|h| h.split_whitespace().map(|s| s.to_lowercase()).collect())
+            .unwrap_or_else(|| vec!["date".to_string()]);
+        Some(Signature {
+                 key_id: params.get("keyId")?.clone(),
+                 headers,
+                 signature: base64::decode(params.get("signature")?).ok()?,
+             })
+    }
+
+    /// Checks the signature of a request. `values` maps the request's
+    /// lowercase header names to their values and must also contain the
+    /// `(request-target)` pseudo-header. The signature must cover
+    /// `REQUIRED_HEADERS`, the body must match the signed `Digest` header,
+    /// and the request must be recent.
+    pub fn verify(&self, values: &HashMap<String, String>, body: &[u8], public_key_pem: &str) -> Result<bool> {
+        if !REQUIRED_HEADERS.iter().all(|r| self.headers.iter().any(|h| h == r)) {
+            return Ok(false);
+        }
+        let expected_digest = digest(body);
+        let digest_matches = values
+            .get("digest")
+            .map(|d| d.split(',').any(|part| part.trim() == expected_digest))
+            .unwrap_or(false);
+        if !digest_matches {
+            return Ok(false);
+        }
+        let date = values
+            .get("date")
+            .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+            .map(|d| d.with_timezone(&UTC));
+        match date {
+            Some(date) if (UTC::now() - date).num_hours().abs() <= MAX_CLOCK_SKEW_HOURS => {}
+            _ => return Ok(false),
+        }
+        let signing_string = match signing_string(&self.headers, values) {
+            Ok(s) => s,
+            Err(_) => return Ok(false),
+        };
+        let key = PKey::from_rsa(Rsa::public_key_from_pem(public_key_pem.as_bytes())?)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(signing_string.as_bytes())?;
+        Ok(verifier.finish(&self.signature)?)
+    }
+}
@@ -0,0 +1,542 @@
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process::Command;
+
+use image::{self, DynamicImage, FilterType, GenericImage, ImageFormat};
+use image::jpeg::JPEGEncoder;
+use rand;
+
+use errors::*;
+
+/// The widths resized variants are generated at. Only widths below that of
+/// the original are used.
+pub const VARIANT_WIDTHS: &[u32] = &[320, 640, 1024, 1600];
+
+/// Quality of generated JPEG and WebP variants.
+const QUALITY: u8 = 82;
+
+/// The `cwebp` tool from libwebp, which encodes the WebP variants.
+const CWEBP: &str = "cwebp";
+
+/// A resized and recompressed copy of an image.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// An uploaded image, ready to be stored.
+#[derive(Debug, Clone)]
+pub struct Processed {
+    /// The original without metadata and turned upright.
+    pub original: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub variants: Vec<Variant>,
+}
+
+/// Calls `f` with the marker and payload of each JPEG segment before the
+/// image data. Stops early if `f` returns false. Returns the offset of the
+/// image data, or `None` if the file is not a well-formed JPEG.
+fn jpeg_segments<'a, F>(data: &'a [u8], mut f: F) -> Option<usize>
+    where F: FnMut(u8, &'a [u8], &'a [u8]) -> bool
+{
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xff {
+        let marker = data[pos + 1];
+        // Start of scan: the rest is image data.
+        if marker == 0xda {
+            return Some(pos);
+        }
+        let len = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+        if !f(marker, &data[pos..end], &data[pos + 4..end]) {
+            return Some(pos);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Images with more pixels than this are rejected before they are
+/// decoded, as decoding them would take too much memory.
+pub const MAX_PIXELS: u64 = 40 * 1000 * 1000;
+
+fn u16_be(b: &[u8]) -> u32 {
+    (b[0] as u32) << 8 | b[1] as u32
+}
+
+fn u32_be(b: &[u8]) -> u32 {
+    u16_be(b) << 16 | u16_be(&b[2..])
+}
+
+fn u16_le(b: &[u8]) -> u32 {
+    (b[1] as u32) << 8 | b[0] as u32
+}
+
+fn u24_le(b: &[u8]) -> u32 {
+    (b[2] as u32) << 16 | u16_le(b)
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    (b[3] as u32) << 24 | u24_le(b)
+}
+
+/// Reads the width and height of a JPEG, PNG, GIF or WebP image from its
+/// header, without decoding it.
+pub fn dimensions(data: &[u8], content_type: &str) -> Option<(u32, u32)> {
+    match content_type {
+        "image/jpeg" => {
+            let mut size = None;
+            jpeg_segments(data, |marker, _, payload| {
+                // Start of frame, except for the DHT, JPG and DAC markers
+                // that share the range.
+                let is_sof = marker >= 0xc0 && marker <= 0xcf && marker != 0xc4 && marker != 0xc8 &&
+                             marker != 0xcc;
+                if is_sof && payload.len() >= 5 {
+                    size = Some((u16_be(&payload[3..]), u16_be(&payload[1..])));
+                    false
+                } else {
+                    true
+                }
+            });
+            size
+        }
+        "image/png" if data.len() >= 24 && &data[12..16] == b"IHDR" => {
+            Some((u32_be(&data[16..]), u32_be(&data[20..])))
+        }
+        "image/gif" if data.len() >= 10 => Some((u16_le(&data[6..]), u16_le(&data[8..]))),
+        "image/webp" if data.len() >= 30 => {
+            match &data[12..16] {
+                b"VP8X" => Some((u24_le(&data[24..]) + 1, u24_le(&data[27..]) + 1)),
+                b"VP8L" => {
+                    let bits = u32_le(&data[21..]);
+                    Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+                }
+                b"VP8 " => Some((u16_le(&data[26..]) & 0x3fff, u16_le(&data[28..]) & 0x3fff)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether an image is small enough to be decoded.
+pub fn within_pixel_limit(width: u32, height: u32) -> bool {
+    width as u64 * height as u64 <= MAX_PIXELS
+}
+
+/// Removes the APP1 (Exif and XMP) and APP13 (IPTC) segments of a JPEG.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(data.get(..2)?);
+    let pos = jpeg_segments(data, |marker, segment, _| {
+        if marker != 0xe1 && marker != 0xed {
+            out.extend_from_slice(segment);
+        }
+        true
+    })?;
+    out.extend_from_slice(&data[pos..]);
+    Some(out)
+}
+
+/// Removes the `eXIf` chunk of a PNG and its text chunks, which may hold
+/// XMP.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(data.get(..8)?);
+    let mut pos = 8;
+    while pos < data.len() {
+        let len = u32_be(data.get(pos..pos + 4)?) as usize;
+        let end = pos.checked_add(12)?.checked_add(len)?;
+        let chunk = data.get(pos..end)?;
+        match &chunk[4..8] {
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => out.extend_from_slice(chunk),
+        }
+        if &chunk[4..8] == b"IEND" {
+            return Some(out);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Removes the `EXIF` and `XMP ` chunks of a WebP and clears their flags
+/// in the `VP8X` header.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(data.get(..12)?);
+    let riff_end = cmp::min(data.len(), (u32_le(&data[4..]) as usize).checked_add(8)?);
+    let mut pos = 12;
+    while pos + 8 <= riff_end {
+        let len = u32_le(&data[pos + 4..]) as usize;
+        let end = pos.checked_add(8)?.checked_add(len)?.checked_add(len % 2)?;
+        let chunk = data.get(pos..cmp::min(end, riff_end))?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if chunk.len() > 8 => {
+                let start = out.len();
+                out.extend_from_slice(chunk);
+                out[start + 8] &= !0x0c;
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+    Some(out)
+}
+
+/// Removes the comment and application extensions of a GIF, which may hold
+/// XMP, except for the one that makes animations loop.
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+    // Skips the sub-blocks starting at `pos`, returns the end.
+    let sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+    let color_table = |flags: u8| -> usize {
+        if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 }
+    };
+    let mut pos = 13 + color_table(*data.get(10)?);
+    let mut out = data.get(..pos)?.to_vec();
+    loop {
+        match *data.get(pos)? {
+            0x3b => {
+                out.push(0x3b);
+                return Some(out);
+            }
+            0x2c => {
+                let table = color_table(*data.get(pos + 9)?);
+                // The descriptor, the color table and the LZW code size.
+                let end = sub_blocks(pos + 10 + table + 1)?;
+                out.extend_from_slice(data.get(pos..end)?);
+                pos = end;
+            }
+            0x21 => {
+                let label = *data.get(pos + 1)?;
+                let end = sub_blocks(pos + 2)?;
+                let keep = match label {
+                    0xfe => false,
+                    0xff => data.get(pos + 3..pos + 14) == Some(&b"NETSCAPE2.0"[..]),
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(&data[pos..end]);
+                }
+                pos = end;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Removes the metadata of an image, which can carry camera and location
+/// data. Other files are returned as they are. Returns `None` if an image
+/// is malformed.
+pub fn strip_metadata(data: &[u8], content_type: &str) -> Option<Vec<u8>> {
+    match content_type {
+        "image/jpeg" => strip_jpeg(data),
+        "image/png" => strip_png(data),
+        "image/webp" => strip_webp(data),
+        "image/gif" => strip_gif(data),
+        _ => Some(data.to_vec()),
+    }
+}
+
+/// Reads the Exif orientation of a JPEG, from 1 (upright) to 8.
+fn jpeg_orientation(data: &[u8]) -> Option<u16> {
+    let mut exif: Option<&[u8]> = None;
+    jpeg_segments(data, |marker, _, payload| {
+        if marker == 0xe1 && payload.starts_with(b"Exif\0\0") {
+            exif = Some(&payload[6..]);
+            false
+        } else {
+            true
+        }
+    });
+    let tiff = exif?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| -> Option<u16> {
+        let b = tiff.get(i..i + 2)?;
+        Some(if big_endian {
+                 (b[0] as u16) << 8 | b[1] as u16
+             } else {
+                 (b[1] as u16) << 8 | b[0] as u16
+             })
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        let (high, low) = if big_endian { (u16_at(i)?, u16_at(i + 2)?) } else { (u16_at(i + 2)?, u16_at(i)?) };
+        Some((high as u32) << 16 | low as u32)
+    };
+    let ifd = u32_at(4)? as usize;
+    for i in 0..u16_at(ifd)? as usize {
+        let entry = ifd + 2 + i * 12;
+        if u16_at(entry)? == 0x0112 {
+            return u16_at(entry + 8);
+        }
+    }
+    None
+}
+
+fn orient(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    JPEGEncoder::new_with_quality(&mut out, quality)
+        .encode(&img.raw_pixels(), img.width(), img.height(), img.color())?;
+    Ok(out)
+}
+
+fn encode_png(img: &DynamicImage) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    img.save(&mut out, ImageFormat::PNG)
+        .map_err(|e| Error::from(e.to_string()))?;
+    Ok(out)
+}
+
+/// Encodes an image as WebP with `cwebp`. Returns `None` if the tool is
+/// not installed.
+fn encode_webp(img: &DynamicImage) -> Result<Option<Vec<u8>>> {
+    let base = env::temp_dir().join(format!("blog-{:016x}", rand::random::<u64>()));
+    let input = base.with_extension("png");
+    let output = base.with_extension("webp");
+    File::create(&input)?.write_all(&encode_png(img)?)?;
+    let result = Command::new(CWEBP)
+        .arg("-quiet")
+        .arg("-q")
+        .arg(QUALITY.to_string())
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .status();
+    let _ = fs::remove_file(&input);
+    let status = match result {
+        Ok(status) => status,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("{} is not installed, not generating WebP variants", CWEBP);
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !status.success() {
+        let _ = fs::remove_file(&output);
+        return Err(format!("{} failed with {}", CWEBP, status).into());
+    }
+    let mut data = vec![];
+    File::open(&output)?.read_to_end(&mut data)?;
+    fs::remove_file(&output)?;
+    Ok(Some(data))
+}
+
+/// Prepares an uploaded image for storage: strips its metadata, records
+/// its dimensions and generates the resized variants. WebP variants are
+/// only generated if `cwebp` is installed, animated GIFs are not resized.
+/// Returns `None` for files that are not images the decoder understands.
+/// Images above `MAX_PIXELS` are not decoded.
+pub fn process(data: &[u8], content_type: &str) -> Result<Option<Processed>> {
+    if !content_type.starts_with("image/") {
+        return Ok(None);
+    }
+    match dimensions(data, content_type) {
+        Some((width, height)) if within_pixel_limit(width, height) => {}
+        Some((width, height)) => return Err(format!("The image has too many pixels, {}x{}", width, height).into()),
+        None => return Err("Could not read the dimensions of the image".into()),
+    }
+    let img = match image::load_from_memory(data) {
+        Ok(img) => img,
+        Err(e) => {
+            warn!("Could not decode uploaded {}: {}", content_type, e);
+            return Ok(None);
+        }
+    };
+    // The metadata is stripped, so rotated photos have to be turned
+    // upright for real.
+    let (img, original) = match jpeg_orientation(data).unwrap_or(1) {
+        1 => {
+            let stripped = strip_metadata(data, content_type)
+                .ok_or_else(|| Error::from("Could not strip the metadata of the image"))?;
+            (img, stripped)
+        }
+        orientation => {
+            let upright = orient(img, orientation);
+            let data = encode_jpeg(&upright, 92)?;
+            (upright, data)
+        }
+    };
+    variants(img, original, content_type).map(Some)
+}
+
+fn variants(img: DynamicImage, original: Vec<u8>, content_type: &str) -> Result<Processed> {
+    let (width, height) = img.dimensions();
+    let mut variants = vec![];
+    if content_type != "image/gif" {
+        let mut sizes: Vec<DynamicImage> = VARIANT_WIDTHS
+            .iter()
+            .filter(|&&w| w < width)
+            .map(|&w| img.resize(w, height, FilterType::Lanczos3))
+            .collect();
+        sizes.push(img);
+        for resized in sizes {
+            let (w, h) = resized.dimensions();
+            if w < width {
+                let encoded = match content_type {
+                    "image/jpeg" => Some(("image/jpeg", encode_jpeg(&resized, QUALITY)?)),
+                    "image/png" => Some(("image/png", encode_png(&resized)?)),
+                    _ => None,
+                };
+                if let Some((variant_type, data)) = encoded {
+                    variants.push(Variant {
+                                      content_type: variant_type,
+                                      width: w,
+                                      height: h,
+                                      data,
+                                  });
+                }
+            }
+            if content_type != "image/webp" || w < width {
+                if let Some(data) = encode_webp(&resized)? {
+                    variants.push(Variant {
+                                      content_type: "image/webp",
+                                      width: w,
+                                      height: h,
+                                      data,
+                                  });
+                }
+            }
+        }
+    }
+    Ok(Processed {
+           original,
+           width,
+           height,
+           variants,
+       })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u32;
+        let mut chunk = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        // The CRC is not checked when stripping.
+        chunk.extend_from_slice(&[0, 0, 0, 0]);
+        chunk
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(png_chunk(b"IHDR", &[0, 0, 0x1f, 0x40, 0, 0, 0x17, 0x70, 8, 6, 0, 0, 0]));
+        for chunk in chunks {
+            data.extend_from_slice(chunk);
+        }
+        data.extend(png_chunk(b"IEND", &[]));
+        data
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u32;
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.iter().flat_map(|c| c.iter().cloned()).collect();
+        let size = body.len() as u32 + 4;
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+        data.extend_from_slice(b"WEBP");
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn png_dimensions_are_read_from_header() {
+        assert_eq!(dimensions(&png(&[]), "image/png"), Some((8000, 6000)));
+        assert!(!within_pixel_limit(8000, 6000));
+        assert!(within_pixel_limit(4000, 3000));
+    }
+
+    #[test]
+    fn png_metadata_is_stripped() {
+        let idat = png_chunk(b"IDAT", b"pixels");
+        let original = png(&[png_chunk(b"eXIf", b"MM\0*GPS"), idat.clone(), png_chunk(b"iTXt", b"XML:com.adobe.xmp")]);
+        assert_eq!(strip_metadata(&original, "image/png"), Some(png(&[idat])));
+    }
+
+    #[test]
+    fn webp_metadata_is_stripped() {
+        let vp8x = |flags: u8| webp_chunk(b"VP8X", &[flags, 0, 0, 0, 99, 0, 0, 99, 0, 0]);
+        let image = webp_chunk(b"VP8L", b"pixels!");
+        let original = webp(&[vp8x(0x0c), image.clone(), webp_chunk(b"EXIF", b"MM\0*GPS"), webp_chunk(b"XMP ", b"x")]);
+        let stripped = strip_metadata(&original, "image/webp").unwrap();
+        assert_eq!(stripped, webp(&[vp8x(0), image]));
+        assert_eq!(dimensions(&stripped, "image/webp"), Some((100, 100)));
+    }
+
+    #[test]
+    fn gif_comments_are_stripped() {
+        let header = b"GIF89a\x02\x00\x02\x00\x00\x00\x00".to_vec();
+        let image = b"\x2c\x00\x00\x00\x00\x02\x00\x02\x00\x00\x02\x02\x44\x01\x00".to_vec();
+        let looping = b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00".to_vec();
+        let mut original = header.clone();
+        original.extend_from_slice(&looping);
+        original.extend_from_slice(b"\x21\xfe\x05hello\x00");
+        original.extend_from_slice(b"\x21\xff\x0bXMP DataXMP\x02<x\x00");
+        original.extend_from_slice(&image);
+        original.push(0x3b);
+        let mut expected = header;
+        expected.extend(looping);
+        expected.extend(image);
+        expected.push(0x3b);
+        assert_eq!(strip_metadata(&original, "image/gif"), Some(expected));
+    }
+
+    #[test]
+    fn truncated_images_are_malformed() {
+        let data = png(&[png_chunk(b"IDAT", b"pixels")]);
+        assert_eq!(strip_metadata(&data[..data.len() - 6], "image/png"), None);
+        assert_eq!(strip_metadata(b"\xff\xd8\xff\xe1\xff\xff", "image/jpeg"), None);
+    }
+}
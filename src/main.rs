@@ -2,24 +2,34 @@
 #![plugin(rocket_codegen)]
 #![allow(unknown_lints, needless_pass_by_value)]
 
+extern crate ammonia;
+extern crate argon2;
+extern crate base64;
+extern crate brotli2;
 extern crate chrono;
 #[macro_use]
 extern crate diesel_codegen;
 #[macro_use]
 extern crate diesel;
+extern crate diff;
 extern crate dotenv;
 extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
+extern crate flate2;
+extern crate image;
 #[macro_use]
 extern crate lazy_static;
-#[macro_use(warn, log, info)]
+#[macro_use(debug, warn, log, info)]
 extern crate log;
 #[macro_use]
 extern crate maplit;
 extern crate markdown;
+extern crate multipart;
+extern crate openssl;
 extern crate r2d2_diesel;
 extern crate r2d2;
+extern crate rand;
 extern crate regex;
 extern crate reqwest;
 extern crate ring_pwhash;
@@ -30,7 +40,10 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate serde;
+extern crate sha2;
 extern crate time;
+extern crate toml;
+extern crate xml;
 
 mod auth;
 mod model;
@@ -40,55 +53,397 @@ mod errors;
 mod schema;
 mod service;
 mod db_util;
+mod throttle;
+mod password;
+mod publisher;
+mod spam;
+mod http_client;
+mod webmention;
+mod media;
+mod images;
+mod static_files;
+mod assets;
+mod compression;
+mod security_headers;
+mod micropub;
+mod http_signature;
+mod activitypub;
+mod xmlrpc;
+#[cfg(test)]
+mod test_server;
 
-use std::env;
-use std::path::{PathBuf, Path};
+use std::cmp;
+use std::process;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use rocket_contrib::Template;
-use rocket::http::{Cookie, Cookies};
+use chrono::{Datelike, UTC};
+use diesel::pg::PgConnection;
+use rocket_contrib::{Json, Template};
+use rocket::{Data, State};
+use rocket::http::{ContentType, Cookie, Cookies, Status};
+use rocket::http::uri::URI;
 use rocket::request::{Form, FlashMessage};
-use rocket::response::NamedFile;
-use rocket::response::{Redirect, Flash};
+use rocket::response::{status, Redirect, Flash};
 use serde_json::Value;
 
-use db_util::Connection;
-use model::{User, CreateUserRequest, CreatePostRequest, LoginRequest};
+use db_util::{Connection, Pool, RetryLater};
+use model::{User, Post, Draft, CreateUserRequest, CreatePostRequest, LoginRequest, RevisionRange,
+            SaveDraftRequest, PreviewRequest, PostFormError, SearchQuery, SearchResult,
+            TagCount, RenameTagRequest, ArchiveMonth, Comment, CommentForm, CommentStatus,
+            NewComment, CreateTokenRequest};
+use util::Page;
+use auth::Admin;
 use errors::Result;
 use service::user;
+use throttle::{Throttle, LoginThrottle};
+use password::HashParams;
+use config::{RegistrationMode, Settings};
+use spam::{SpamFilter, Submission, SubmissionKind};
+use media::{LocalStorage, MediaLibrary, StoredFile};
+use static_files::{AcceptEncoding, StaticFile, StaticFiles};
+use assets::Assets;
+use compression::Compression;
+use security_headers::SecurityHeaders;
+
+/// A form that may have failed to validate, with the typed error.
+type FormResult<T> = ::std::result::Result<T, PostFormError>;
+
+/// Either a redirect after saving a post, or the editor shown again with
+/// validation errors.
+type PostFormResponse = ::std::result::Result<Flash<Redirect>, status::Custom<Template>>;
 
 #[error(404)]
 fn catch_404(_: &rocket::Request) -> Template {
     Template::render("404", &hashmap! {"parent" => "base"})
 }
 
+#[error(429)]
+fn catch_429(_: &rocket::Request) -> Template {
+    Template::render("429", &hashmap! {"parent" => "base"})
+}
+
+#[error(503)]
+fn catch_503(_: &rocket::Request) -> RetryLater<Template> {
+    RetryLater(Template::render("503", &hashmap! {"parent" => "base"}))
+}
+
+/// Pool statistics for monitoring. Answers with 503 if no connection can
+/// be had, which may take up to the connection timeout.
+#[get("/health")]
+fn health(pool: State<Pool>, settings: State<Settings>) -> status::Custom<Json<Value>> {
+    let healthy = pool.get().is_ok();
+    let status = if healthy { Status::Ok } else { Status::ServiceUnavailable };
+    status::Custom(status,
+                   Json(json!({
+                       "healthy": healthy,
+                       "pool": db_util::pool_stats(&pool, &settings.database),
+                   })))
+}
+
 #[get("/post/<id>")]
-fn show_post(id: i32, conn: Connection, user: Option<User>) -> Result<Option<Template>> {
+fn show_post(id: i32,
+             conn: Connection,
+             user: Option<User>,
+             flash: Option<FlashMessage>)
+             -> Result<Option<Template>> {
     let post = service::post::find_one(id, &conn)?;
     let mut context = hashmap! {
         "parent" => Value::String("base".into())
     };
+    let is_owner = match (&post, &user) {
+        (&Some(ref post), &Some(ref user)) => post.owner_id == user.id,
+        _ => false,
+    };
     match post {
-        Some(post) => {
+        // Only the owner gets to see posts that are not published yet.
+        Some(ref post) if post.published || is_owner => {
             context.insert("post".into(), post.to_json());
             let user_name = service::user::get_name(post.owner_id, &conn)?;
             context.insert("user_name", Value::String(user_name));
         }, 
-        None => return Ok(None)
+        _ => return Ok(None)
     }
     if let Some(user) = user {
         context.insert("user".into(), serde_json::to_value(user)?);
     }
+    context.insert("is_owner", Value::Bool(is_owner));
+    let comments: Vec<_> = service::comment::thread(service::comment::find_approved(id, &conn)?)
+        .into_iter()
+        .map(|(comment, depth)| {
+            let mut value = comment.to_json();
+            value["depth"] = json!(cmp::min(depth, MAX_COMMENT_INDENT));
+            value
+        })
+        .collect();
+    context.insert("comments", Value::Array(comments));
+    let mentions: Vec<_> = service::webmention::find_verified(id, &conn)?
+        .iter()
+        .map(|m| m.to_json())
+        .collect();
+    context.insert("mentions", Value::Array(mentions));
+    context.insert("rendered_at", json!(spam::stamp(UTC::now())?));
+    if let Some(msg) = flash {
+        context.insert("flash", Value::String(msg.msg().to_string()));
+    }
     info!("{:#?}", context);
 
     Ok(Some(Template::render("show_post", &context)))
 }
 
+/// Replies nested deeper than this are not indented any further.
+const MAX_COMMENT_INDENT: usize = 5;
+
+#[post("/post/<id>/comments", data = "<data>")]
+fn post_comment(id: i32,
+                data: Form<CommentForm>,
+                user: Option<User>,
+                spam_filter: State<SpamFilter>,
+                conn: Connection)
+                -> Result<Option<Flash<Redirect>>> {
+    let post = match service::post::find_one(id, &conn)? {
+        Some(ref post) if post.published => post.clone(),
+        _ => return Ok(None),
+    };
+    let form = data.into_inner();
+    let back = Redirect::to(&format!("/post/{}#comments", id));
+    if form.body.trim().is_empty() {
+        return Ok(Some(Flash::error(back, "The comment must not be empty.")));
+    }
+    if let Some(parent_id) = form.parent_id {
+        match service::comment::find_one(parent_id, &conn)? {
+            Some(ref parent) if parent.post_id == id => {}
+            _ => return Ok(Some(Flash::error(back, "The comment you replied to does not exist."))),
+        }
+    }
+
+    let (user_id, author_name, author_email) = match user {
+        Some(ref user) => (Some(user.id), user.name.clone(), None),
+        None => {
+            let name = form.author_name.as_ref().map(|s| s.trim().to_string()).unwrap_or_default();
+            if name.is_empty() {
+                return Ok(Some(Flash::error(back, "Please enter your name.")));
+            }
+            let email = form.author_email.as_ref().map(|s| s.trim().to_string()).and_then(|s| {
+                if s.is_empty() { None } else { Some(s) }
+            });
+            (None, name, email)
+        }
+    };
+    // The post's author does not need to moderate their own comments.
+    let (status, spam_score) = if user_id == Some(post.owner_id) {
+        (CommentStatus::Approved, None)
+    } else {
+        let submission = Submission {
+            kind: SubmissionKind::Comment,
+            author: &author_name,
+            email: author_email.as_ref().map(|s| s.as_str()),
+            body: &form.body,
+            honeypot: form.website.as_ref().map(|s| s.as_str()),
+            rendered_at: form.rendered_at.as_ref().and_then(|s| spam::parse_stamp(s)),
+        };
+        let verdict = spam_filter.check(&submission, &conn);
+        let status = if verdict.is_spam() { CommentStatus::Spam } else { CommentStatus::Pending };
+        (status, Some(verdict.score() as f32))
+    };
+
+    let comment = NewComment {
+        post_id: id,
+        parent_id: form.parent_id,
+        user_id,
+        author_name,
+        author_email,
+        content: util::markdown_to_safe_html(&form.body),
+        markdown_content: form.body,
+        status: status.as_str().into(),
+        spam_score,
+        remote_id: None,
+        author_url: None,
+    };
+    service::comment::insert(&comment, &conn)?;
+    // Spam is not announced as such, so as not to help anyone tune it.
+    let message = match status {
+        CommentStatus::Approved => "Comment posted.",
+        _ => "Thanks! Your comment will appear once it has been approved.",
+    };
+    Ok(Some(Flash::success(back, message)))
+}
+
+#[get("/admin/comments")]
+fn moderation_queue(user: User, flash: Option<FlashMessage>, conn: Connection) -> Result<Template> {
+    let to_json = |comments: Vec<Comment>| -> Vec<Value> { comments.iter().map(|c| c.to_json()).collect() };
+    let context = json!({
+        "parent": "base",
+        "title": "Comments",
+        "pending": to_json(service::comment::find_for_owner(user.id, CommentStatus::Pending, &conn)?),
+        "spam": to_json(service::comment::find_for_owner(user.id, CommentStatus::Spam, &conn)?),
+        "flash": flash.map(|f| f.msg().to_string()),
+        "user": user,
+    });
+    Ok(Template::render("moderation_queue", &context))
+}
+
+#[post("/admin/comments/<id>/<action>")]
+fn moderate_comment(id: i32,
+                    action: String,
+                    user: User,
+                    spam_filter: State<SpamFilter>,
+                    conn: Connection)
+                    -> Result<Option<Flash<Redirect>>> {
+    let comment = match service::comment::find_owned(id, user.id, &conn)? {
+        Some(comment) => comment,
+        None => return Ok(None),
+    };
+    let message = match action.as_str() {
+        "approve" => {
+            service::comment::set_status(id, CommentStatus::Approved, &conn)?;
+            service::comment::train(&comment, false, &spam_filter, &conn)?;
+            "Comment approved."
+        }
+        "reject" => {
+            service::comment::set_status(id, CommentStatus::Spam, &conn)?;
+            service::comment::train(&comment, true, &spam_filter, &conn)?;
+            "Comment marked as spam."
+        }
+        "delete" => {
+            service::comment::delete(id, &conn)?;
+            "Comment deleted."
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(Flash::success(Redirect::to("/admin/comments"), message)))
+}
+
+const SEARCH_PAGE_SIZE: i64 = 10;
+
+/// The requested page of search results, with negative pages clamped to
+/// the first.
+fn search_page(query: &SearchQuery) -> i64 {
+    cmp::max(query.page.unwrap_or(0), 0)
+}
+
+#[get("/search?<query>")]
+fn search(query: SearchQuery, conn: Connection) -> Result<Option<Template>> {
+    let page = match service::search::search(&query.q, search_page(&query), SEARCH_PAGE_SIZE, &conn)? {
+        Some(page) => page,
+        None => return Ok(None),
+    };
+    let context = json!({
+        "parent": "base",
+        "q_encoded": URI::percent_encode(&query.q),
+        "q": query.q,
+        "results": page,
+        "has_previous": page.current_page > 0,
+        "has_next": page.current_page + 1 < page.num_pages,
+        "previous_page": page.current_page - 1,
+        "next_page": page.current_page + 1,
+    });
+    Ok(Some(Template::render("search", &context)))
+}
+
+#[get("/search", rank = 2)]
+fn search_form() -> Template {
+    Template::render("search", &hashmap! {"parent" => "base"})
+}
+
+#[get("/api/search?<query>")]
+fn api_search(query: SearchQuery, conn: Connection) -> Result<Option<Json<Page<SearchResult>>>> {
+    let page = service::search::search(&query.q, search_page(&query), SEARCH_PAGE_SIZE, &conn)?;
+    Ok(page.map(Json))
+}
+
+/// Context for the archive widget in `base.html.hbs`, which is shown on
+/// every page whose context has an `archive` entry.
+fn archive_widget(conn: &PgConnection) -> Result<Value> {
+    let months: Vec<_> = service::archive::months(conn)?
+        .iter()
+        .take(12)
+        .map(|m| m.to_json())
+        .collect();
+    Ok(Value::Array(months))
+}
+
+/// Groups posts, which must be sorted by date, into months.
+fn group_by_month(posts: Vec<Post>) -> Vec<Value> {
+    let mut groups: Vec<(ArchiveMonth, Vec<Value>)> = vec![];
+    for post in posts {
+        let (year, month) = (post.created_on.year(), post.created_on.month() as i32);
+        if groups.last().map(|g| (g.0.year, g.0.month) != (year, month)).unwrap_or(true) {
+            groups.push((ArchiveMonth { year, month, count: 0 }, vec![]));
+        }
+        let group = groups.last_mut().unwrap();
+        group.0.count += 1;
+        group.1.push(post.to_json());
+    }
+    groups
+        .into_iter()
+        .map(|(month, posts)| {
+            let mut value = month.to_json();
+            value["posts"] = Value::Array(posts);
+            value
+        })
+        .collect()
+}
+
+#[get("/archive")]
+fn archive(conn: Connection) -> Result<Template> {
+    let posts = service::archive::find_all(&conn)?;
+    let context = json!({
+        "parent": "base",
+        "title": "Archive",
+        "heading": "Archive",
+        "groups": group_by_month(posts),
+        "archive": archive_widget(&conn)?,
+    });
+    Ok(Template::render("archive_posts", &context))
+}
+
+#[get("/<year>", rank = 3)]
+fn archive_year(year: i32, conn: Connection) -> Result<Option<Template>> {
+    match service::archive::find_by_year(year, &conn)? {
+        Some(posts) => {
+            let context = json!({
+                "parent": "base",
+                "title": format!("Archive {}", year),
+                "heading": year.to_string(),
+                "groups": group_by_month(posts),
+                "archive": archive_widget(&conn)?,
+            });
+            Ok(Some(Template::render("archive_posts", &context)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/<year>/<month>", rank = 3)]
+fn archive_month(year: i32, month: u32, conn: Connection) -> Result<Option<Template>> {
+    match service::archive::find_by_month(year, month, &conn)? {
+        Some(posts) => {
+            let groups = group_by_month(posts);
+            let heading = groups
+                .first()
+                .map(|g| format!("{} {}", g["month_name"].as_str().unwrap_or(""), year))
+                .unwrap_or_else(|| format!("{}-{:02}", year, month));
+            let context = json!({
+                "parent": "base",
+                "title": format!("Archive {}", heading),
+                "heading": heading,
+                "groups": groups,
+                "archive": archive_widget(&conn)?,
+            });
+            Ok(Some(Template::render("archive_posts", &context)))
+        }
+        None => Ok(None),
+    }
+}
+
 #[get("/user/<id>")]
 fn show_user(id: i32, conn: Connection) -> Result<Option<Template>> {
     match service::user::find_one(id, &*conn)? {
         Some(user) => {
             let posts = service::post::find_page(id, 0, 20, &*conn)?
-                .map(|p| p.to_json());
+                .map(|page| page.map(|p| p.to_json()));
             let context = json!({
                 "parent": "base",
                 "posts": posts,
@@ -111,17 +466,29 @@ fn login(flash: Option<FlashMessage>) -> Template {
 }
 
 #[post("/login", data = "<data>")]
-fn do_login(mut cookies: Cookies, data: Form<LoginRequest>, conn: Connection) -> Flash<Redirect> {
+fn do_login(mut cookies: Cookies,
+            data: Form<LoginRequest>,
+            throttle: Throttle,
+            hash_params: State<HashParams>,
+            conn: Connection)
+            -> Result<Flash<Redirect>> {
     let form = data.into_inner();
-    if let Ok(Some(user)) = user::find_by_name(&form.name, &conn) {
-        if user.verify_password(&form.password) {
+    if let Some(until) = throttle.blocked_until(&form.name)? {
+        let wait = cmp::max((until - UTC::now()).num_seconds(), 1);
+        return Ok(Flash::error(Redirect::to("/login"),
+                               format!("Too many failed logins. Try again in {} seconds.", wait)));
+    }
+
+    match user::authenticate(&form.name, &form.password, &hash_params, &conn)? {
+        Some(user) => {
+            throttle.success(&form.name)?;
             cookies.add_private(Cookie::new("user_id", user.id.to_string()));
-            Flash::success(Redirect::to("/"), "Successfully logged in.")
-        } else {
-            Flash::error(Redirect::to("/login"), "Invalid username/password.")
+            Ok(Flash::success(Redirect::to("/"), "Successfully logged in."))
+        }
+        None => {
+            throttle.failure(&form.name, &conn)?;
+            Ok(Flash::error(Redirect::to("/login"), "Invalid username/password."))
         }
-    } else {
-        Flash::error(Redirect::to("/login"), "Invalid username/password.")
     }
 }
 
@@ -133,71 +500,424 @@ fn do_logout(mut cookies: Cookies, user: User) -> Flash<Redirect> {
     Flash::success(Redirect::to("/"), "You were logged out.")
 }
 
+#[get("/register")]
+fn register(flash: Option<FlashMessage>, settings: State<Settings>) -> Result<Option<Template>> {
+    if settings.site.registration == RegistrationMode::Closed {
+        return Ok(None);
+    }
+    let context = json!({
+        "parent": "base",
+        "flash": flash.map(|f| f.msg().to_string()),
+        "rendered_at": spam::stamp(UTC::now())?,
+    });
+    Ok(Some(Template::render("register", &context)))
+}
+
 #[post("/register", data = "<form>")]
-fn new_user(form: Form<CreateUserRequest>, conn: Connection) -> Result<Flash<Redirect>> {
+fn new_user(form: Form<CreateUserRequest>,
+            hash_params: State<HashParams>,
+            spam_filter: State<SpamFilter>,
+            settings: State<Settings>,
+            conn: Connection)
+            -> Result<Flash<Redirect>> {
+    if settings.site.registration == RegistrationMode::Closed {
+        return Ok(Flash::error(Redirect::to("/"), "Registration is closed."));
+    }
     let request = form.into_inner();
-    service::user::create_user(request, &conn)?;
+    let verdict = {
+        let submission = Submission {
+            kind: SubmissionKind::Registration,
+            author: &request.name,
+            email: None,
+            body: "",
+            honeypot: request.website.as_ref().map(|s| s.as_str()),
+            rendered_at: request.rendered_at.as_ref().and_then(|s| spam::parse_stamp(s)),
+        };
+        spam_filter.check(&submission, &conn)
+    };
+    if verdict.is_spam() {
+        warn!("Rejecting registration of {:?} as spam (score {})", request.name, verdict.score());
+        return Ok(Flash::error(Redirect::to("/register"), "Registration failed."));
+    }
+    if request.password != request.password_repeated {
+        return Ok(Flash::error(Redirect::to("/register"), "The passwords do not match."));
+    }
+    service::user::create_user(request, &hash_params, &conn)?;
 
     Ok(Flash::success(Redirect::to("/"), "User created!"))
 }
 
 #[get("/")]
-fn index(user: Option<User>, flash: Option<FlashMessage>) -> Template {
-    let mut context = hashmap!{ "parent" => serde_json::to_value("base").unwrap(), "title" => serde_json::to_value("Blog").unwrap() };
+fn index(user: Option<User>,
+         flash: Option<FlashMessage>,
+         settings: State<Settings>,
+         conn: Connection)
+         -> Result<Template> {
+    let mut context = hashmap! {
+        "parent" => serde_json::to_value("base").unwrap(),
+        "title" => serde_json::to_value(&settings.site.title).unwrap()
+    };
+    context.insert("archive", archive_widget(&conn)?);
     if let Some(user) = user {
         context.insert("user", serde_json::to_value(user).unwrap());
     }
     if let Some(msg) = flash {
         context.insert("flash", serde_json::to_value(msg.msg()).unwrap());
     }
-    Template::render("index", &context)
+    Ok(Template::render("index", &context))
 }
 
 #[get("/static/<file..>")]
-fn serve_static_file(file: PathBuf) -> Result<NamedFile> {
-    NamedFile::open(Path::new("static/").join(file)).map_err(From::from)
+fn serve_static_file(file: PathBuf,
+                     encoding: AcceptEncoding,
+                     files: State<StaticFiles>)
+                     -> Result<Option<StaticFile>> {
+    files.open(&file, encoding.0.as_ref().map(|s| s.as_str()))
 }
 
-#[get("/post/new")]
-fn post_editor(user: User) -> Template {
-    let context = json!( { 
+/// Serves uploaded files. Their names are content hashes, so they never
+/// change.
+#[get("/media/<file>")]
+fn serve_media(file: String, library: State<MediaLibrary>) -> Result<Option<StoredFile>> {
+    library.open(&file)
+}
+
+/// The choices for the editor's language select, with `selected` marked.
+fn language_options(selected: &str) -> Value {
+    model::LANGUAGES
+        .iter()
+        .map(|&name| json!({ "name": name, "selected": name == selected }))
+        .collect()
+}
+
+/// Builds the context for the post editor. An autosaved draft takes
+/// precedence over the saved post, so nothing typed is lost on reload.
+fn editor_context(user: &User, post: Option<&Post>, draft: Option<Draft>) -> Value {
+    let mut context = json!({
         "parent": "base",
         "user": user,
-    } );
-    Template::render("write_post", &context)
+        "post": post,
+        "tags": post.map(|p| p.tags.join(" ")),
+        "languages": language_options(post.map(|p| p.language.as_str()).unwrap_or("english")),
+    });
+    if let Some(draft) = draft {
+        context["post"]["title"] = Value::String(draft.title.clone());
+        context["post"]["markdown_content"] = Value::String(draft.markdown_content.clone());
+        context["tags"] = Value::String(draft.tags.clone());
+        context["draft"] = draft.to_json();
+    }
+    context
+}
+
+/// Shows the editor again with the submitted values and the validation
+/// errors next to their fields.
+fn invalid_post_form(post_id: Option<i32>, error: &PostFormError) -> status::Custom<Template> {
+    let field = |name: &str| error.input.get(name).cloned().unwrap_or_default();
+    let published = field("published");
+    let context = json!({
+        "parent": "base",
+        "post": {
+            "id": post_id,
+            "title": field("title"),
+            "markdown_content": field("markdown_content"),
+            "published": published == "true" || published == "on",
+            "publish_at": field("publish_at"),
+        },
+        "tags": field("tags"),
+        "languages": language_options(&field("language")),
+        "errors": error.messages(),
+    });
+    status::Custom(Status::UnprocessableEntity, Template::render("write_post", &context))
+}
+
+#[get("/post/new")]
+fn post_editor(user: User, conn: Connection) -> Result<Template> {
+    let draft = service::draft::find(user.id, None, &conn)?;
+    let context = editor_context(&user, None, draft);
+    Ok(Template::render("write_post", &context))
 }
 
 #[post("/post/new", data = "<data>")]
-fn create_post(data: Form<CreatePostRequest>, conn: Connection) -> Result<Flash<Redirect>> {
-    let mut data = data.into_inner();
+fn create_post(data: Form<FormResult<CreatePostRequest>>,
+               user: User,
+               conn: Connection)
+               -> Result<PostFormResponse> {
+    let mut data = match data.into_inner() {
+        Ok(data) => data,
+        Err(e) => return Ok(Err(invalid_post_form(None, &e))),
+    };
     data.convert_markdown();
-    service::post::insert_post(data, &conn)?;
-    Ok(Flash::success(Redirect::to("/"), "Post created!"))
+    let post = service::post::insert_post(data, &user, &conn)?;
+    publisher::announce(&post, None, &conn)?;
+    service::draft::discard(user.id, None, &conn)?;
+    Ok(Ok(Flash::success(Redirect::to("/"), "Post created!")))
+}
+
+#[get("/admin/posts")]
+fn admin_posts(user: User, conn: Connection) -> Result<Template> {
+    let posts: Vec<_> = service::post::find_all_by_owner(user.id, &conn)?
+        .iter()
+        .map(|p| p.to_json())
+        .collect();
+    let context = json!({
+        "parent": "base",
+        "user": user,
+        "posts": posts,
+    });
+    Ok(Template::render("admin_posts", &context))
+}
+
+/// Renders Markdown through the same pipeline that is used when saving a post.
+#[post("/api/preview", data = "<data>")]
+fn preview(data: Json<PreviewRequest>, _user: User, conn: Connection) -> Result<Json<Value>> {
+    let html = media::responsive_images(&util::markdown_to_html(&data.markdown_content), &conn)?;
+    Ok(Json(json!({ "html": html })))
+}
+
+#[put("/api/draft", data = "<data>")]
+fn save_draft(data: Json<SaveDraftRequest>, user: User, conn: Connection) -> Result<Option<Json<Value>>> {
+    let request = data.into_inner();
+    if let Some(post_id) = request.post_id {
+        if service::post::find_owned(post_id, user.id, &conn)?.is_none() {
+            return Ok(None);
+        }
+    }
+    let draft = service::draft::save(user.id, request, &conn)?;
+    Ok(Some(Json(draft.to_json())))
 }
 
 #[get("/user/<user_id>/tag/<tag>")]
 fn get_by_tag(user_id: i32, tag: String, conn: Connection) -> Result<Template> {
-    let posts = service::post::get_by_tag(user_id, &tag, &conn)?;
+    let posts: Vec<_> = service::post::get_by_tag(user_id, &model::normalize_tag(&tag), &conn)?
+        .iter()
+        .map(|p| p.to_json())
+        .collect();
     let context = json!({
         "parent": "base",
-        "posts": posts
+        "posts": { "data": posts },
     });
 
     Ok(Template::render("post_list", &context))
 }
 
+#[get("/tag/<tag>")]
+fn show_tag(tag: String, conn: Connection) -> Result<Template> {
+    let tag = model::normalize_tag(&tag);
+    let posts: Vec<_> = service::post::find_by_tag(&tag, &conn)?
+        .iter()
+        .map(|p| p.to_json())
+        .collect();
+    let context = json!({
+        "parent": "base",
+        "title": format!("Posts tagged {}", tag),
+        "heading": format!("Posts tagged \u{201c}{}\u{201d}", tag),
+        "posts": { "data": posts },
+    });
+
+    Ok(Template::render("post_list", &context))
+}
+
+/// Attaches a weight from 1 to 5 to each tag, scaled by its post count.
+fn tag_cloud(counts: Vec<TagCount>) -> Vec<Value> {
+    let max = counts.iter().map(|t| t.count).max().unwrap_or(1);
+    counts
+        .into_iter()
+        .map(|t| {
+            let weight = 1 + (t.count - 1) * 4 / cmp::max(max - 1, 1);
+            json!({ "tag": t.tag, "count": t.count, "weight": weight })
+        })
+        .collect()
+}
+
+#[get("/tags")]
+fn tags(conn: Connection) -> Result<Template> {
+    let context = json!({
+        "parent": "base",
+        "title": "Tags",
+        "tags": tag_cloud(service::tag::counts(&conn)?),
+    });
+    Ok(Template::render("tags", &context))
+}
+
+#[get("/admin/tags")]
+fn admin_tags(admin: Admin, flash: Option<FlashMessage>, conn: Connection) -> Result<Template> {
+    let context = json!({
+        "parent": "base",
+        "user": admin.0,
+        "tags": service::tag::counts(&conn)?,
+        "flash": flash.map(|f| f.msg().to_string()),
+    });
+    Ok(Template::render("admin_tags", &context))
+}
+
+/// Renders the token list. `new_secret` is shown once, right after the
+/// token was created.
+fn tokens_page(user: User,
+               new_secret: Option<String>,
+               flash: Option<String>,
+               conn: &PgConnection)
+               -> Result<Template> {
+    let tokens: Vec<_> = service::token::find_for_user(user.id, conn)?
+        .iter()
+        .map(|t| t.to_json())
+        .collect();
+    let context = json!({
+        "parent": "base",
+        "title": "API tokens",
+        "user": user,
+        "tokens": tokens,
+        "scopes": model::TOKEN_SCOPES,
+        "new_secret": new_secret,
+        "flash": flash,
+    });
+    Ok(Template::render("admin_tokens", &context))
+}
+
+#[get("/admin/tokens")]
+fn admin_tokens(user: User, flash: Option<FlashMessage>, conn: Connection) -> Result<Template> {
+    tokens_page(user, None, flash.map(|f| f.msg().to_string()), &conn)
+}
+
+#[post("/admin/tokens", data = "<data>")]
+fn create_token(user: User, data: Form<CreateTokenRequest>, conn: Connection) -> Result<Template> {
+    let request = data.into_inner();
+    let name = request.name.trim();
+    if name.is_empty() {
+        return tokens_page(user, None, Some("Please give the token a name.".into()), &conn);
+    }
+    let (token, secret) = service::token::create(user.id, name, &request.scope(), &conn)?;
+    service::audit::record("token_created",
+                           &user.name,
+                           &format!("{} ({})", token.name, token.scope),
+                           &conn)?;
+    tokens_page(user, Some(secret), None, &conn)
+}
+
+#[post("/admin/tokens/<id>/revoke")]
+fn revoke_token(id: i32, user: User, conn: Connection) -> Result<Option<Flash<Redirect>>> {
+    if !service::token::revoke(id, user.id, &conn)? {
+        return Ok(None);
+    }
+    service::audit::record("token_revoked", &user.name, &id.to_string(), &conn)?;
+    Ok(Some(Flash::success(Redirect::to("/admin/tokens"), "Token revoked.")))
+}
+
+fn media_page(user: User, flash: Option<String>, conn: &PgConnection) -> Result<Template> {
+    let files: Vec<_> = service::media::find_for_owner(user.id, conn)?
+        .iter()
+        .map(|f| f.to_json())
+        .collect();
+    let context = json!({
+        "parent": "base",
+        "title": "Media",
+        "user": user,
+        "files": files,
+        "max_upload_mb": media::MAX_UPLOAD_SIZE / 1024 / 1024,
+        "flash": flash,
+    });
+    Ok(Template::render("admin_media", &context))
+}
+
+#[get("/admin/media")]
+fn admin_media(user: User, flash: Option<FlashMessage>, conn: Connection) -> Result<Template> {
+    media_page(user, flash.map(|f| f.msg().to_string()), &conn)
+}
+
+#[post("/admin/media", data = "<data>")]
+fn upload_media(user: User,
+                data: Data,
+                content_type: ContentType,
+                library: State<MediaLibrary>,
+                conn: Connection)
+                -> Result<Flash<Redirect>> {
+    let upload = match media::read_upload(data, &content_type, "file") {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(Flash::error(Redirect::to("/admin/media"), "Please choose a file.")),
+        Err(e) => return Ok(Flash::error(Redirect::to("/admin/media"), e.to_string())),
+    };
+    if let Some(message) = media::upload_error(&upload.data) {
+        return Ok(Flash::error(Redirect::to("/admin/media"), message));
+    }
+    library.store(user.id, &upload, &conn)?;
+    Ok(Flash::success(Redirect::to("/admin/media"), "File uploaded."))
+}
+
+#[post("/admin/media/<id>/delete")]
+fn delete_media(id: i32,
+                user: User,
+                library: State<MediaLibrary>,
+                conn: Connection)
+                -> Result<Option<Flash<Redirect>>> {
+    let file = match service::media::find_owned(id, user.id, &conn)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    library.remove(&file, &conn)?;
+    Ok(Some(Flash::success(Redirect::to("/admin/media"), "File deleted.")))
+}
+
+/// The library of the logged in user, for the editor's media picker.
+#[get("/api/media")]
+fn api_media(user: User, conn: Connection) -> Result<Json<Value>> {
+    let files: Vec<_> = service::media::find_for_owner(user.id, &conn)?
+        .iter()
+        .map(|f| f.to_json())
+        .collect();
+    Ok(Json(Value::Array(files)))
+}
+
+/// Uploads from the editor's media picker. Answers with the new file, or
+/// with an `error` message.
+#[post("/api/media", data = "<data>")]
+fn api_upload_media(user: User,
+                    data: Data,
+                    content_type: ContentType,
+                    library: State<MediaLibrary>,
+                    conn: Connection)
+                    -> Result<status::Custom<Json<Value>>> {
+    let error = |message: String| -> Result<status::Custom<Json<Value>>> {
+        Ok(status::Custom(Status::BadRequest, Json(json!({ "error": message }))))
+    };
+    let upload = match media::read_upload(data, &content_type, "file") {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return error("The request has no file.".into()),
+        Err(e) => return error(e.to_string()),
+    };
+    if let Some(message) = media::upload_error(&upload.data) {
+        return error(message.into());
+    }
+    let file = library.store(user.id, &upload, &conn)?;
+    Ok(status::Custom(Status::Created, Json(file.to_json())))
+}
+
+#[post("/admin/tags/rename", data = "<data>")]
+fn rename_tag(admin: Admin, data: Form<RenameTagRequest>, conn: Connection) -> Result<Flash<Redirect>> {
+    let request = data.into_inner();
+    let from = model::normalize_tag(&request.from);
+    let mut to = model::normalize_tags(&request.to);
+    if from.is_empty() || to.len() != 1 {
+        return Ok(Flash::error(Redirect::to("/admin/tags"), "Tags must be single, non-empty words."));
+    }
+    let to = to.remove(0);
+    if !model::is_valid_tag(&to) {
+        return Ok(Flash::error(Redirect::to("/admin/tags"), "Tags must not contain '/', '?' or '#'."));
+    }
+    let changed = service::tag::rename(&from, &to, &conn)?;
+    service::audit::record("tag_rename",
+                           &admin.0.name,
+                           &format!("{} -> {} on {} posts", from, to, changed),
+                           &conn)?;
+    Ok(Flash::success(Redirect::to("/admin/tags"),
+                      format!("Renamed \"{}\" to \"{}\" on {} posts.", from, to, changed)))
+}
+
 #[get("/post/<id>/edit")]
 fn edit_post(id: i32, conn: Connection, user: User) -> Result<Option<Template>> {
-    let post = service::post::find_one(id, &conn)?;
+    let post = service::post::find_owned(id, user.id, &conn)?;
     match post {
         Some(p) => {
-            let tags = p.tags.join(" ");
-            let context = json!({
-                "parent": "base",
-                "user": user,
-                "post": p,
-                "tags": tags
-            });
+            let draft = service::draft::find(user.id, Some(id), &conn)?;
+            let context = editor_context(&user, Some(&p), draft);
             Ok(Some(Template::render("write_post", &context)))
         },
         None => Ok(None)
@@ -205,19 +925,182 @@ fn edit_post(id: i32, conn: Connection, user: User) -> Result<Option<Template>>
 }
 
 #[post("/post/<id>/edit", data = "<data>")]
-fn do_post_edit(id: i32, data: Form<CreatePostRequest>) -> Flash<Redirect> {
-    unimplemented!()
+fn do_post_edit(id: i32,
+                data: Form<FormResult<CreatePostRequest>>,
+                user: User,
+                conn: Connection)
+                -> Result<Option<PostFormResponse>> {
+    let mut post = match service::post::find_owned(id, user.id, &conn)? {
+        Some(post) => post,
+        None => return Ok(None),
+    };
+    let mut data = match data.into_inner() {
+        Ok(data) => data,
+        Err(e) => return Ok(Some(Err(invalid_post_form(Some(id), &e)))),
+    };
+    // Links dropped from a published post are notified as well.
+    let previous_content = if post.published { Some(post.content.clone()) } else { None };
+    data.convert_markdown();
+    data.apply_to(&mut post);
+    let post = service::post::update_post(&post, user.id, &conn)?;
+    publisher::announce(&post, previous_content.as_ref().map(|s| s.as_str()), &conn)?;
+    service::draft::discard(user.id, Some(id), &conn)?;
+    Ok(Some(Ok(Flash::success(Redirect::to(&format!("/post/{}", id)), "Post updated!"))))
+}
+
+#[get("/post/<id>/history")]
+fn post_history(id: i32, user: User, conn: Connection) -> Result<Option<Template>> {
+    let post = match service::post::find_owned(id, user.id, &conn)? {
+        Some(post) => post,
+        None => return Ok(None),
+    };
+    let revisions: Vec<_> = service::revision::find_for_post(id, &conn)?
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            // Compare the two most recent revisions by default.
+            let mut value = r.to_json();
+            value["default_from"] = Value::Bool(i == 1);
+            value
+        })
+        .collect();
+    let context = json!({
+        "parent": "base",
+        "user": user,
+        "post": post,
+        "revisions": revisions,
+    });
+    Ok(Some(Template::render("post_history", &context)))
+}
+
+#[get("/post/<id>/diff?<range>")]
+fn post_diff(id: i32, range: RevisionRange, user: User, conn: Connection) -> Result<Option<Template>> {
+    if service::post::find_owned(id, user.id, &conn)?.is_none() {
+        return Ok(None);
+    }
+    let from = service::revision::find_one(id, range.from, &conn)?;
+    let to = service::revision::find_one(id, range.to, &conn)?;
+    match (from, to) {
+        (Some(from), Some(to)) => {
+            let context = json!({
+                "parent": "base",
+                "user": user,
+                "post_id": id,
+                "from": from.to_json(),
+                "to": to.to_json(),
+                "title_changed": from.title != to.title,
+                "tags_changed": from.tags != to.tags,
+                "lines": util::line_diff(&from.markdown_content, &to.markdown_content),
+            });
+            Ok(Some(Template::render("post_diff", &context)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[post("/post/<id>/revision/<revision_id>/restore")]
+fn restore_revision(id: i32,
+                    revision_id: i32,
+                    user: User,
+                    conn: Connection)
+                    -> Result<Option<Flash<Redirect>>> {
+    let mut post = match service::post::find_owned(id, user.id, &conn)? {
+        Some(post) => post,
+        None => return Ok(None),
+    };
+    match service::revision::find_one(id, revision_id, &conn)? {
+        Some(revision) => {
+            let previous_content = if post.published { Some(post.content.clone()) } else { None };
+            let post = service::revision::restore(&mut post, &revision, user.id, &conn)?;
+            publisher::announce(&post, previous_content.as_ref().map(|s| s.as_str()), &conn)?;
+            Ok(Some(Flash::success(Redirect::to(&format!("/post/{}", id)), "Revision restored!")))
+        }
+        None => Ok(None),
+    }
 }
 
 fn main() {
     config::configure_logger();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    config::install(settings.clone());
+    let pool = match db_util::init_pool(&settings.database) {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let login_throttle = LoginThrottle::with_store(settings.login_throttle_store, &pool);
+    let hash_params = settings.password.clone();
+    publisher::start(pool.clone(),
+                     Duration::from_secs(settings.workers.publisher_interval_secs));
+    let spam_filter = SpamFilter::new(settings.spam_threshold);
+    let media_library = MediaLibrary::new(LocalStorage::new(&settings.upload_dir));
+    let mut static_files = match StaticFiles::new("static") {
+        Ok(static_files) => static_files,
+        Err(e) => {
+            eprintln!("The static directory is missing: {}", e);
+            process::exit(1);
+        }
+    };
+    let assets = match Assets::load("static") {
+        Ok(assets) => Arc::new(assets),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    for asset in assets.iter() {
+        static_files.alias(asset.fingerprinted.clone(), asset.path.clone());
+    }
+    let security_headers = match SecurityHeaders::new(&settings.security) {
+        Ok(security_headers) => security_headers,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let compression = Compression::new(&settings.compression);
+    let nonce_placeholder = security_headers.placeholder();
+    webmention::start(pool.clone(),
+                      Duration::from_secs(settings.workers.webmention_interval_secs));
+    activitypub::start(pool.clone(),
+                       Duration::from_secs(settings.workers.activitypub_interval_secs));
     rocket::ignite()
-        .manage(db_util::init_pool(&database_url))
-        .attach(Template::fairing())
+        .manage(settings)
+        .manage(pool)
+        .manage(login_throttle)
+        .manage(hash_params)
+        .manage(spam_filter)
+        .manage(media_library)
+        .manage(static_files)
+        .attach(security_headers)
+        .attach(compression)
+        .attach(Template::custom(move |engines| {
+                                     util::register_helpers(&mut engines.handlebars);
+                                     assets::register_helper(&mut engines.handlebars, assets.clone());
+                                     security_headers::register_helper(&mut engines.handlebars,
+                                                                       nonce_placeholder.clone());
+                                 }))
         .mount("/",
                routes![show_post, show_user, new_user, login, index, create_post, do_post_edit,
-                       do_login, serve_static_file, do_logout, post_editor, get_by_tag, edit_post])
-        .catch(errors![catch_404])
+                       do_login, serve_static_file, do_logout, post_editor, get_by_tag, edit_post,
+                       post_history, post_diff, restore_revision, save_draft,
+                       admin_posts, preview, search, search_form, api_search, show_tag, tags,
+                       admin_tags, rename_tag, archive, archive_year, archive_month,
+                       post_comment, moderation_queue, moderate_comment, register,
+                       webmention::receive, micropub::post_json, micropub::post_form,
+                       micropub::query, micropub::upload_media, serve_media, admin_tokens,
+                       create_token, revoke_token, activitypub::webfinger, activitypub::actor,
+                       activitypub::outbox, activitypub::followers, activitypub::inbox,
+                       xmlrpc::endpoint, admin_media, upload_media, delete_media, api_media,
+                       api_upload_media, health, security_headers::csp_report])
+        .catch(errors![catch_404, catch_429, catch_503])
         .launch();
 }
@@ -0,0 +1,414 @@
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+
+use diesel::pg::PgConnection;
+use multipart::server::{Multipart, MultipartData};
+use regex::{Captures, Regex};
+use rocket::Data;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+use config;
+use errors::*;
+use images;
+use model::{MediaFile, MediaVariant, NewMediaFile};
+use service;
+use util;
+
+/// Directory uploaded files are stored in unless `UPLOAD_DIR` is set.
+pub const DEFAULT_UPLOAD_DIR: &str = "uploads";
+
+/// Uploads larger than this are rejected.
+pub const MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The file types that may be uploaded, with their extensions.
+const ALLOWED_TYPES: &[(&str, &str)] = &[("image/jpeg", "jpg"),
+                                         ("image/png", "png"),
+                                         ("image/gif", "gif"),
+                                         ("image/webp", "webp"),
+                                         ("application/pdf", "pdf")];
+
+/// The error message for files of other types.
+pub const UNSUPPORTED_TYPE: &str = "Only JPEG, PNG, GIF and WebP images and PDF files can be uploaded.";
+
+/// The error message for images that can't be read.
+pub const MALFORMED_IMAGE: &str = "The image could not be read.";
+
+/// The error message for images above `images::MAX_PIXELS`.
+pub const TOO_MANY_PIXELS: &str = "Images may have at most 40 megapixels.";
+
+/// A file read from a `multipart/form-data` body.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Reads the file sent in the form field `field`.
+pub fn read_upload(data: Data, content_type: &ContentType, field: &str) -> Result<Option<Upload>> {
+    if !content_type.is_form_data() {
+        return Err("Expected a multipart/form-data body.".into());
+    }
+    let boundary = content_type
+        .params()
+        .find(|&(key, _)| key == "boundary")
+        .map(|(_, value)| value.to_string())
+        .ok_or_else(|| Error::from("The multipart body has no boundary."))?;
+    let mut multipart = Multipart::with_body(data.open(), boundary);
+    while let Some(mut entry) = multipart.read_entry()? {
+        if entry.name != field {
+            continue;
+        }
+        if let MultipartData::File(ref mut file) = entry.data {
+            let mut bytes = vec![];
+            file.take(MAX_UPLOAD_SIZE + 1).read_to_end(&mut bytes)?;
+            if bytes.len() as u64 > MAX_UPLOAD_SIZE {
+                return Err(format!("Uploads are limited to {} MB.", MAX_UPLOAD_SIZE / 1024 / 1024).into());
+            }
+            return Ok(Some(Upload {
+                               filename: file.filename().map(String::from),
+                               data: bytes,
+                           }));
+        }
+    }
+    Ok(None)
+}
+
+/// Detects the type of a file from its first bytes. The type claimed by
+/// the client is not trusted.
+pub fn sniff_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+/// Checks whether a file may be uploaded. Returns the error message to
+/// show the uploader if not.
+pub fn upload_error(data: &[u8]) -> Option<&'static str> {
+    let content_type = match sniff_type(data) {
+        Some(content_type) => content_type,
+        None => return Some(UNSUPPORTED_TYPE),
+    };
+    if !content_type.starts_with("image/") {
+        return None;
+    }
+    match images::dimensions(data, content_type) {
+        Some((width, height)) if images::within_pixel_limit(width, height) => None,
+        Some(_) => Some(TOO_MANY_PIXELS),
+        None => Some(MALFORMED_IMAGE),
+    }
+}
+
+fn extension(content_type: &str) -> Option<&'static str> {
+    ALLOWED_TYPES
+        .iter()
+        .find(|&&(mime, _)| mime == content_type)
+        .map(|&(_, ext)| ext)
+}
+
+fn content_type_of(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit('.').next().unwrap_or("");
+    ALLOWED_TYPES
+        .iter()
+        .find(|&&(_, allowed)| allowed == ext)
+        .map(|&(mime, _)| mime)
+}
+
+/// Returns true for names that `MediaLibrary::store` could have produced,
+/// that is `<sha256>.<ext>` for originals and `<sha256>-<width>w.<ext>`
+/// for variants, so that requests for media cannot reach other files.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut parts = name.splitn(2, '.');
+    let (stem, ext) = match (parts.next(), parts.next()) {
+        (Some(stem), Some(ext)) => (stem, ext),
+        _ => return false,
+    };
+    let hash = match stem.find('-') {
+        Some(dash) => {
+            let width = &stem[dash + 1..];
+            if !width.ends_with('w') || width.len() < 2 ||
+               !width[..width.len() - 1].chars().all(|c| c.is_digit(10)) {
+                return false;
+            }
+            &stem[..dash]
+        }
+        None => stem,
+    };
+    hash.len() == 64 && hash.chars().all(|c| c.is_digit(16)) &&
+    ALLOWED_TYPES.iter().any(|&(_, allowed)| allowed == ext)
+}
+
+/// Where uploaded files are kept. Names are always valid according to
+/// `is_valid_name`.
+pub trait Storage: Send + Sync {
+    /// Stores a file. Storing a name that already exists is harmless, as
+    /// names are derived from the content.
+    fn put(&self, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Reads a stored file, or returns `None` if there is no such file.
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    fn delete(&self, name: &str) -> Result<()>;
+}
+
+/// Keeps files in a directory on the local disk.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> LocalStorage {
+        LocalStorage { root: root.into() }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.root.join(name);
+        if !path.exists() {
+            fs::create_dir_all(&self.root)?;
+            // Written under a temporary name first, so that a file is never
+            // served half-written.
+            let tmp = self.root.join(format!(".{}.tmp", name));
+            let mut file = File::create(&tmp)?;
+            file.write_all(data)?;
+            fs::rename(&tmp, &path)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let mut file = match File::open(self.root.join(name)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.root.join(name)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A stored file as served to clients. Its name is its content hash, so it
+/// can be cached forever.
+pub struct StoredFile {
+    content_type: &'static str,
+    data: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for StoredFile {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let mut parts = self.content_type.splitn(2, '/');
+        let content_type = ContentType::new(parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        Response::build()
+            .header(content_type)
+            .raw_header("Cache-Control", "public, max-age=31536000, immutable")
+            .sized_body(Cursor::new(self.data))
+            .ok()
+    }
+}
+
+/// The media libraries of all users, on top of a storage backend. Managed
+/// as Rocket state.
+pub struct MediaLibrary {
+    storage: Box<Storage>,
+}
+
+impl MediaLibrary {
+    pub fn new<S: Storage + 'static>(storage: S) -> MediaLibrary {
+        MediaLibrary { storage: Box::new(storage) }
+    }
+
+    /// Stores an upload and adds it to the library of `owner`. Images have
+    /// their metadata stripped, also if they can't be decoded, and get
+    /// resized variants, see `images::process`. Fails with the message of
+    /// `upload_error` for files that may not be uploaded.
+    pub fn store(&self, owner: i32, upload: &Upload, conn: &PgConnection) -> Result<MediaFile> {
+        if let Some(message) = upload_error(&upload.data) {
+            return Err(message.into());
+        }
+        let content_type = sniff_type(&upload.data).ok_or_else(|| Error::from(UNSUPPORTED_TYPE))?;
+        let ext = extension(content_type).ok_or_else(|| Error::from(UNSUPPORTED_TYPE))?;
+        let processed = match images::process(&upload.data, content_type) {
+            Ok(processed) => processed,
+            Err(e) => {
+                warn!("Error processing uploaded image: {}", e);
+                None
+            }
+        };
+        let stripped;
+        let data = match processed {
+            Some(ref processed) => &processed.original[..],
+            None => {
+                stripped = images::strip_metadata(&upload.data, content_type)
+                    .ok_or_else(|| Error::from(MALFORMED_IMAGE))?;
+                &stripped[..]
+            }
+        };
+        let checksum = util::sha256_hex(data);
+        let name = format!("{}.{}", checksum, ext);
+        self.storage.put(&name, data)?;
+
+        let mut variants = vec![];
+        if let Some(ref processed) = processed {
+            for variant in &processed.variants {
+                let variant_ext = extension(variant.content_type).unwrap_or(ext);
+                let variant_name = format!("{}-{}w.{}", checksum, variant.width, variant_ext);
+                self.storage.put(&variant_name, &variant.data)?;
+                variants.push(MediaVariant {
+                                  name: variant_name,
+                                  original: name.clone(),
+                                  content_type: variant.content_type.into(),
+                                  width: variant.width as i32,
+                                  height: variant.height as i32,
+                                  size_bytes: variant.data.len() as i64,
+                              });
+            }
+        }
+        let file = NewMediaFile {
+            owner_id: owner,
+            name,
+            original_name: upload.filename.as_ref().map(|n| n.chars().take(255).collect()),
+            content_type: content_type.into(),
+            size_bytes: data.len() as i64,
+            checksum,
+            width: processed.as_ref().map(|p| p.width as i32),
+            height: processed.as_ref().map(|p| p.height as i32),
+        };
+        service::media::add(&file, &variants, conn)
+    }
+
+    pub fn open(&self, name: &str) -> Result<Option<StoredFile>> {
+        let content_type = match content_type_of(name) {
+            Some(content_type) if is_valid_name(name) => content_type,
+            _ => return Ok(None),
+        };
+        Ok(self.storage
+               .get(name)?
+               .map(|data| StoredFile { content_type, data }))
+    }
+
+    /// Removes a file from its owner's library. The stored file is only
+    /// deleted once no library contains it anymore.
+    pub fn remove(&self, file: &MediaFile, conn: &PgConnection) -> Result<()> {
+        service::media::delete(file.id, conn)?;
+        if !service::media::is_referenced(&file.name, conn)? {
+            for variant in service::media::find_variants(&file.name, conn)? {
+                self.storage.delete(&variant.name)?;
+            }
+            service::media::delete_variants(&file.name, conn)?;
+            self.storage.delete(&file.name)?;
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref IMG_REGEX: Regex = Regex::new(r"(?is)<img\b[^>]*>").unwrap();
+    static ref SRC_REGEX: Regex =
+        Regex::new(r#"(?i)\ssrc\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+    static ref SIZED_REGEX: Regex = Regex::new(r"(?i)\s(srcset|width|height)\s*=").unwrap();
+}
+
+/// The name of the stored file a URL points to, if it is in the library.
+fn local_name(url: &str) -> Option<&str> {
+    let base = config::base_url();
+    let path = if url.starts_with(&base) { &url[base.len()..] } else { url };
+    if path.starts_with("/media/") && is_valid_name(&path["/media/".len()..]) {
+        Some(&path["/media/".len()..])
+    } else {
+        None
+    }
+}
+
+fn srcset(variants: &[&MediaVariant], original: Option<(&str, i32)>) -> String {
+    let mut candidates: Vec<String> = variants
+        .iter()
+        .map(|v| format!("{} {}w", util::media_url(&v.name), v.width))
+        .collect();
+    if let Some((name, width)) = original {
+        candidates.push(format!("{} {}w", util::media_url(name), width));
+    }
+    candidates.join(", ")
+}
+
+fn responsive_image(tag: &str, conn: &PgConnection) -> Result<Option<String>> {
+    if SIZED_REGEX.is_match(tag) {
+        return Ok(None);
+    }
+    let src = match SRC_REGEX.captures(tag) {
+        Some(caps) => caps.iter().skip(1).filter_map(|m| m).next().map(|m| m.as_str().to_string()),
+        None => None,
+    };
+    let name = match src.as_ref().and_then(|s| local_name(s)) {
+        Some(name) => name.to_string(),
+        None => return Ok(None),
+    };
+    let file = match service::media::find_by_name(&name, conn)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let (width, height) = match (file.width, file.height) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Ok(None),
+    };
+    let variants = service::media::find_variants(&name, conn)?;
+    let webp: Vec<&MediaVariant> = variants.iter().filter(|v| v.content_type == "image/webp").collect();
+    let same_type: Vec<&MediaVariant> = variants.iter().filter(|v| v.content_type == file.content_type).collect();
+
+    let sizes = format!("(max-width: {0}px) 100vw, {0}px", width);
+    let mut attributes = format!(" width=\"{}\" height=\"{}\"", width, height);
+    if !same_type.is_empty() {
+        attributes.push_str(&format!(" srcset=\"{}\" sizes=\"{}\"",
+                                     srcset(&same_type, Some((&name, width))),
+                                     sizes));
+    }
+    let img = format!("{}{}>", tag.trim_right_matches('>').trim_right_matches('/').trim_right(), attributes);
+    if webp.is_empty() || file.content_type == "image/webp" {
+        return Ok(Some(img));
+    }
+    Ok(Some(format!("<picture><source type=\"image/webp\" srcset=\"{}\" sizes=\"{}\">{}</picture>",
+                    srcset(&webp, None),
+                    sizes,
+                    img)))
+}
+
+/// Gives images from the media library their dimensions, so the page does
+/// not jump while they load, and lets browsers pick a suitably sized
+/// variant. Images that already have a size or `srcset` are left alone, so
+/// running this twice is harmless.
+pub fn responsive_images(html: &str, conn: &PgConnection) -> Result<String> {
+    let mut error = None;
+    let result = IMG_REGEX.replace_all(html, |caps: &Captures| match responsive_image(&caps[0], conn) {
+        Ok(Some(img)) => img,
+        Ok(None) => caps[0].to_string(),
+        Err(e) => {
+            error = Some(e);
+            caps[0].to_string()
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(result.into_owned()),
+    }
+}
@@ -0,0 +1,494 @@
+use chrono::UTC;
+use diesel::pg::PgConnection;
+use rocket::{Data, State};
+use rocket::http::{ContentType, Status};
+use rocket::request::{Form, FormItems, FromForm, Request};
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::Json;
+use serde_json::{Map, Value};
+
+use activitypub;
+use auth::BearerToken;
+use config;
+use db_util::Connection;
+use errors::*;
+use media::{self, MediaLibrary};
+use model::{self, CreatePostRequest, Post, User, MAX_TITLE_LENGTH};
+use service;
+use publisher;
+use util;
+
+/// Request parameters in the order they were sent. Micropub clients repeat
+/// a key for every value, optionally with a `[]` suffix.
+#[derive(Debug, Default)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.all(key).into_iter().next()
+    }
+
+    fn all(&self, key: &str) -> Vec<&str> {
+        let array_key = format!("{}[]", key);
+        self.0
+            .iter()
+            .filter(|&&(ref k, _)| *k == key || *k == array_key)
+            .map(|&(_, ref v)| v.as_str())
+            .collect()
+    }
+}
+
+impl<'f> FromForm<'f> for Params {
+    type Error = String;
+
+    fn from_form(items: &mut FormItems<'f>, _strict: bool) -> ::std::result::Result<Params, String> {
+        let mut params = vec![];
+        for (k, v) in items {
+            match (k.url_decode(), v.url_decode()) {
+                (Ok(key), Ok(value)) => params.push((key, value)),
+                _ => return Err(format!("Malformed parameter {}", k.as_str())),
+            }
+        }
+        Ok(Params(params))
+    }
+}
+
+/// The responses of the Micropub endpoints.
+pub enum Reply {
+    /// A post or file was created at the given URL.
+    Created(String),
+    NoContent,
+    Json(Value),
+    /// An error with one of the codes from the Micropub spec.
+    Error(Status, &'static str, String),
+}
+
+impl<'r> Responder<'r> for Reply {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            Reply::Created(url) => {
+                Response::build()
+                    .status(Status::Created)
+                    .raw_header("Location", url)
+                    .ok()
+            }
+            Reply::NoContent => Response::build().status(Status::NoContent).ok(),
+            Reply::Json(value) => Json(value).respond_to(request),
+            Reply::Error(status, error, description) => {
+                let mut response = Json(json!({
+                                                  "error": error,
+                                                  "error_description": description,
+                                              }))
+                        .respond_to(request)?;
+                response.set_status(status);
+                Ok(response)
+            }
+        }
+    }
+}
+
+fn invalid_request<S: Into<String>>(description: S) -> Reply {
+    Reply::Error(Status::BadRequest, "invalid_request", description.into())
+}
+
+/// Finds the user for the access token, which is sent either in the
+/// `Authorization` header or as an `access_token` parameter, and checks
+/// that the token has `scope`.
+fn authorize(bearer: &BearerToken,
+             param: Option<&str>,
+             scope: Option<&str>,
+             conn: &PgConnection)
+             -> Result<::std::result::Result<User, Reply>> {
+    let secret = match bearer.0.as_ref().map(|s| s.as_str()).or(param) {
+        Some(secret) => secret,
+        None => {
+            return Ok(Err(Reply::Error(Status::Unauthorized,
+                                       "unauthorized",
+                                       "No access token was sent.".into())))
+        }
+    };
+    match service::token::authenticate(secret, conn)? {
+        Some((token, user)) => {
+            match scope {
+                Some(scope) if !token.has_scope(scope) => {
+                    Ok(Err(Reply::Error(Status::Unauthorized,
+                                        "insufficient_scope",
+                                        format!("The access token lacks the {} scope.", scope))))
+                }
+                _ => Ok(Ok(user)),
+            }
+        }
+        None => {
+            Ok(Err(Reply::Error(Status::Unauthorized,
+                                "unauthorized",
+                                "The access token is invalid.".into())))
+        }
+    }
+}
+
+/// The properties of an `h-entry` that map onto a post.
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    name: Option<String>,
+    content: Option<String>,
+    categories: Vec<String>,
+    status: Option<String>,
+}
+
+/// Returns a property value as a string. Content may also be an object
+/// with `html` or `value`.
+fn property_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Object(ref obj) => {
+            obj.get("html")
+                .or_else(|| obj.get("value"))
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        }
+        _ => None,
+    }
+}
+
+fn property_strings(values: &Value) -> Vec<String> {
+    values
+        .as_array()
+        .map(|values| values.iter().filter_map(property_string).collect())
+        .unwrap_or_default()
+}
+
+impl Entry {
+    fn from_params(params: &Params) -> Entry {
+        Entry {
+            name: params.get("name").map(String::from),
+            content: params.get("content").map(String::from),
+            categories: params.all("category").into_iter().map(String::from).collect(),
+            status: params.get("post-status").map(String::from),
+        }
+    }
+
+    /// Reads the entry from the `properties` of a JSON request.
+    fn from_json(properties: &Value) -> Entry {
+        let mut entry = Entry::default();
+        entry.set_properties(properties);
+        entry
+    }
+
+    fn from_post(post: &Post) -> Entry {
+        Entry {
+            name: Some(post.title.clone()),
+            content: Some(post.markdown_content.clone()),
+            categories: post.tags.clone(),
+            status: Some(if post.published { "published" } else { "draft" }.into()),
+        }
+    }
+
+    /// Sets the properties present in `properties`, replacing their values.
+    fn set_properties(&mut self, properties: &Value) {
+        if let Some(obj) = properties.as_object() {
+            for (key, values) in obj {
+                let mut values = property_strings(values);
+                match key.as_str() {
+                    "name" => self.name = values.drain(..).next(),
+                    "content" => self.content = values.drain(..).next(),
+                    "category" => self.categories = values,
+                    "post-status" => self.status = values.drain(..).next(),
+                    other => debug!("Ignoring Micropub property {}", other),
+                }
+            }
+        }
+    }
+
+    /// Applies the `replace`, `add` and `delete` parts of an update request.
+    fn update(&mut self, request: &Value) {
+        self.set_properties(&request["replace"]);
+        if let Some(obj) = request["add"].as_object() {
+            for (key, values) in obj {
+                let values = property_strings(values);
+                if key == "category" {
+                    self.categories.extend(values);
+                } else {
+                    let mut single = json!({});
+                    single[key] = json!(values);
+                    self.set_properties(&single);
+                }
+            }
+        }
+        match request["delete"] {
+            Value::Array(ref keys) => {
+                for key in keys.iter().filter_map(|k| k.as_str()) {
+                    match key {
+                        "name" => self.name = None,
+                        "category" => self.categories.clear(),
+                        "post-status" => self.status = None,
+                        _ => {}
+                    }
+                }
+            }
+            Value::Object(ref obj) => {
+                if let Some(values) = obj.get("category") {
+                    let removed = property_strings(values);
+                    self.categories.retain(|c| !removed.contains(c));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Without a `post-status` the post is `published`: true for new posts,
+    /// and the post's current state for updates, which may have deleted
+    /// the status.
+    fn into_request(self, published: bool) -> ::std::result::Result<CreatePostRequest, String> {
+        let content = self.content.unwrap_or_default();
+        if content.trim().is_empty() {
+            return Err("The content must not be empty.".into());
+        }
+        // Notes have no name, so their title is taken from the content.
+        let title = match self.name.as_ref().map(|s| s.trim()) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => title_from_content(&content),
+        };
+        let title = title.chars().take(MAX_TITLE_LENGTH).collect();
+        // Categories can be several words, but tags are single words.
+        // Categories that are URLs, such as person tags, are left out.
+        let categories: Vec<String> = self.categories
+            .iter()
+            .filter(|c| !c.contains(|ch| ch == '/' || ch == '?' || ch == '#'))
+            .map(|c| c.split_whitespace().collect::<Vec<_>>().join("-"))
+            .collect();
+        Ok(CreatePostRequest {
+               title,
+               markdown_content: content,
+               content: None,
+               tags: model::normalize_tags(&categories.join(" ")),
+               created_on: UTC::now(),
+               published: self.status.as_ref().map(|s| s != "draft").unwrap_or(published),
+               publish_at: None,
+               language: model::default_language(),
+           })
+    }
+}
+
+/// The first line of the content without Markdown heading markers,
+/// shortened to a reasonable title length.
+fn title_from_content(content: &str) -> String {
+    const LENGTH: usize = 60;
+
+    let line = content
+        .lines()
+        .map(|l| l.trim_left_matches('#').trim())
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    if line.chars().count() > LENGTH {
+        let mut title: String = line.chars().take(LENGTH - 1).collect();
+        title.push('\u{2026}');
+        title
+    } else {
+        line.to_string()
+    }
+}
+
+fn create(entry: Entry, user: &User, conn: &PgConnection) -> Result<Reply> {
+    let mut request = match entry.into_request(true) {
+        Ok(request) => request,
+        Err(message) => return Ok(invalid_request(message)),
+    };
+    request.convert_markdown();
+    let post = service::post::insert_post(request, user, conn)?;
+    publisher::announce(&post, None, conn)?;
+    info!("Created post {} via Micropub", post.id);
+    Ok(Reply::Created(util::post_url(post.id)))
+}
+
+/// Finds the post of `user` at `url`.
+fn find_post(url: Option<&str>, user: &User, conn: &PgConnection) -> Result<::std::result::Result<Post, Reply>> {
+    let post_id = match url.and_then(util::post_id_from_url) {
+        Some(id) => id,
+        None => return Ok(Err(invalid_request("The url is not a post on this site."))),
+    };
+    match service::post::find_owned(post_id, user.id, conn)? {
+        Some(post) => Ok(Ok(post)),
+        None => Ok(Err(invalid_request("There is no such post."))),
+    }
+}
+
+fn update(request: &Value, user: &User, conn: &PgConnection) -> Result<Reply> {
+    let mut post = match find_post(request["url"].as_str(), user, conn)? {
+        Ok(post) => post,
+        Err(reply) => return Ok(reply),
+    };
+    let mut entry = Entry::from_post(&post);
+    entry.update(request);
+    let mut changes = match entry.into_request(post.published) {
+        Ok(changes) => changes,
+        Err(message) => return Ok(invalid_request(message)),
+    };
+    changes.publish_at = post.publish_at;
+    changes.language = post.language.clone();
+    changes.convert_markdown();
+    let previous_content = if post.published { Some(post.content.clone()) } else { None };
+    changes.apply_to(&mut post);
+    let post = service::post::update_post(&post, user.id, conn)?;
+    publisher::announce(&post, previous_content.as_ref().map(|s| s.as_str()), conn)?;
+    info!("Updated post {} via Micropub", post.id);
+    Ok(Reply::NoContent)
+}
+
+fn delete(url: Option<&str>, user: &User, conn: &PgConnection) -> Result<Reply> {
+    let post = match find_post(url, user, conn)? {
+        Ok(post) => post,
+        Err(reply) => return Ok(reply),
+    };
+    activitypub::queue_delete(&post, conn)?;
+    service::post::delete(post.id, conn)?;
+    service::audit::record("post_deleted", &user.name, &format!("{} via Micropub", post.id), conn)?;
+    Ok(Reply::NoContent)
+}
+
+/// The supported actions, which double as the token scope they require.
+const ACTIONS: &[&str] = &["create", "update", "delete"];
+
+#[post("/micropub", format = "application/json", data = "<data>")]
+pub fn post_json(data: Json<Value>, bearer: BearerToken, conn: Connection) -> Result<Reply> {
+    let request = data.into_inner();
+    let action = request["action"].as_str().unwrap_or("create");
+    if !ACTIONS.contains(&action) {
+        return Ok(invalid_request(format!("Unsupported action {}.", action)));
+    }
+    let user = match authorize(&bearer, None, Some(action), &conn)? {
+        Ok(user) => user,
+        Err(reply) => return Ok(reply),
+    };
+    match action {
+        "create" => {
+            if request["type"][0].as_str() != Some("h-entry") {
+                return Ok(invalid_request("Only h-entry posts are supported."));
+            }
+            create(Entry::from_json(&request["properties"]), &user, &conn)
+        }
+        "update" => update(&request, &user, &conn),
+        _ => delete(request["url"].as_str(), &user, &conn),
+    }
+}
+
+#[post("/micropub", data = "<data>", rank = 2)]
+pub fn post_form(data: Form<Params>, bearer: BearerToken, conn: Connection) -> Result<Reply> {
+    let params = data.into_inner();
+    let action = params.get("action").unwrap_or("create");
+    if !ACTIONS.contains(&action) {
+        return Ok(invalid_request(format!("Unsupported action {}.", action)));
+    }
+    let user = match authorize(&bearer, params.get("access_token"), Some(action), &conn)? {
+        Ok(user) => user,
+        Err(reply) => return Ok(reply),
+    };
+    match action {
+        "create" => {
+            if params.get("h") != Some("entry") {
+                return Ok(invalid_request("Only h-entry posts are supported."));
+            }
+            create(Entry::from_params(&params), &user, &conn)
+        }
+        "update" => Ok(invalid_request("Updates must be sent as JSON.")),
+        _ => delete(params.get("url"), &user, &conn),
+    }
+}
+
+#[get("/micropub?<params>")]
+pub fn query(params: Params, bearer: BearerToken, conn: Connection) -> Result<Reply> {
+    let user = match authorize(&bearer, params.get("access_token"), None, &conn)? {
+        Ok(user) => user,
+        Err(reply) => return Ok(reply),
+    };
+    match params.get("q") {
+        Some("config") => {
+            Ok(Reply::Json(json!({
+                "media-endpoint": format!("{}/micropub/media", config::base_url()),
+                "syndicate-to": [],
+            })))
+        }
+        Some("syndicate-to") => Ok(Reply::Json(json!({ "syndicate-to": [] }))),
+        Some("source") => {
+            let post = match find_post(params.get("url"), &user, &conn)? {
+                Ok(post) => post,
+                Err(reply) => return Ok(reply),
+            };
+            let properties = json!({
+                "name": [post.title],
+                "content": [post.markdown_content],
+                "category": post.tags,
+                "post-status": [if post.published { "published" } else { "draft" }],
+                "published": [post.created_on.to_rfc3339()],
+            });
+            let wanted = params.all("properties");
+            if !wanted.is_empty() {
+                let selected: Map<String, Value> = properties
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|&(ref key, _)| wanted.contains(&key.as_str()))
+                    .collect();
+                return Ok(Reply::Json(json!({ "properties": selected })));
+            }
+            Ok(Reply::Json(json!({ "type": ["h-entry"], "properties": properties })))
+        }
+        _ => Ok(invalid_request("Unsupported query.")),
+    }
+}
+
+#[post("/micropub/media", data = "<data>")]
+pub fn upload_media(data: Data,
+                    content_type: ContentType,
+                    bearer: BearerToken,
+                    library: State<MediaLibrary>,
+                    conn: Connection)
+                    -> Result<Reply> {
+    let user = match authorize(&bearer, None, Some("media"), &conn)? {
+        Ok(user) => user,
+        Err(reply) => return Ok(reply),
+    };
+    let upload = match media::read_upload(data, &content_type, "file") {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(invalid_request("The request has no file.")),
+        Err(e) => return Ok(invalid_request(e.to_string())),
+    };
+    if let Some(message) = media::upload_error(&upload.data) {
+        return Ok(invalid_request(message));
+    }
+    let file = library.store(user.id, &upload, &conn)?;
+    Ok(Reply::Created(util::media_url(&file.name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: Option<&str>) -> Entry {
+        Entry {
+            content: Some("Hello".into()),
+            status: status.map(String::from),
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn status_decides_publishing() {
+        assert!(!entry(Some("draft")).into_request(true).unwrap().published);
+        assert!(entry(Some("published")).into_request(false).unwrap().published);
+    }
+
+    #[test]
+    fn missing_status_keeps_the_default() {
+        assert!(entry(None).into_request(true).unwrap().published);
+        assert!(!entry(None).into_request(false).unwrap().published);
+    }
+
+    #[test]
+    fn deleting_the_status_keeps_a_draft_unpublished() {
+        let mut entry = entry(Some("draft"));
+        entry.update(&json!({"delete": ["post-status"]}));
+        assert_eq!(entry.status, None);
+        assert!(!entry.into_request(false).unwrap().published);
+    }
+}
@@ -1,14 +1,17 @@
 use std::collections::HashMap;
+use std::fmt;
 
-use chrono::{DateTime, UTC, Datelike};
+use chrono::{DateTime, NaiveDateTime, UTC, Datelike};
 use schema::*;
 use util;
+use password;
 use rocket::request::{FromForm, FormItems};
 use regex::Regex;
 use serde_json::{self, Value};
 
 #[derive(PartialEq, Eq, Debug, Clone, Queryable, Identifiable, Serialize, Deserialize, AsChangeset)]
 #[table_name = "posts"]
+#[changeset_options(treat_none_as_null = "true")]
 pub struct Post {
     pub title: String,
     pub content: String,
@@ -18,6 +21,8 @@ pub struct Post {
     pub tags: Vec<String>,
     pub published: bool,
     pub markdown_content: String,
+    pub publish_at: Option<DateTime<UTC>>,
+    pub language: String,
 }
 
 impl Post {
@@ -27,10 +32,21 @@ impl Post {
             let mut obj = value.as_object_mut().unwrap();
             obj.insert("created_on_short".to_string(),
                        Value::String(format!("{}", self.created_on.format("%Y-%m-%d"))));
+            obj.insert("status".to_string(), Value::String(self.status().into()));
         }
         value
     }
 
+    /// Either `published`, `scheduled` (not yet published, but with a
+    /// publishing date) or `draft`.
+    pub fn status(&self) -> &'static str {
+        match (self.published, self.publish_at) {
+            (true, _) => "published",
+            (false, Some(_)) => "scheduled",
+            (false, None) => "draft",
+        }
+    }
+
     pub fn url(&self) -> String {
         let title = self.title.replace(' ', "-").to_lowercase();
         format!("{}/{}/{}/{}", 
@@ -49,73 +65,299 @@ pub struct User {
     #[serde(default)]
     pub pw_hash: String,
     pub id: i32,
+    #[serde(default)]
+    pub is_admin: bool,
 }
 
 impl User {
     pub fn verify_password(&self, cleartext_pw: &str) -> bool {
-        use ring_pwhash::scrypt;
-
-        scrypt::scrypt_check(cleartext_pw, &self.pw_hash).unwrap_or(false)
+        password::verify(cleartext_pw, &self.pw_hash)
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Insertable)]
-#[table_name = "posts"]
+/// A new or edited post as submitted by its author. Deliberately has no
+/// owner: that always comes from the authenticated user.
+#[derive(Debug, Clone, Deserialize)]
 pub struct CreatePostRequest {
     pub title: String,
     pub markdown_content: String,
     pub content: Option<String>,
     pub tags: Vec<String>,
-    pub owner_id: i32,
     #[serde(default = "UTC::now")]
     pub created_on: DateTime<UTC>,
     pub published: bool,
+    #[serde(default)]
+    pub publish_at: Option<DateTime<UTC>>,
+    #[serde(default = "default_language")]
+    pub language: String,
+}
+
+/// Text search configurations a post can be written in.
+pub const LANGUAGES: &[&str] = &["simple", "danish", "dutch", "english", "finnish", "french", "german",
+                                 "hungarian", "italian", "norwegian", "portuguese", "romanian",
+                                 "russian", "spanish", "swedish", "turkish"];
+
+pub fn default_language() -> String {
+    "english".into()
 }
 
 impl CreatePostRequest {
     pub fn convert_markdown(&mut self) {
         self.content = Some(util::markdown_to_html(&self.markdown_content));
     }
+
+    pub fn into_new_post(self, owner: &User) -> NewPost {
+        NewPost {
+            content: self.content.unwrap_or_else(|| util::markdown_to_html(&self.markdown_content)),
+            title: self.title,
+            markdown_content: self.markdown_content,
+            tags: self.tags,
+            owner_id: owner.id,
+            created_on: self.created_on,
+            published: self.published,
+            publish_at: self.publish_at,
+            language: self.language,
+        }
+    }
+
+    /// Copies the editable fields onto an existing post. Ownership and
+    /// creation date are left untouched.
+    pub fn apply_to(self, post: &mut Post) {
+        post.content = self.content.unwrap_or_else(|| util::markdown_to_html(&self.markdown_content));
+        post.title = self.title;
+        post.markdown_content = self.markdown_content;
+        post.tags = self.tags;
+        post.published = self.published;
+        post.publish_at = self.publish_at;
+        post.language = self.language;
+    }
 }
 
-impl<'r> FromForm<'r> for CreatePostRequest {
-    type Error = String;
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "posts"]
+pub struct NewPost {
+    pub title: String,
+    pub markdown_content: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub owner_id: i32,
+    pub created_on: DateTime<UTC>,
+    pub published: bool,
+    pub publish_at: Option<DateTime<UTC>>,
+    pub language: String,
+}
 
-    fn from_form(form_items: &mut FormItems<'r>, _strict: bool) -> Result<CreatePostRequest, Self::Error> {
-        lazy_static! {
-            static ref TAGS_REGEX: Regex = Regex::new("\\s").unwrap();
+/// Parses an RFC 3339 timestamp, or a `datetime-local` input value without
+/// an offset, which is taken to be UTC.
+fn parse_datetime(s: &str) -> Option<DateTime<UTC>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&UTC))
+        .ok()
+        .or_else(|| {
+                     NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M")
+                         .ok()
+                         .map(|t| DateTime::from_utc(t, UTC))
+                 })
+}
+
+/// Tags end up in URLs, so they must not contain characters that end a
+/// path segment.
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.contains(|c| c == '/' || c == '?' || c == '#')
+}
+
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Splits whitespace-separated tags and normalizes them: lowercase, without
+/// empty entries or duplicates, in the order they were given.
+pub fn normalize_tags(input: &str) -> Vec<String> {
+    lazy_static! {
+        static ref TAGS_REGEX: Regex = Regex::new("\\s+").unwrap();
+    }
+
+    let mut tags: Vec<String> = vec![];
+    for tag in TAGS_REGEX.split(input) {
+        let tag = normalize_tag(tag);
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Maximum length of a post title, in characters.
+pub const MAX_TITLE_LENGTH: usize = 200;
+
+/// A single problem with a submitted post form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostFieldError {
+    EmptyTitle,
+    TitleTooLong,
+    EmptyBody,
+    InvalidTag(String),
+    /// The form tried to set the post's owner.
+    BadOwner,
+    InvalidPublishDate(String),
+    InvalidLanguage(String),
+    /// A value that could not be URL-decoded.
+    Malformed(String),
+}
+
+impl PostFieldError {
+    /// The name of the form field the error belongs to.
+    pub fn field(&self) -> &str {
+        match *self {
+            PostFieldError::EmptyTitle |
+            PostFieldError::TitleTooLong => "title",
+            PostFieldError::EmptyBody => "markdown_content",
+            PostFieldError::InvalidTag(_) => "tags",
+            PostFieldError::BadOwner => "owner_id",
+            PostFieldError::InvalidPublishDate(_) => "publish_at",
+            PostFieldError::InvalidLanguage(_) => "language",
+            PostFieldError::Malformed(ref field) => field,
+        }
+    }
+}
+
+impl fmt::Display for PostFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostFieldError::EmptyTitle => write!(f, "The title must not be empty."),
+            PostFieldError::TitleTooLong => {
+                write!(f, "The title must not be longer than {} characters.", MAX_TITLE_LENGTH)
+            }
+            PostFieldError::EmptyBody => write!(f, "The post must have some content."),
+            PostFieldError::InvalidTag(ref tag) => {
+                write!(f, "Invalid tag \"{}\": tags must not contain '/', '?' or '#'.", tag)
+            }
+            PostFieldError::BadOwner => write!(f, "The owner of a post cannot be set through the form."),
+            PostFieldError::InvalidPublishDate(ref date) => write!(f, "Invalid publishing date: {}", date),
+            PostFieldError::InvalidLanguage(ref language) => write!(f, "Unsupported language: {}", language),
+            PostFieldError::Malformed(ref field) => write!(f, "Malformed value for {}.", field),
+        }
+    }
+}
+
+/// Returned when a post form does not validate. Keeps the submitted values
+/// so the editor can be shown again without losing the author's text.
+#[derive(Debug, Clone)]
+pub struct PostFormError {
+    pub errors: Vec<PostFieldError>,
+    pub input: HashMap<String, String>,
+}
+
+impl PostFormError {
+    /// Returns the messages keyed by field name, one message per field.
+    pub fn messages(&self) -> Value {
+        let mut messages = serde_json::Map::new();
+        for error in &self.errors {
+            if !messages.contains_key(error.field()) {
+                messages.insert(error.field().to_string(), Value::String(error.to_string()));
+            }
         }
-        const KEYS: &[&str] = &["title", "markdown_content", "tags", "owner_id", "published"];
+        Value::Object(messages)
+    }
+}
 
+impl fmt::Display for PostFormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<_> = self.errors.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", messages.join(" "))
+    }
+}
+
+impl<'r> FromForm<'r> for CreatePostRequest {
+    type Error = PostFormError;
+
+    fn from_form(form_items: &mut FormItems<'r>, _strict: bool) -> Result<CreatePostRequest, Self::Error> {
+        let mut errors = vec![];
         let mut items = HashMap::new();
         for (k, v) in form_items {
-            let decoded = v.url_decode().map_err(|e| format!("{}", e))?;
-            items.insert(k.as_str(), decoded);
+            match v.url_decode() {
+                Ok(decoded) => {
+                    items.insert(k.as_str().to_string(), decoded);
+                }
+                Err(_) => errors.push(PostFieldError::Malformed(k.as_str().to_string())),
+            }
         }
 
-        for key in KEYS {
-            if !items.contains_key(key) {
-                return Err(format!("Missing form parameter: {}", key));
+        let title = items.get("title").map(|s| s.trim().to_string()).unwrap_or_default();
+        if title.is_empty() {
+            errors.push(PostFieldError::EmptyTitle);
+        } else if title.chars().count() > MAX_TITLE_LENGTH {
+            errors.push(PostFieldError::TitleTooLong);
+        }
+
+        let markdown_content = items.get("markdown_content").cloned().unwrap_or_default();
+        if markdown_content.trim().is_empty() {
+            errors.push(PostFieldError::EmptyBody);
+        }
+
+        let tags = normalize_tags(items.get("tags").map(|s| s.as_str()).unwrap_or(""));
+        for tag in &tags {
+            if !is_valid_tag(tag) {
+                errors.push(PostFieldError::InvalidTag(tag.clone()));
             }
         }
 
-        let mut tags = vec![];
-        for tag in TAGS_REGEX.split(&items["tags"]) {
-            tags.push(tag.into());
+        // Posts always belong to the logged in user. A form that tries to
+        // say otherwise is rejected rather than silently corrected.
+        if items.contains_key("owner_id") {
+            warn!("Rejecting post form with owner_id {:?}", items["owner_id"]);
+            errors.push(PostFieldError::BadOwner);
         }
 
-        let owner = items["owner_id"]
-            .parse()
-            .map_err(|e| format!("Failed to parse owner ID: {}", e))?;
-        let published = items["published"].parse().unwrap_or(false);
+        // Unchecked checkboxes are not sent at all.
+        let mut published = items.get("published").map(|s| s == "true" || s == "on").unwrap_or(false);
+        let publish_at = match items.get("publish_at").map(|s| s.trim()) {
+            Some("") | None => None,
+            Some(s) => {
+                let date = parse_datetime(s);
+                if date.is_none() {
+                    errors.push(PostFieldError::InvalidPublishDate(s.into()));
+                }
+                date
+            }
+        };
+        // A post scheduled for the future stays unpublished until the
+        // publisher picks it up. A date that has passed was already used,
+        // keeping it would have the publisher publish an unpublished post
+        // again.
+        let publish_at = match publish_at {
+            Some(t) if t > UTC::now() => {
+                published = false;
+                Some(t)
+            }
+            _ => None,
+        };
+
+        let language = match items.get("language").map(|s| s.trim()) {
+            Some("") | None => default_language(),
+            Some(s) => {
+                if !LANGUAGES.contains(&s) {
+                    errors.push(PostFieldError::InvalidLanguage(s.into()));
+                }
+                s.to_string()
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(PostFormError {
+                           errors,
+                           input: items,
+                       });
+        }
 
         Ok(CreatePostRequest {
-               title: items["title"].clone(),
-               markdown_content: items["markdown_content"].clone(),
+               title: title,
+               markdown_content: markdown_content,
                tags: tags,
-               owner_id: owner,
                created_on: UTC::now(),
                published: published,
+               publish_at: publish_at,
+               language: language,
                content: None,
            })
     }
@@ -126,6 +368,12 @@ pub struct CreateUserRequest {
     pub name: String,
     pub password: String,
     pub password_repeated: String,
+    /// Honeypot field, hidden from humans.
+    #[serde(default)]
+    pub website: Option<String>,
+    /// When the form was rendered, as stamped by `spam::stamp`.
+    #[serde(default)]
+    pub rendered_at: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, FromForm, Debug)]
@@ -133,3 +381,606 @@ pub struct LoginRequest {
     pub name: String,
     pub password: String,
 }
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct PostRevision {
+    pub id: i32,
+    pub post_id: i32,
+    pub author_id: i32,
+    pub created_on: DateTime<UTC>,
+    pub title: String,
+    pub markdown_content: String,
+    pub tags: Vec<String>,
+}
+
+impl PostRevision {
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("created_on_short".to_string(),
+                       Value::String(format!("{}", self.created_on.format("%Y-%m-%d %H:%M"))));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "post_revisions"]
+pub struct NewPostRevision {
+    pub post_id: i32,
+    pub author_id: i32,
+    pub title: String,
+    pub markdown_content: String,
+    pub tags: Vec<String>,
+}
+
+impl NewPostRevision {
+    pub fn from_post(post: &Post, author_id: i32) -> NewPostRevision {
+        NewPostRevision {
+            post_id: post.id,
+            author_id,
+            title: post.title.clone(),
+            markdown_content: post.markdown_content.clone(),
+            tags: post.tags.clone(),
+        }
+    }
+}
+
+/// A published post matching a full-text search. The snippet is HTML with
+/// the matched terms wrapped in `<mark>`.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct SearchResult {
+    pub id: i32,
+    pub title: String,
+    pub snippet: String,
+    pub created_on: DateTime<UTC>,
+    pub rank: f32,
+}
+
+#[derive(Debug, FromForm)]
+pub struct SearchQuery {
+    pub q: String,
+    pub page: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStatus {
+    Pending,
+    Approved,
+    Spam,
+}
+
+impl CommentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CommentStatus::Pending => "pending",
+            CommentStatus::Approved => "approved",
+            CommentStatus::Spam => "spam",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct Comment {
+    pub id: i32,
+    pub post_id: i32,
+    pub parent_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub author_name: String,
+    #[serde(skip_serializing)]
+    pub author_email: Option<String>,
+    pub markdown_content: String,
+    pub content: String,
+    pub status: String,
+    pub created_on: DateTime<UTC>,
+    pub spam_score: Option<f32>,
+    #[serde(skip_serializing)]
+    pub trained_as: Option<String>,
+    /// The ActivityPub id of a reply received from another server.
+    pub remote_id: Option<String>,
+    pub author_url: Option<String>,
+}
+
+impl Comment {
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("created_on_short".to_string(),
+                       Value::String(format!("{}", self.created_on.format("%Y-%m-%d %H:%M"))));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "comments"]
+pub struct NewComment {
+    pub post_id: i32,
+    pub parent_id: Option<i32>,
+    pub user_id: Option<i32>,
+    pub author_name: String,
+    pub author_email: Option<String>,
+    pub markdown_content: String,
+    pub content: String,
+    pub status: String,
+    pub spam_score: Option<f32>,
+    pub remote_id: Option<String>,
+    pub author_url: Option<String>,
+}
+
+/// A comment as submitted from the form under a post. Name and email are
+/// only used for readers who are not logged in.
+#[derive(Debug, FromForm)]
+pub struct CommentForm {
+    pub body: String,
+    pub parent_id: Option<i32>,
+    pub author_name: Option<String>,
+    pub author_email: Option<String>,
+    /// Honeypot field, hidden from humans.
+    pub website: Option<String>,
+    /// When the form was rendered, as stamped by `spam::stamp`.
+    pub rendered_at: Option<String>,
+}
+
+/// The number of published posts in a month.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct ArchiveMonth {
+    pub year: i32,
+    pub month: i32,
+    pub count: i64,
+}
+
+impl ArchiveMonth {
+    pub fn month_name(&self) -> &'static str {
+        const MONTHS: &[&str] = &["January", "February", "March", "April", "May", "June", "July",
+                                  "August", "September", "October", "November", "December"];
+        MONTHS[(self.month - 1) as usize]
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "year": self.year,
+            "month": self.month,
+            "month_name": self.month_name(),
+            "count": self.count,
+        })
+    }
+}
+
+/// A tag with the number of published posts carrying it.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+#[derive(Debug, FromForm)]
+pub struct RenameTagRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Query parameters for comparing two revisions of a post.
+#[derive(Debug, FromForm)]
+pub struct RevisionRange {
+    pub from: i32,
+    pub to: i32,
+}
+
+/// Autosaved editor state, kept apart from the post until the author saves
+/// the post explicitly. `post_id` is `None` for a post that does not exist yet.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct Draft {
+    pub id: i32,
+    pub post_id: Option<i32>,
+    pub owner_id: i32,
+    pub title: String,
+    pub markdown_content: String,
+    pub tags: String,
+    pub updated_on: DateTime<UTC>,
+}
+
+impl Draft {
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("updated_on_short".to_string(),
+                       Value::String(format!("{}", self.updated_on.format("%H:%M:%S"))));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreviewRequest {
+    pub markdown_content: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SaveDraftRequest {
+    #[serde(default)]
+    pub post_id: Option<i32>,
+    pub title: String,
+    pub markdown_content: String,
+    #[serde(default)]
+    pub tags: String,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset)]
+#[table_name = "login_attempts"]
+pub struct LoginAttempt {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure: DateTime<UTC>,
+    pub locked_until: Option<DateTime<UTC>>,
+}
+
+/// A row of the spam classifier's token counts, see `spam::BayesClassifier`.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "spam_tokens"]
+pub struct NewSpamToken {
+    pub token: String,
+    pub spam_count: i32,
+    pub ham_count: i32,
+}
+
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub event: String,
+    pub subject: String,
+    pub detail: String,
+    pub created_on: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry {
+    pub event: String,
+    pub subject: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MentionStatus {
+    /// Received, the source has not been fetched yet.
+    Pending,
+    /// The source exists and links to the target.
+    Verified,
+    Rejected,
+}
+
+impl MentionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MentionStatus::Pending => "pending",
+            MentionStatus::Verified => "verified",
+            MentionStatus::Rejected => "rejected",
+        }
+    }
+}
+
+/// A webmention received for one of our posts: `source` claims to link to
+/// `target`.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct Webmention {
+    pub id: i32,
+    pub post_id: i32,
+    pub source: String,
+    pub target: String,
+    pub status: String,
+    pub title: Option<String>,
+    pub created_on: DateTime<UTC>,
+    pub verified_on: Option<DateTime<UTC>>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_on: DateTime<UTC>,
+}
+
+impl Webmention {
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("created_on_short".to_string(),
+                       Value::String(format!("{}", self.created_on.format("%Y-%m-%d"))));
+            obj.insert("display_title".to_string(),
+                       Value::String(self.title.clone().unwrap_or_else(|| self.source.clone())));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "webmentions"]
+pub struct NewWebmention {
+    pub post_id: i32,
+    pub source: String,
+    pub target: String,
+}
+
+/// The form body of a webmention sent to `/webmention`.
+#[derive(Debug, FromForm)]
+pub struct WebmentionRequest {
+    pub source: String,
+    pub target: String,
+}
+
+/// A webmention to be sent for a link in one of our posts.
+#[derive(Debug, Clone, Queryable)]
+pub struct OutgoingWebmention {
+    pub id: i32,
+    pub post_id: i32,
+    pub source: String,
+    pub target: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_on: DateTime<UTC>,
+    pub created_on: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "outgoing_webmentions"]
+pub struct NewOutgoingWebmention {
+    pub post_id: i32,
+    pub source: String,
+    pub target: String,
+}
+
+/// The scopes an API token can be granted.
+pub const TOKEN_SCOPES: &[&str] = &["create", "update", "delete", "media"];
+
+/// A bearer token for API clients, owned by a user.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    /// Space-separated list of granted scopes.
+    pub scope: String,
+    pub created_on: DateTime<UTC>,
+    pub last_used_on: Option<DateTime<UTC>>,
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("created_on_short".to_string(),
+                       Value::String(format!("{}", self.created_on.format("%Y-%m-%d"))));
+            obj.insert("last_used_on_short".to_string(),
+                       self.last_used_on
+                           .map(|t| Value::String(format!("{}", t.format("%Y-%m-%d %H:%M"))))
+                           .unwrap_or(Value::Null));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "api_tokens"]
+pub struct NewApiToken {
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub scope: String,
+}
+
+/// The form for creating an API token. Unchecked scopes are not sent.
+#[derive(Debug, FromForm)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub create: Option<bool>,
+    pub update: Option<bool>,
+    pub delete: Option<bool>,
+    pub media: Option<bool>,
+}
+
+impl CreateTokenRequest {
+    pub fn scope(&self) -> String {
+        let granted = [self.create, self.update, self.delete, self.media];
+        TOKEN_SCOPES
+            .iter()
+            .zip(granted.iter())
+            .filter(|&(_, granted)| granted.unwrap_or(false))
+            .map(|(scope, _)| *scope)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Query parameters of a WebFinger lookup.
+#[derive(Debug, FromForm)]
+pub struct WebfingerQuery {
+    pub resource: String,
+}
+
+/// The key pair a user signs ActivityPub deliveries with.
+#[derive(Debug, Clone, Queryable)]
+pub struct ActorKey {
+    pub user_id: i32,
+    pub public_key_pem: String,
+    pub private_key_pem: String,
+    pub created_on: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "actor_keys"]
+pub struct NewActorKey {
+    pub user_id: i32,
+    pub public_key_pem: String,
+    pub private_key_pem: String,
+}
+
+/// A remote ActivityPub actor following a user.
+#[derive(Debug, Clone, Queryable)]
+pub struct Follower {
+    pub id: i32,
+    pub user_id: i32,
+    pub actor: String,
+    pub inbox: String,
+    pub created_on: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "followers"]
+pub struct NewFollower {
+    pub user_id: i32,
+    pub actor: String,
+    pub inbox: String,
+}
+
+/// An activity waiting to be delivered to a remote inbox.
+#[derive(Debug, Clone, Queryable)]
+pub struct Delivery {
+    pub id: i32,
+    pub user_id: i32,
+    pub inbox: String,
+    pub activity: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_on: DateTime<UTC>,
+    pub created_on: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "deliveries"]
+pub struct NewDelivery {
+    pub user_id: i32,
+    pub inbox: String,
+    pub activity: String,
+}
+
+/// A file in a user's media library.
+#[derive(Debug, Clone, Queryable, Serialize)]
+pub struct MediaFile {
+    pub id: i32,
+    pub owner_id: i32,
+    /// The name the file is stored and served under.
+    pub name: String,
+    /// The file name it was uploaded with, if the client sent one.
+    pub original_name: Option<String>,
+    pub content_type: String,
+    pub size_bytes: i64,
+    /// Hex encoded SHA-256 of the content.
+    pub checksum: String,
+    pub created_on: DateTime<UTC>,
+    /// Dimensions of images, in pixels.
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+impl MediaFile {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        {
+            let mut obj = value.as_object_mut().unwrap();
+            obj.insert("created_on_short".to_string(),
+                       Value::String(format!("{}", self.created_on.format("%Y-%m-%d"))));
+            obj.insert("url".to_string(), Value::String(util::media_url(&self.name)));
+            obj.insert("is_image".to_string(), Value::Bool(self.is_image()));
+            obj.insert("size_kb".to_string(), json!((self.size_bytes + 1023) / 1024));
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "media"]
+pub struct NewMediaFile {
+    pub owner_id: i32,
+    pub name: String,
+    pub original_name: Option<String>,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub checksum: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+/// A resized copy of a stored image.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "media_variants"]
+pub struct MediaVariant {
+    pub name: String,
+    /// The name of the stored original.
+    pub original: String,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size_bytes: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::request::{FormItems, FromForm};
+
+    use super::*;
+
+    fn parse(form: &str) -> Result<CreatePostRequest, PostFormError> {
+        CreatePostRequest::from_form(&mut FormItems::from(form), true)
+    }
+
+    #[test]
+    fn post_form_without_owner_is_accepted() {
+        let request = parse("title=Hello&markdown_content=Some%20text&tags=rust%20web").unwrap();
+        assert_eq!(request.title, "Hello");
+        assert_eq!(request.tags, vec!["rust", "web"]);
+    }
+
+    #[test]
+    fn post_form_rejects_owner_id() {
+        let error = parse("title=Hello&markdown_content=Some%20text&owner_id=2").unwrap_err();
+        assert_eq!(error.errors, vec![PostFieldError::BadOwner]);
+        assert_eq!(error.messages()["owner_id"],
+                   "The owner of a post cannot be set through the form.");
+    }
+
+    #[test]
+    fn post_form_rejects_empty_owner_id() {
+        let error = parse("owner_id=&title=Hello&markdown_content=Some%20text").unwrap_err();
+        assert!(error.errors.contains(&PostFieldError::BadOwner));
+    }
+
+    #[test]
+    fn post_form_keeps_future_publish_date() {
+        let request = parse("title=Hello&markdown_content=Text&published=on&publish_at=2999-01-01T10%3A00%3A00Z")
+            .unwrap();
+        assert!(!request.published);
+        assert!(request.publish_at.is_some());
+    }
+
+    #[test]
+    fn post_form_drops_past_publish_date() {
+        let request = parse("title=Hello&markdown_content=Text&publish_at=2017-01-01T10%3A00%3A00Z").unwrap();
+        assert!(!request.published);
+        assert_eq!(request.publish_at, None);
+    }
+
+    #[test]
+    fn post_json_ignores_owner_id() {
+        // The JSON API deserializes the request directly. It has no owner
+        // field, so a sent one is dropped.
+        let request: CreatePostRequest = serde_json::from_str(r#"{"title": "Hello",
+                "markdown_content": "Some text", "tags": [], "published": true,
+                "owner_id": 2}"#)
+                .unwrap();
+        assert_eq!(request.title, "Hello");
+    }
+}
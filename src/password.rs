@@ -0,0 +1,247 @@
+use std::str::FromStr;
+
+use argon2;
+use base64;
+use rand::{OsRng, Rng};
+use ring_pwhash::scrypt;
+
+use errors::*;
+
+const SCRYPT_PREFIX: &str = "$rscrypt$";
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+
+/// The algorithm used for new password hashes. Stored hashes carry their
+/// algorithm in their prefix (`$rscrypt$` or `$argon2id$`), so changing
+/// this does not invalidate existing passwords.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Scrypt,
+    Argon2id,
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Algorithm> {
+        match s {
+            "scrypt" => Ok(Algorithm::Scrypt),
+            "argon2id" => Ok(Algorithm::Argon2id),
+            other => Err(format!("Unknown password hash algorithm: {}", other).into()),
+        }
+    }
+}
+
+/// Work factors for new password hashes, part of the `Settings`. Managed
+/// as Rocket state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HashParams {
+    pub algorithm: Algorithm,
+    pub scrypt_log_n: u8,
+    pub scrypt_r: u32,
+    pub scrypt_p: u32,
+    /// Memory cost in KiB.
+    pub argon2_mem_cost: u32,
+    pub argon2_time_cost: u32,
+    pub argon2_lanes: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> HashParams {
+        HashParams {
+            algorithm: Algorithm::Scrypt,
+            scrypt_log_n: 14,
+            scrypt_r: 8,
+            scrypt_p: 1,
+            argon2_mem_cost: 65536,
+            argon2_time_cost: 3,
+            argon2_lanes: 1,
+        }
+    }
+}
+
+/// Hashing may use at most this much memory per password, in KiB.
+const MAX_MEMORY_KIB: u64 = 1024 * 1024;
+
+impl HashParams {
+    /// Describes the parameters the hash functions would reject or panic
+    /// on, for `Settings::validate`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let (log_n, r, p) = (self.scrypt_log_n as u64, self.scrypt_r as u64, self.scrypt_p as u64);
+        if log_n < 1 || log_n > 63 {
+            problems.push(format!("password.scrypt_log_n must be between 1 and 63, not {}", log_n));
+        } else if r < 1 || p < 1 {
+            problems.push("password.scrypt_r and password.scrypt_p must be at least 1".to_string());
+        } else if log_n >= r * 16 {
+            problems.push(format!("password.scrypt_log_n must be less than 16 * scrypt_r, {}", r * 16));
+        } else if r * p >= 1 << 30 {
+            problems.push("password.scrypt_r * password.scrypt_p must be less than 2^30".to_string());
+        } else if r.checked_mul(1 << log_n).map(|blocks| blocks / 8 > MAX_MEMORY_KIB).unwrap_or(true) {
+            // scrypt uses 128 * r * 2^log_n bytes, eight 128 byte blocks are a KiB.
+            problems.push(format!("scrypt with password.scrypt_log_n {} and scrypt_r {} needs more than {} KiB",
+                                  log_n,
+                                  r,
+                                  MAX_MEMORY_KIB));
+        }
+        if self.argon2_lanes < 1 || self.argon2_lanes > 0xffffff {
+            problems.push(format!("password.argon2_lanes must be between 1 and 16777215, not {}",
+                                  self.argon2_lanes));
+        } else if (self.argon2_mem_cost as u64) < 8 * self.argon2_lanes as u64 {
+            problems.push(format!("password.argon2_mem_cost must be at least 8 KiB per lane, {}",
+                                  8 * self.argon2_lanes as u64));
+        }
+        if self.argon2_mem_cost as u64 > MAX_MEMORY_KIB {
+            problems.push(format!("password.argon2_mem_cost must be at most {} KiB", MAX_MEMORY_KIB));
+        }
+        if self.argon2_time_cost < 1 {
+            problems.push("password.argon2_time_cost must be at least 1".to_string());
+        }
+        problems
+    }
+
+    fn argon2_config(&self) -> argon2::Config {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            mem_cost: self.argon2_mem_cost,
+            time_cost: self.argon2_time_cost,
+            lanes: self.argon2_lanes,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+pub fn hash(password: &str, params: &HashParams) -> Result<String> {
+    match params.algorithm {
+        Algorithm::Scrypt => {
+            let scrypt_params =
+                scrypt::ScryptParams::new(params.scrypt_log_n, params.scrypt_r, params.scrypt_p);
+            scrypt::scrypt_simple(password, &scrypt_params).map_err(From::from)
+        }
+        Algorithm::Argon2id => {
+            let mut salt = [0u8; 16];
+            OsRng::new()?.fill_bytes(&mut salt);
+            argon2::hash_encoded(password.as_bytes(), &salt, &params.argon2_config())
+                .map_err(|e| format!("Argon2 error: {:?}", e).into())
+        }
+    }
+}
+
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with(ARGON2ID_PREFIX) {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    } else {
+        scrypt::scrypt_check(password, hash).unwrap_or(false)
+    }
+}
+
+/// Reads `(log_n, r, p)` from an `$rscrypt$` hash.
+fn scrypt_params(hash: &str) -> Option<(u8, u32, u32)> {
+    let mut parts = hash[SCRYPT_PREFIX.len()..].split('$');
+    let format = parts.next()?;
+    let params = base64::decode(parts.next()?).ok()?;
+    match (format, params.len()) {
+        ("0", 3) => Some((params[0], params[1] as u32, params[2] as u32)),
+        ("1", 9) => {
+            let le = |b: &[u8]| b.iter().rev().fold(0u32, |acc, &x| (acc << 8) | x as u32);
+            Some((params[0], le(&params[1..5]), le(&params[5..9])))
+        }
+        _ => None,
+    }
+}
+
+/// Reads `(m, t, p)` from an `$argon2id$` hash.
+fn argon2_params(hash: &str) -> Option<(u32, u32, u32)> {
+    let params = hash[ARGON2ID_PREFIX.len()..]
+        .split('$')
+        .find(|part| part.starts_with("m="))?;
+    let (mut m, mut t, mut p) = (None, None, None);
+    for pair in params.split(',') {
+        let mut kv = pair.splitn(2, '=');
+        match (kv.next(), kv.next().and_then(|v| v.parse().ok())) {
+            (Some("m"), Some(v)) => m = Some(v),
+            (Some("t"), Some(v)) => t = Some(v),
+            (Some("p"), Some(v)) => p = Some(v),
+            _ => {}
+        }
+    }
+    Some((m?, t?, p?))
+}
+
+/// Returns true if the hash uses a different algorithm or weaker parameters
+/// than `params` and should be replaced after the next successful login.
+/// Argon2id hashes are never replaced by scrypt ones: switching the setting
+/// back to scrypt only affects new passwords.
+pub fn needs_rehash(hash: &str, params: &HashParams) -> bool {
+    match params.algorithm {
+        Algorithm::Scrypt if hash.starts_with(SCRYPT_PREFIX) => {
+            match scrypt_params(hash) {
+                Some((log_n, r, p)) => {
+                    log_n < params.scrypt_log_n || r < params.scrypt_r || p < params.scrypt_p
+                }
+                None => true,
+            }
+        }
+        Algorithm::Argon2id if hash.starts_with(ARGON2ID_PREFIX) => {
+            match argon2_params(hash) {
+                Some((m, t, p)) => {
+                    m < params.argon2_mem_cost || t < params.argon2_time_cost ||
+                    p < params.argon2_lanes
+                }
+                None => true,
+            }
+        }
+        Algorithm::Scrypt if hash.starts_with(ARGON2ID_PREFIX) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(algorithm: Algorithm) -> HashParams {
+        HashParams {
+            algorithm,
+            scrypt_log_n: 4,
+            argon2_mem_cost: 64,
+            argon2_time_cost: 1,
+            ..HashParams::default()
+        }
+    }
+
+    #[test]
+    fn default_params_are_valid() {
+        assert!(HashParams::default().problems().is_empty());
+        assert!(params(Algorithm::Scrypt).problems().is_empty());
+    }
+
+    #[test]
+    fn params_that_would_panic_are_problems() {
+        let invalid = vec![HashParams { scrypt_log_n: 0, ..HashParams::default() },
+                           HashParams { scrypt_r: 0, ..HashParams::default() },
+                           HashParams { scrypt_p: 0, ..HashParams::default() },
+                           HashParams { scrypt_r: 1 << 16, scrypt_p: 1 << 14, ..HashParams::default() },
+                           HashParams { scrypt_log_n: 40, ..HashParams::default() },
+                           HashParams { argon2_lanes: 0, ..HashParams::default() },
+                           HashParams { argon2_lanes: 4, argon2_mem_cost: 16, ..HashParams::default() },
+                           HashParams { argon2_time_cost: 0, ..HashParams::default() }];
+        for params in invalid {
+            assert_eq!(params.problems().len(), 1, "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn scrypt_is_upgraded_to_argon2id() {
+        let hash = hash("secret", &params(Algorithm::Scrypt)).unwrap();
+        assert!(needs_rehash(&hash, &params(Algorithm::Argon2id)));
+    }
+
+    #[test]
+    fn argon2id_is_not_downgraded_to_scrypt() {
+        let hash = hash("secret", &params(Algorithm::Argon2id)).unwrap();
+        assert!(verify("secret", &hash));
+        assert!(!needs_rehash(&hash, &params(Algorithm::Scrypt)));
+    }
+}
@@ -0,0 +1,47 @@
+use std::thread;
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+
+use activitypub;
+use db_util::Pool;
+use errors::*;
+use model::Post;
+use service;
+use webmention;
+
+/// Lets other sites know about a published post: queues its webmentions
+/// and sends it to the author's followers. `previous_content` is the
+/// post's HTML if it was already published before.
+pub fn announce(post: &Post, previous_content: Option<&str>, conn: &PgConnection) -> Result<()> {
+    webmention::queue_for_post(post, previous_content, conn)?;
+    activitypub::queue_post(post, previous_content.is_some(), conn)
+}
+
+/// Starts a background thread that publishes scheduled posts once their
+/// `publish_at` date has passed, checking every `interval`, and announces
+/// them.
+pub fn start(pool: Pool, interval: Duration) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("publisher".into())
+        .spawn(move || loop {
+                   match pool.get() {
+                       Ok(conn) => {
+                           match service::post::publish_scheduled(&conn) {
+                               Ok(posts) => {
+                                   for post in posts {
+                                       info!("Published scheduled post {} ({})", post.id, post.title);
+                                       if let Err(e) = announce(&post, None, &conn) {
+                                           warn!("Error announcing post {}: {}", post.id, e);
+                                       }
+                                   }
+                               }
+                               Err(e) => warn!("Error publishing scheduled posts: {}", e),
+                           }
+                       }
+                       Err(e) => warn!("Publisher could not get a database connection: {}", e),
+                   }
+                   thread::sleep(interval);
+               })
+        .expect("Failed to start the publisher thread")
+}
@@ -1 +1,205 @@
-infer_schema!("dotenv:DATABASE_URL");
+// Declared by hand rather than with `infer_schema!`, because diesel cannot
+// map the `tsvector` column `posts.search_vector`, which is only ever used
+// from raw SQL in `service::search` and is left out here.
+
+table! {
+    users {
+        name -> VarChar,
+        pw_hash -> VarChar,
+        id -> Integer,
+        is_admin -> Bool,
+    }
+}
+
+table! {
+    posts {
+        title -> VarChar,
+        content -> VarChar,
+        id -> Integer,
+        created_on -> Timestamptz,
+        owner_id -> Integer,
+        tags -> Array<Text>,
+        published -> Bool,
+        markdown_content -> VarChar,
+        publish_at -> Nullable<Timestamptz>,
+        language -> VarChar,
+    }
+}
+
+table! {
+    login_attempts (key) {
+        key -> VarChar,
+        failures -> Integer,
+        last_failure -> Timestamptz,
+        locked_until -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_log {
+        id -> Integer,
+        event -> VarChar,
+        subject -> VarChar,
+        detail -> VarChar,
+        created_on -> Timestamptz,
+    }
+}
+
+table! {
+    post_revisions {
+        id -> Integer,
+        post_id -> Integer,
+        author_id -> Integer,
+        created_on -> Timestamptz,
+        title -> VarChar,
+        markdown_content -> VarChar,
+        tags -> Array<Text>,
+    }
+}
+
+table! {
+    post_drafts {
+        id -> Integer,
+        post_id -> Nullable<Integer>,
+        owner_id -> Integer,
+        title -> VarChar,
+        markdown_content -> VarChar,
+        tags -> VarChar,
+        updated_on -> Timestamptz,
+    }
+}
+
+table! {
+    comments {
+        id -> Integer,
+        post_id -> Integer,
+        parent_id -> Nullable<Integer>,
+        user_id -> Nullable<Integer>,
+        author_name -> VarChar,
+        author_email -> Nullable<VarChar>,
+        markdown_content -> VarChar,
+        content -> VarChar,
+        status -> VarChar,
+        created_on -> Timestamptz,
+        spam_score -> Nullable<Float>,
+        trained_as -> Nullable<VarChar>,
+        remote_id -> Nullable<VarChar>,
+        author_url -> Nullable<VarChar>,
+    }
+}
+
+table! {
+    spam_tokens (token) {
+        token -> VarChar,
+        spam_count -> Integer,
+        ham_count -> Integer,
+    }
+}
+
+table! {
+    spam_corpus (label) {
+        label -> VarChar,
+        documents -> Integer,
+    }
+}
+
+table! {
+    webmentions {
+        id -> Integer,
+        post_id -> Integer,
+        source -> VarChar,
+        target -> VarChar,
+        status -> VarChar,
+        title -> Nullable<VarChar>,
+        created_on -> Timestamptz,
+        verified_on -> Nullable<Timestamptz>,
+        attempts -> Integer,
+        last_error -> Nullable<VarChar>,
+        next_attempt_on -> Timestamptz,
+    }
+}
+
+table! {
+    outgoing_webmentions {
+        id -> Integer,
+        post_id -> Integer,
+        source -> VarChar,
+        target -> VarChar,
+        status -> VarChar,
+        attempts -> Integer,
+        last_error -> Nullable<VarChar>,
+        next_attempt_on -> Timestamptz,
+        created_on -> Timestamptz,
+    }
+}
+
+table! {
+    api_tokens {
+        id -> Integer,
+        user_id -> Integer,
+        name -> VarChar,
+        token_hash -> VarChar,
+        scope -> VarChar,
+        created_on -> Timestamptz,
+        last_used_on -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    actor_keys (user_id) {
+        user_id -> Integer,
+        public_key_pem -> Text,
+        private_key_pem -> Text,
+        created_on -> Timestamptz,
+    }
+}
+
+table! {
+    followers {
+        id -> Integer,
+        user_id -> Integer,
+        actor -> VarChar,
+        inbox -> VarChar,
+        created_on -> Timestamptz,
+    }
+}
+
+table! {
+    deliveries {
+        id -> Integer,
+        user_id -> Integer,
+        inbox -> VarChar,
+        activity -> Text,
+        status -> VarChar,
+        attempts -> Integer,
+        last_error -> Nullable<VarChar>,
+        next_attempt_on -> Timestamptz,
+        created_on -> Timestamptz,
+    }
+}
+
+table! {
+    media {
+        id -> Integer,
+        owner_id -> Integer,
+        name -> VarChar,
+        original_name -> Nullable<VarChar>,
+        content_type -> VarChar,
+        size_bytes -> BigInt,
+        checksum -> VarChar,
+        created_on -> Timestamptz,
+        width -> Nullable<Integer>,
+        height -> Nullable<Integer>,
+    }
+}
+
+table! {
+    media_variants (name) {
+        name -> VarChar,
+        original -> VarChar,
+        content_type -> VarChar,
+        width -> Integer,
+        height -> Integer,
+        size_bytes -> BigInt,
+    }
+}
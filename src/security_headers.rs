@@ -0,0 +1,218 @@
+use std::io::{Cursor, Read, Write};
+use std::sync::Arc;
+
+use base64;
+use rand::{OsRng, Rng};
+use rocket::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::{Request, Response};
+use rocket_contrib::handlebars::{Handlebars, Helper, RenderContext, RenderError};
+use serde_json::{self, Value};
+
+use config::SecuritySettings;
+use errors::*;
+
+/// The default policy. `{nonce}` is replaced with the request's nonce, so
+/// that only the inline scripts of the templates run.
+pub const DEFAULT_POLICY: &str = "default-src 'self'; script-src 'self' 'nonce-{nonce}'; style-src 'self'; \
+                              img-src 'self' https: data:; object-src 'none'; base-uri 'self'; \
+                              form-action 'self'; frame-ancestors 'none'; report-uri /csp-report";
+
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 31536000;
+
+pub const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+
+pub const DEFAULT_PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=(), payment=(), usb=()";
+
+/// Reports larger than this are cut off.
+const MAX_REPORT_SIZE: u64 = 16 * 1024;
+
+fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(base64::encode(&bytes))
+}
+
+/// Adds the security headers to every response.
+///
+/// Templates mark their inline scripts with `<script nonce="{{csp_nonce}}">`.
+/// The helper renders a random placeholder, which is only known to the
+/// server, and the fairing replaces it with a fresh nonce for each HTML
+/// response. Markup injected through posts or comments can't know the
+/// placeholder, so its scripts don't get the nonce.
+pub struct SecurityHeaders {
+    policy: String,
+    report_only: bool,
+    /// `0` disables `Strict-Transport-Security`.
+    hsts_max_age: u64,
+    referrer_policy: String,
+    permissions_policy: String,
+    placeholder: Arc<String>,
+}
+
+impl SecurityHeaders {
+    /// Fails only if the placeholder can't be generated, the settings are
+    /// checked by `Settings::load`.
+    pub fn new(settings: &SecuritySettings) -> Result<SecurityHeaders> {
+        Ok(SecurityHeaders {
+               policy: settings.csp_policy.clone(),
+               report_only: settings.csp_report_only,
+               hsts_max_age: settings.hsts_max_age,
+               referrer_policy: settings.referrer_policy.clone(),
+               permissions_policy: settings.permissions_policy.clone(),
+               placeholder: Arc::new(format!("csp-{}", random_token()?)),
+           })
+    }
+
+    /// The placeholder the `csp_nonce` helper renders.
+    pub fn placeholder(&self) -> Arc<String> {
+        self.placeholder.clone()
+    }
+
+    /// Replaces the placeholder in an HTML body with a new nonce. Returns
+    /// the nonce, or `None` if no nonce could be generated.
+    fn insert_nonce(&self, response: &mut Response) -> Option<String> {
+        let nonce = match random_token() {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                warn!("Could not generate a CSP nonce: {}", e);
+                return None;
+            }
+        };
+        let is_html = response
+            .headers()
+            .get_one("Content-Type")
+            .map(|t| t.starts_with("text/html"))
+            .unwrap_or(false);
+        if is_html {
+            // The body is put back whatever it contains, it doesn't have to
+            // be valid UTF-8.
+            if let Some(body) = response.body_bytes() {
+                let body = replace_bytes(&body, self.placeholder.as_bytes(), nonce.as_bytes());
+                response.set_sized_body(Cursor::new(body));
+            }
+        }
+        Some(nonce)
+    }
+}
+
+/// Replaces every occurrence of `from`, which must not be empty, in `data`.
+fn replace_bytes(data: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while rest.len() >= from.len() {
+        if rest.starts_with(from) {
+            replaced.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            replaced.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    replaced.extend_from_slice(rest);
+    replaced
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        // Without a nonce the policy still applies, the inline scripts
+        // just don't run.
+        let nonce = self.insert_nonce(response).unwrap_or_default();
+        let header = if self.report_only {
+            "Content-Security-Policy-Report-Only"
+        } else {
+            "Content-Security-Policy"
+        };
+        response.set_raw_header(header, self.policy.replace("{nonce}", &nonce));
+        if self.hsts_max_age > 0 {
+            response.set_raw_header("Strict-Transport-Security",
+                                    format!("max-age={}; includeSubDomains", self.hsts_max_age));
+        }
+        response.set_raw_header("X-Content-Type-Options", "nosniff");
+        response.set_raw_header("Referrer-Policy", self.referrer_policy.clone());
+        response.set_raw_header("Permissions-Policy", self.permissions_policy.clone());
+    }
+}
+
+/// Registers the `csp_nonce` helper, which renders the placeholder from
+/// `SecurityHeaders::placeholder`.
+pub fn register_helper(handlebars: &mut Handlebars, placeholder: Arc<String>) {
+    handlebars.register_helper("csp_nonce",
+                               Box::new(move |_: &Helper, _: &Handlebars, rc: &mut RenderContext| {
+                                            rc.writer
+                                                .write_all(placeholder.as_bytes())
+                                                .map_err(RenderError::from)
+                                        }));
+}
+
+/// Logs a report. The two report formats name the fields differently, so
+/// both names are tried.
+fn log_report(report: &Value) {
+    let field = |names: &[&str]| {
+        names
+            .iter()
+            .filter_map(|name| report.get(*name).and_then(|v| v.as_str()))
+            .next()
+            .unwrap_or("")
+            .to_string()
+    };
+    warn!("CSP violation on {}: {} blocked {}",
+          field(&["document-uri", "documentURL"]),
+          field(&["violated-directive", "effectiveDirective"]),
+          field(&["blocked-uri", "blockedURL"]));
+}
+
+/// Collects the reports browsers send for policy violations, in the
+/// `application/csp-report` format as well as the Reporting API's
+/// `application/reports+json`. They are logged.
+#[post("/csp-report", data = "<data>")]
+pub fn csp_report(data: Data) -> Status {
+    let mut body = String::new();
+    if data.open()
+           .take(MAX_REPORT_SIZE)
+           .read_to_string(&mut body)
+           .is_err() {
+        return Status::BadRequest;
+    }
+    let report: Value = match serde_json::from_str(&body) {
+        Ok(report) => report,
+        Err(_) => return Status::BadRequest,
+    };
+    match report {
+        Value::Array(reports) => {
+            for r in &reports {
+                if let Some(body) = r.get("body") {
+                    log_report(body);
+                }
+            }
+        }
+        ref report => {
+            if let Some(body) = report.get("csp-report") {
+                log_report(body);
+            }
+        }
+    }
+    Status::NoContent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced_in_any_bytes() {
+        let body = b"<script nonce=\"csp-x\">\xff\xfe</script><style nonce=\"csp-x\">";
+        assert_eq!(replace_bytes(body, b"csp-x", b"abc"),
+                   b"<script nonce=\"abc\">\xff\xfe</script><style nonce=\"abc\">".to_vec());
+        assert_eq!(replace_bytes(b"csp-", b"csp-x", b"abc"), b"csp-".to_vec());
+        assert_eq!(replace_bytes(b"", b"csp-x", b"abc"), b"".to_vec());
+    }
+}
@@ -4,13 +4,9 @@ pub mod user {
     use diesel::prelude::*;
     use diesel;
     use diesel::pg::PgConnection;
-    use ring_pwhash::scrypt;
 
     use model::CreateUserRequest;
-
-    const SCRYPT_LOG_N: u8 = 14;
-    const SCRYPT_R: u32 = 8;
-    const SCRYPT_P: u32 = 1;
+    use password::{self, HashParams};
 
     pub fn find_one(user_id: i32, conn: &PgConnection) -> Result<Option<User>> {
         use schema::users::dsl::*;
@@ -21,15 +17,17 @@ pub mod user {
             .map_err(From::from)
     }
 
-    pub fn create_user(request: CreateUserRequest, conn: &PgConnection) -> Result<User> {
+    pub fn create_user(request: CreateUserRequest,
+                       params: &HashParams,
+                       conn: &PgConnection)
+                       -> Result<User> {
         use schema::users;
 
-        let params = scrypt::ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
-
         let user = User {
             name: request.name,
-            pw_hash: scrypt::scrypt_simple(&request.password, &params).unwrap(),
+            pw_hash: password::hash(&request.password, params)?,
             id: 0,
+            is_admin: false,
         };
         diesel::insert(&user)
             .into(users::table)
@@ -56,6 +54,42 @@ pub mod user {
             .map_err(From::from)
     }
 
+    /// Looks up the user and checks the password. On success, a hash that
+    /// is weaker than `params` is transparently replaced by a new one. If
+    /// that fails, the old hash is kept and the user still logged in.
+    pub fn authenticate(username: &str,
+                        cleartext_pw: &str,
+                        params: &HashParams,
+                        conn: &PgConnection)
+                        -> Result<Option<User>> {
+        let mut user = match find_by_name(username, conn)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        if !user.verify_password(cleartext_pw) {
+            return Ok(None);
+        }
+        if password::needs_rehash(&user.pw_hash, params) {
+            info!("Upgrading password hash for user {}", user.id);
+            let upgraded = password::hash(cleartext_pw, params)
+                .and_then(|hash| update_pw_hash(user.id, &hash, conn).map(|_| hash));
+            match upgraded {
+                Ok(hash) => user.pw_hash = hash,
+                Err(e) => warn!("Could not upgrade the password hash of user {}: {}", user.id, e),
+            }
+        }
+        Ok(Some(user))
+    }
+
+    pub fn update_pw_hash(user_id: i32, hash: &str, conn: &PgConnection) -> Result<()> {
+        use schema::users::dsl::*;
+
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(pw_hash.eq(hash))
+            .execute(conn)?;
+        Ok(())
+    }
+
     pub fn get_name(user_id: i32, conn: &PgConnection) -> Result<String> {
         use schema::users;
 
@@ -73,16 +107,25 @@ pub mod post {
     use diesel;
     use diesel::pg::PgConnection;
 
-    use util::Page;
-    use model::{CreatePostRequest, Post};
+    use media;
+    use util::{self, Page};
+    use model::{CreatePostRequest, Post, User};
+    use service::revision;
 
-    pub fn insert_post(request: CreatePostRequest, conn: &PgConnection) -> Result<Post> {
+    /// Creates a post owned by `owner`. Images from the media library get
+    /// responsive markup.
+    pub fn insert_post(request: CreatePostRequest, owner: &User, conn: &PgConnection) -> Result<Post> {
         use schema::posts;
 
-        diesel::insert(&request)
-            .into(posts::table)
-            .get_result::<Post>(conn)
-            .map_err(From::from)
+        let mut new_post = request.into_new_post(owner);
+        new_post.content = media::responsive_images(&new_post.content, conn)?;
+        conn.transaction::<_, Error, _>(|| {
+            let post = diesel::insert(&new_post)
+                .into(posts::table)
+                .get_result::<Post>(conn)?;
+            revision::record(&post, post.owner_id, conn)?;
+            Ok(post)
+        })
     }
 
     pub fn find_one(post_id: i32, conn: &PgConnection) -> Result<Option<Post>> {
@@ -95,27 +138,50 @@ pub mod post {
             .map_err(From::from)
     }
 
+    /// Returns `None` for a page that is out of range.
     pub fn find_page(user_id: i32,
                      page_num: i64,
                      page_size: i64,
                      conn: &PgConnection)
-                     -> Result<Page<Post>> {
+                     -> Result<Option<Page<Post>>> {
         use schema::posts::dsl::*;
 
-        let offset = page_num * page_size;
-        let limit = offset + page_size;
+        let start = match util::page_offset(page_num, page_size) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
 
         posts
             .filter(owner_id.eq(user_id).and(published.eq(true)))
-            .offset(offset)
-            .limit(limit)
+            .offset(start)
+            .limit(page_size)
             .load(conn)
-            .and_then(|v| Ok(Page::new(v, page_num, 0, page_size)))
+            .map(|v| Some(Page::new(v, page_num, 0, page_size)))
             .map_err(From::from)
     }
 
-    pub fn update_post(post: &Post, conn: &PgConnection) -> Result<Post> {
-        post.save_changes(conn).map_err(From::from)
+    /// Finds a post only if it belongs to the given user.
+    pub fn find_owned(post_id: i32, user_id: i32, conn: &PgConnection) -> Result<Option<Post>> {
+        use schema::posts::dsl::*;
+
+        posts
+            .filter(id.eq(post_id).and(owner_id.eq(user_id)))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Saves the post and records the new state as a revision by `author_id`.
+    /// Like `insert_post`, gives images from the media library responsive
+    /// markup.
+    pub fn update_post(post: &Post, author_id: i32, conn: &PgConnection) -> Result<Post> {
+        let mut post = post.clone();
+        post.content = media::responsive_images(&post.content, conn)?;
+        conn.transaction::<_, Error, _>(|| {
+            let post = post.save_changes::<Post>(conn)?;
+            revision::record(&post, author_id, conn)?;
+            Ok(post)
+        })
     }
 
     pub fn get_by_tag(user_id: i32, tag: &str, conn: &PgConnection) -> Result<Vec<Post>> {
@@ -123,7 +189,1167 @@ pub mod post {
 
         posts
             .filter(owner_id.eq(user_id).and(tags.contains(vec![tag])))
+            .filter(published.eq(true))
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns the published posts of all users with the given tag, newest first.
+    pub fn find_by_tag(tag: &str, conn: &PgConnection) -> Result<Vec<Post>> {
+        use schema::posts::dsl::*;
+
+        posts
+            .filter(published.eq(true).and(tags.contains(vec![tag])))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns all posts of a user regardless of their status, newest first.
+    pub fn find_all_by_owner(user_id: i32, conn: &PgConnection) -> Result<Vec<Post>> {
+        use schema::posts::dsl::*;
+
+        posts
+            .filter(owner_id.eq(user_id))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Deletes a post along with its revisions, drafts and comments.
+    pub fn delete(post_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::posts::dsl::*;
+
+        diesel::delete(posts.filter(id.eq(post_id))).execute(conn)?;
+        Ok(())
+    }
+
+    /// Publishes all posts whose `publish_at` date has passed and returns them.
+    /// Their creation date becomes the scheduled date, so they show up as
+    /// new rather than with the date the draft was started. The date is
+    /// cleared, so a post unpublished later stays unpublished.
+    pub fn publish_scheduled(conn: &PgConnection) -> Result<Vec<Post>> {
+        use schema::posts::dsl::*;
+        use chrono::{DateTime, UTC};
+        use diesel::expression::dsl::sql;
+        use diesel::types::Timestamptz;
+
+        diesel::update(posts.filter(published.eq(false).and(publish_at.le(UTC::now()))))
+            .set((published.eq(true),
+                  created_on.eq(sql::<Timestamptz>("coalesce(publish_at, now())")),
+                  publish_at.eq(None::<DateTime<UTC>>)))
+            .get_results(conn)
+            .map_err(From::from)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use diesel::pg::PgConnection;
+        use serde_json;
+
+        use db_util;
+        use model::{CreatePostRequest, CreateUserRequest, User};
+        use password::HashParams;
+        use service::user;
+
+        fn create_user(name: &str, conn: &PgConnection) -> User {
+            let request = CreateUserRequest {
+                name: name.into(),
+                password: "secret".into(),
+                password_repeated: "secret".into(),
+                website: None,
+                rendered_at: None,
+            };
+            let params = HashParams { scrypt_log_n: 4, ..HashParams::default() };
+            user::create_user(request, &params, conn).unwrap()
+        }
+
+        #[test]
+        fn insert_post_takes_owner_from_user() {
+            let conn = match db_util::test_connection() {
+                Some(conn) => conn,
+                None => return,
+            };
+            let author = create_user("impersonation-test-author", &conn);
+            let victim = create_user("impersonation-test-victim", &conn);
+            let request: CreatePostRequest = serde_json::from_value(json!({
+                "title": "Impersonation",
+                "markdown_content": "Body",
+                "content": "<p>Body</p>",
+                "tags": [],
+                "published": false,
+                "owner_id": victim.id,
+            }))
+                    .unwrap();
+
+            let post = super::insert_post(request, &author, &conn).unwrap();
+
+            assert_eq!(post.owner_id, author.id);
+            let stored = super::find_one(post.id, &conn).unwrap().unwrap();
+            assert_eq!(stored.owner_id, author.id);
+        }
+
+        #[test]
+        fn unpublished_post_is_not_published_again() {
+            let conn = match db_util::test_connection() {
+                Some(conn) => conn,
+                None => return,
+            };
+            let author = create_user("unpublish-test-author", &conn);
+            let request: CreatePostRequest = serde_json::from_value(json!({
+                "title": "Scheduled",
+                "markdown_content": "Body",
+                "tags": [],
+                "published": false,
+                "publish_at": "2017-01-01T10:00:00Z",
+            }))
+                    .unwrap();
+            let post = super::insert_post(request, &author, &conn).unwrap();
+
+            let published = super::publish_scheduled(&conn).unwrap();
+            let mut post = published.into_iter().find(|p| p.id == post.id).unwrap();
+            assert!(post.published);
+            assert_eq!(post.publish_at, None);
+
+            post.published = false;
+            super::update_post(&post, author.id, &conn).unwrap();
+            let published = super::publish_scheduled(&conn).unwrap();
+            assert!(published.iter().all(|p| p.id != post.id));
+            assert!(!super::find_one(post.id, &conn).unwrap().unwrap().published);
+        }
+    }
+}
+
+pub mod audit {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use model::{AuditEntry, NewAuditEntry};
+
+    pub fn record(event: &str, subject: &str, detail: &str, conn: &PgConnection) -> Result<AuditEntry> {
+        use schema::audit_log;
+
+        let entry = NewAuditEntry {
+            event: event.into(),
+            subject: subject.into(),
+            detail: detail.into(),
+        };
+        diesel::insert(&entry)
+            .into(audit_log::table)
+            .get_result(conn)
+            .map_err(From::from)
+    }
+}
+
+pub mod revision {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use model::{NewPostRevision, Post, PostRevision};
+    use util;
+
+    pub fn record(post: &Post, author_id: i32, conn: &PgConnection) -> Result<PostRevision> {
+        use schema::post_revisions;
+
+        diesel::insert(&NewPostRevision::from_post(post, author_id))
+            .into(post_revisions::table)
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns all revisions of a post, newest first.
+    pub fn find_for_post(post: i32, conn: &PgConnection) -> Result<Vec<PostRevision>> {
+        use schema::post_revisions::dsl::*;
+
+        post_revisions
+            .filter(post_id.eq(post))
+            .order(id.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    pub fn find_one(post: i32, revision_id: i32, conn: &PgConnection) -> Result<Option<PostRevision>> {
+        use schema::post_revisions::dsl::*;
+
+        post_revisions
+            .filter(id.eq(revision_id).and(post_id.eq(post)))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Copies a past revision back onto the post. The restore itself is
+    /// recorded as a new revision, so history is never rewritten.
+    pub fn restore(post: &mut Post,
+                   revision: &PostRevision,
+                   author_id: i32,
+                   conn: &PgConnection)
+                   -> Result<Post> {
+        post.title = revision.title.clone();
+        post.markdown_content = revision.markdown_content.clone();
+        post.tags = revision.tags.clone();
+        post.content = util::markdown_to_html(&post.markdown_content);
+        super::post::update_post(post, author_id, conn)
+    }
+}
+
+pub mod draft {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::types::{Integer, Nullable, Timestamptz, VarChar};
+
+    use db_util;
+    use model::{Draft, SaveDraftRequest};
+
+    pub fn find(owner: i32, post: Option<i32>, conn: &PgConnection) -> Result<Option<Draft>> {
+        use schema::post_drafts::dsl::*;
+
+        let query = post_drafts.filter(owner_id.eq(owner)).into_boxed();
+        let query = match post {
+            Some(post) => query.filter(post_id.eq(post)),
+            None => query.filter(post_id.is_null()),
+        };
+        query.first(conn).optional().map_err(From::from)
+    }
+
+    /// Creates or overwrites the author's draft for the post, in one
+    /// statement, so overlapping autosaves don't both try to create it.
+    /// Diesel can't name the unique index as the conflict target, it is on
+    /// an expression, so the statement is written out.
+    pub fn save(owner: i32, request: SaveDraftRequest, conn: &PgConnection) -> Result<Draft> {
+        let post = match request.post_id {
+            Some(post) => post.to_string(),
+            None => "NULL".to_string(),
+        };
+        sql::<(Integer, Nullable<Integer>, Integer, VarChar, VarChar, VarChar, Timestamptz)>(&format!(
+            "INSERT INTO post_drafts (post_id, owner_id, title, markdown_content, tags) \
+             VALUES ({post}, {owner}, {title}, {markdown_content}, {tags}) \
+             ON CONFLICT (owner_id, COALESCE(post_id, 0)) DO UPDATE \
+             SET title = excluded.title, markdown_content = excluded.markdown_content, \
+                 tags = excluded.tags, updated_on = now() \
+             RETURNING id, post_id, owner_id, title, markdown_content, tags, updated_on",
+            post = post,
+            owner = owner,
+            title = db_util::quote(&request.title)?,
+            markdown_content = db_util::quote(&request.markdown_content)?,
+            tags = db_util::quote(&request.tags)?))
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    pub fn discard(owner: i32, post: Option<i32>, conn: &PgConnection) -> Result<()> {
+        if let Some(draft) = find(owner, post, conn)? {
+            use schema::post_drafts::dsl::*;
+
+            diesel::delete(post_drafts.filter(id.eq(draft.id))).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use db_util;
+        use model::{CreateUserRequest, SaveDraftRequest};
+        use password::HashParams;
+        use service::user;
+
+        fn request(title: &str) -> SaveDraftRequest {
+            SaveDraftRequest {
+                post_id: None,
+                title: title.into(),
+                markdown_content: "It's a \\draft".into(),
+                tags: "rust".into(),
+            }
+        }
+
+        #[test]
+        fn saving_again_overwrites_the_draft() {
+            let conn = match db_util::test_connection() {
+                Some(conn) => conn,
+                None => return,
+            };
+            let request_user = CreateUserRequest {
+                name: "draft-test-author".into(),
+                password: "secret".into(),
+                password_repeated: "secret".into(),
+                website: None,
+                rendered_at: None,
+            };
+            let params = HashParams { scrypt_log_n: 4, ..HashParams::default() };
+            let author = user::create_user(request_user, &params, &conn).unwrap();
+
+            let first = super::save(author.id, request("First"), &conn).unwrap();
+            let second = super::save(author.id, request("Second"), &conn).unwrap();
+
+            assert_eq!(first.id, second.id);
+            assert_eq!(second.title, "Second");
+            assert_eq!(second.markdown_content, "It's a \\draft");
+            assert_eq!(super::find(author.id, None, &conn).unwrap().unwrap().title, "Second");
+        }
+    }
+}
+
+pub mod search {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::types::{BigInt, Float, Integer, Text, Timestamptz, VarChar};
+
+    use db_util;
+    use model::SearchResult;
+    use util::{self, Page};
+
+    // Unlikely to show up in a post; replaced by `<mark>` after escaping.
+    const START_MARK: &str = "\u{2060}[[";
+    const STOP_MARK: &str = "]]\u{2060}";
+
+    /// Searches published posts, best matches first. Every post is matched
+    /// using its own text search configuration. Returns `None` for a page
+    /// that is out of range.
+    pub fn search(query: &str,
+                  page_num: i64,
+                  page_size: i64,
+                  conn: &PgConnection)
+                  -> Result<Option<Page<SearchResult>>> {
+        let offset = match util::page_offset(page_num, page_size) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        // Diesel can't express text search, so the query is quoted into
+        // the SQL.
+        let tsquery = format!("plainto_tsquery(p.language::regconfig, {})", db_util::quote(query)?);
+        let filter = format!("p.published AND p.search_vector @@ {}", tsquery);
+
+        let count = sql::<BigInt>(&format!("SELECT count(*) FROM posts p WHERE {}", filter))
+            .get_result::<i64>(conn)?;
+
+        let options = format!("StartSel={}, StopSel={}, MaxFragments=2, MaxWords=30, MinWords=10",
+                              START_MARK,
+                              STOP_MARK);
+        let results = sql::<(Integer, VarChar, Text, Timestamptz, Float)>(&format!(
+            "SELECT p.id, p.title, \
+                    ts_headline(p.language::regconfig, p.markdown_content, {tsquery}, {options}), \
+                    p.created_on, \
+                    ts_rank(p.search_vector, {tsquery}) AS rank \
+             FROM posts p WHERE {filter} \
+             ORDER BY rank DESC, p.created_on DESC \
+             LIMIT {limit} OFFSET {offset}",
+            tsquery = tsquery,
+            options = db_util::quote(&options)?,
+            filter = filter,
+            limit = page_size,
+            offset = offset))
+            .load::<SearchResult>(conn)?
+            .into_iter()
+            .map(|mut result| {
+                result.snippet = util::escape_html(&result.snippet)
+                    .replace(START_MARK, "<mark>")
+                    .replace(STOP_MARK, "</mark>");
+                result
+            })
+            .collect();
+
+        let num_pages = (count + page_size - 1) / page_size;
+        Ok(Some(Page::new(results, page_num, num_pages, page_size)))
+    }
+}
+
+pub mod tag {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::types::{Array, BigInt, Text};
+
+    use model::TagCount;
+
+    /// Counts the published posts per tag, alphabetically.
+    pub fn counts(conn: &PgConnection) -> Result<Vec<TagCount>> {
+        sql::<(Text, BigInt)>("SELECT tag, count(*) FROM posts, unnest(tags) AS tag \
+                               WHERE published GROUP BY tag ORDER BY tag")
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    sql_function!(array_remove, array_remove_t, (array: Array<Text>, element: Text) -> Array<Text>);
+    sql_function!(array_replace,
+                  array_replace_t,
+                  (array: Array<Text>, from: Text, to: Text) -> Array<Text>);
+
+    /// Renames a tag on all posts. If a post already has the new tag, the old
+    /// one is just removed, so this also merges tags. Returns the number of
+    /// changed posts.
+    pub fn rename(from: &str, to: &str, conn: &PgConnection) -> Result<usize> {
+        use schema::posts::dsl::*;
+
+        if from == to {
+            return Ok(0);
+        }
+        conn.transaction::<_, Error, _>(|| {
+            let merged = diesel::update(posts.filter(tags.contains(vec![from, to])))
+                .set(tags.eq(array_remove(tags, from)))
+                .execute(conn)?;
+            let renamed = diesel::update(posts.filter(tags.contains(vec![from])))
+                .set(tags.eq(array_replace(tags, from, to)))
+                .execute(conn)?;
+            Ok(merged + renamed)
+        })
+    }
+}
+
+pub mod archive {
+    use chrono::{DateTime, TimeZone, UTC};
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::types::{BigInt, Integer};
+
+    use model::{ArchiveMonth, Post};
+
+    /// Counts published posts per month, newest month first.
+    pub fn months(conn: &PgConnection) -> Result<Vec<ArchiveMonth>> {
+        sql::<(Integer, Integer, BigInt)>("SELECT extract(year FROM created_on AT TIME ZONE 'UTC')::int, \
+                                                  extract(month FROM created_on AT TIME ZONE 'UTC')::int, \
+                                                  count(*) \
+                                           FROM posts WHERE published \
+                                           GROUP BY 1, 2 ORDER BY 1 DESC, 2 DESC")
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    fn start_of_month(year: i32, month: u32) -> Option<DateTime<UTC>> {
+        UTC.ymd_opt(year, month, 1).single().map(|d| d.and_hms(0, 0, 0))
+    }
+
+    /// Returns the published posts created within `[start, end)`, newest first.
+    fn find_between(start: DateTime<UTC>, end: DateTime<UTC>, conn: &PgConnection) -> Result<Vec<Post>> {
+        use schema::posts::dsl::*;
+
+        posts
+            .filter(published.eq(true))
+            .filter(created_on.ge(start).and(created_on.lt(end)))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns all published posts, newest first.
+    pub fn find_all(conn: &PgConnection) -> Result<Vec<Post>> {
+        use schema::posts::dsl::*;
+
+        posts
+            .filter(published.eq(true))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns `None` for a year that cannot be represented.
+    pub fn find_by_year(year: i32, conn: &PgConnection) -> Result<Option<Vec<Post>>> {
+        let end = year.checked_add(1).and_then(|next| start_of_month(next, 1));
+        match (start_of_month(year, 1), end) {
+            (Some(start), Some(end)) => find_between(start, end, conn).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns `None` for an invalid month.
+    pub fn find_by_month(year: i32, month: u32, conn: &PgConnection) -> Result<Option<Vec<Post>>> {
+        let next = if month == 12 {
+            year.checked_add(1).map(|y| (y, 1))
+        } else {
+            month.checked_add(1).map(|m| (year, m))
+        };
+        let end = next.and_then(|(y, m)| start_of_month(y, m));
+        match (start_of_month(year, month), end) {
+            (Some(start), Some(end)) => find_between(start, end, conn).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+pub mod comment {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use model::{Comment, CommentStatus, NewComment};
+    use spam::SpamFilter;
+
+    pub fn insert(comment: &NewComment, conn: &PgConnection) -> Result<Comment> {
+        use schema::comments;
+
+        diesel::insert(comment)
+            .into(comments::table)
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    pub fn find_one(comment_id: i32, conn: &PgConnection) -> Result<Option<Comment>> {
+        use schema::comments::dsl::*;
+
+        comments
+            .filter(id.eq(comment_id))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Returns the approved comments of a post, oldest first.
+    pub fn find_approved(post: i32, conn: &PgConnection) -> Result<Vec<Comment>> {
+        use schema::comments::dsl::*;
+
+        comments
+            .filter(post_id.eq(post).and(status.eq(CommentStatus::Approved.as_str())))
+            .order(created_on.asc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns the comments with the given status on any post of `owner`,
+    /// oldest first.
+    pub fn find_for_owner(owner: i32, comment_status: CommentStatus, conn: &PgConnection) -> Result<Vec<Comment>> {
+        use schema::comments::dsl::*;
+        use schema::posts;
+
+        let owned_posts = posts::table
+            .select(posts::id)
+            .filter(posts::owner_id.eq(owner));
+        comments
+            .filter(post_id.eq_any(owned_posts))
+            .filter(status.eq(comment_status.as_str()))
+            .order(created_on.asc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Finds a comment only if it was made on a post of `owner`.
+    pub fn find_owned(comment_id: i32, owner: i32, conn: &PgConnection) -> Result<Option<Comment>> {
+        use schema::posts;
+
+        match find_one(comment_id, conn)? {
+            Some(comment) => {
+                let post_owner = posts::table
+                    .select(posts::owner_id)
+                    .filter(posts::id.eq(comment.post_id))
+                    .first::<i32>(conn)?;
+                Ok(if post_owner == owner { Some(comment) } else { None })
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_status(comment_id: i32, new_status: CommentStatus, conn: &PgConnection) -> Result<Comment> {
+        use schema::comments::dsl::*;
+
+        diesel::update(comments.filter(id.eq(comment_id)))
+            .set(status.eq(new_status.as_str()))
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    /// Trains the spam filter with a moderation decision, undoing an earlier
+    /// contrary decision on the same comment.
+    pub fn train(comment: &Comment, is_spam: bool, filter: &SpamFilter, conn: &PgConnection) -> Result<()> {
+        use schema::comments::dsl::*;
+
+        let previous = comment.trained_as.as_ref().map(|label| label == "spam");
+        conn.transaction::<_, Error, _>(|| {
+            filter.learn(&comment.markdown_content, is_spam, previous, conn)?;
+            diesel::update(comments.filter(id.eq(comment.id)))
+                .set(trained_as.eq(if is_spam { "spam" } else { "ham" }))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete(comment_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::comments::dsl::*;
+
+        diesel::delete(comments.filter(id.eq(comment_id))).execute(conn)?;
+        Ok(())
+    }
+
+    /// Finds a reply received from another server by its ActivityPub id.
+    pub fn find_by_remote_id(remote: &str, conn: &PgConnection) -> Result<Option<Comment>> {
+        use schema::comments::dsl::*;
+
+        comments
+            .filter(remote_id.eq(remote))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Deletes a reply received from another server, if it was written by
+    /// `author`.
+    pub fn delete_remote(remote: &str, author: &str, conn: &PgConnection) -> Result<()> {
+        use schema::comments::dsl::*;
+
+        diesel::delete(comments.filter(remote_id.eq(remote).and(author_url.eq(author)))).execute(conn)?;
+        Ok(())
+    }
+
+    /// Orders comments into threads: every comment is followed by its
+    /// replies. Returns each comment with its nesting depth. Comments whose
+    /// parent is not in `comments` are treated as top-level.
+    pub fn thread(comments: Vec<Comment>) -> Vec<(Comment, usize)> {
+        fn visit(parent: Option<i32>,
+                 depth: usize,
+                 comments: &[Comment],
+                 out: &mut Vec<(Comment, usize)>) {
+            for comment in comments.iter().filter(|c| c.parent_id == parent) {
+                out.push((comment.clone(), depth));
+                visit(Some(comment.id), depth + 1, comments, out);
+            }
+        }
+
+        let ids: Vec<i32> = comments.iter().map(|c| c.id).collect();
+        let comments: Vec<Comment> = comments
+            .into_iter()
+            .map(|mut c| {
+                if c.parent_id.map(|p| !ids.contains(&p)).unwrap_or(false) {
+                    c.parent_id = None;
+                }
+                c
+            })
+            .collect();
+        let mut out = Vec::with_capacity(comments.len());
+        visit(None, 0, &comments, &mut out);
+        out
+    }
+}
+
+pub mod webmention {
+    use std::cmp;
+
+    use chrono::{Duration, UTC};
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use model::{MentionStatus, NewOutgoingWebmention, NewWebmention, OutgoingWebmention, Webmention};
+
+    /// Stores a received webmention as pending. A mention that was received
+    /// before is reset to pending, so it gets verified again right away.
+    pub fn receive(mention: &NewWebmention, conn: &PgConnection) -> Result<Webmention> {
+        use schema::webmentions::dsl::*;
+
+        conn.transaction::<_, Error, _>(|| {
+            let existing = webmentions
+                .filter(source.eq(&mention.source).and(target.eq(&mention.target)))
+                .first::<Webmention>(conn)
+                .optional()?;
+            match existing {
+                Some(existing) => {
+                    diesel::update(webmentions.filter(id.eq(existing.id)))
+                        .set((post_id.eq(mention.post_id),
+                              status.eq(MentionStatus::Pending.as_str()),
+                              attempts.eq(0),
+                              last_error.eq(None::<String>),
+                              next_attempt_on.eq(UTC::now())))
+                        .get_result(conn)
+                        .map_err(From::from)
+                }
+                None => {
+                    diesel::insert(mention)
+                        .into(webmentions)
+                        .get_result(conn)
+                        .map_err(From::from)
+                }
+            }
+        })
+    }
+
+    /// Returns the verified mentions of a post, oldest first.
+    pub fn find_verified(post: i32, conn: &PgConnection) -> Result<Vec<Webmention>> {
+        use schema::webmentions::dsl::*;
+
+        webmentions
+            .filter(post_id.eq(post).and(status.eq(MentionStatus::Verified.as_str())))
+            .order(created_on.asc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns received mentions that are due to be verified.
+    pub fn find_pending(limit: i64, conn: &PgConnection) -> Result<Vec<Webmention>> {
+        use schema::webmentions::dsl::*;
+
+        webmentions
+            .filter(status.eq(MentionStatus::Pending.as_str()).and(next_attempt_on.le(UTC::now())))
+            .order(next_attempt_on.asc())
+            .limit(limit)
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    pub fn set_verified(mention_id: i32, source_title: Option<String>, conn: &PgConnection) -> Result<()> {
+        use schema::webmentions::dsl::*;
+
+        diesel::update(webmentions.filter(id.eq(mention_id)))
+            .set((status.eq(MentionStatus::Verified.as_str()),
+                  title.eq(source_title),
+                  verified_on.eq(Some(UTC::now()))))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_rejected(mention_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::webmentions::dsl::*;
+
+        diesel::update(webmentions.filter(id.eq(mention_id)))
+            .set(status.eq(MentionStatus::Rejected.as_str()))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Records that the source of a received mention could not be fetched.
+    /// The mention stays pending and is verified again with exponential
+    /// backoff, until `max_attempts` is reached and it is rejected.
+    pub fn retry_verification(mention: &Webmention,
+                              error: &str,
+                              max_attempts: i32,
+                              conn: &PgConnection)
+                              -> Result<()> {
+        use schema::webmentions::dsl::*;
+
+        let tries = mention.attempts + 1;
+        let new_status = if tries >= max_attempts {
+            MentionStatus::Rejected
+        } else {
+            MentionStatus::Pending
+        };
+        let retry_on = UTC::now() + Duration::minutes(2i64.pow(cmp::min(tries, 12) as u32));
+        diesel::update(webmentions.filter(id.eq(mention.id)))
+            .set((status.eq(new_status.as_str()),
+                  attempts.eq(tries),
+                  last_error.eq(Some(error.to_string())),
+                  next_attempt_on.eq(retry_on)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(mention_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::webmentions::dsl::*;
+
+        diesel::delete(webmentions.filter(id.eq(mention_id))).execute(conn)?;
+        Ok(())
+    }
+
+    /// Queues webmentions from `source` to each of `targets`, skipping
+    /// targets that are already waiting to be notified. Returns the number
+    /// of queued mentions.
+    pub fn enqueue(post: i32, from: &str, targets: &[String], conn: &PgConnection) -> Result<usize> {
+        use schema::outgoing_webmentions::dsl::*;
+
+        if targets.is_empty() {
+            return Ok(0);
+        }
+        conn.transaction::<_, Error, _>(|| {
+            let waiting: Vec<String> = outgoing_webmentions
+                .select(target)
+                .filter(source.eq(from).and(status.eq("pending")))
+                .load(conn)?;
+            let new: Vec<NewOutgoingWebmention> = targets
+                .iter()
+                .filter(|t| !waiting.contains(t))
+                .map(|t| {
+                         NewOutgoingWebmention {
+                             post_id: post,
+                             source: from.to_string(),
+                             target: t.clone(),
+                         }
+                     })
+                .collect();
+            if !new.is_empty() {
+                diesel::insert(&new)
+                    .into(outgoing_webmentions)
+                    .execute(conn)?;
+            }
+            Ok(new.len())
+        })
+    }
+
+    /// Returns queued webmentions that are due to be sent.
+    pub fn find_due(limit: i64, conn: &PgConnection) -> Result<Vec<OutgoingWebmention>> {
+        use schema::outgoing_webmentions::dsl::*;
+
+        outgoing_webmentions
+            .filter(status.eq("pending").and(next_attempt_on.le(UTC::now())))
+            .order(next_attempt_on.asc())
+            .limit(limit)
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Takes a mention off the queue with its final status, `sent` or
+    /// `unsupported` if the target has no webmention endpoint.
+    pub fn finish(mention_id: i32, final_status: &str, conn: &PgConnection) -> Result<()> {
+        use schema::outgoing_webmentions::dsl::*;
+
+        diesel::update(outgoing_webmentions.filter(id.eq(mention_id)))
+            .set((status.eq(final_status), attempts.eq(attempts + 1), last_error.eq(None::<String>)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Records a failed attempt. The mention is retried with exponential
+    /// backoff until `max_attempts` is reached, then given up.
+    pub fn mark_failed(mention: &OutgoingWebmention,
+                       error: &str,
+                       max_attempts: i32,
+                       conn: &PgConnection)
+                       -> Result<()> {
+        use schema::outgoing_webmentions::dsl::*;
+
+        let tries = mention.attempts + 1;
+        let new_status = if tries >= max_attempts { "failed" } else { "pending" };
+        let retry_on = UTC::now() + Duration::minutes(2i64.pow(cmp::min(tries, 12) as u32));
+        diesel::update(outgoing_webmentions.filter(id.eq(mention.id)))
+            .set((status.eq(new_status),
+                  attempts.eq(tries),
+                  last_error.eq(Some(error.to_string())),
+                  next_attempt_on.eq(retry_on)))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+pub mod token {
+    use chrono::UTC;
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+    use rand::{OsRng, Rng};
+
+    use model::{ApiToken, NewApiToken, User};
+    use util;
+
+    /// Creates a token for `owner` and returns it together with the secret,
+    /// which is not stored and cannot be recovered later.
+    pub fn create(owner: i32, token_name: &str, token_scope: &str, conn: &PgConnection) -> Result<(ApiToken, String)> {
+        use schema::api_tokens;
+
+        let mut bytes = [0u8; 32];
+        OsRng::new()?.fill_bytes(&mut bytes);
+        let secret = util::hex(&bytes);
+        let new_token = NewApiToken {
+            user_id: owner,
+            name: token_name.into(),
+            token_hash: util::sha256_hex(secret.as_bytes()),
+            scope: token_scope.into(),
+        };
+        let token = diesel::insert(&new_token)
+            .into(api_tokens::table)
+            .get_result(conn)?;
+        Ok((token, secret))
+    }
+
+    pub fn find_for_user(owner: i32, conn: &PgConnection) -> Result<Vec<ApiToken>> {
+        use schema::api_tokens::dsl::*;
+
+        api_tokens
+            .filter(user_id.eq(owner))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    /// Looks up the token and its owner for a secret sent by a client and
+    /// notes that it was used.
+    pub fn authenticate(secret: &str, conn: &PgConnection) -> Result<Option<(ApiToken, User)>> {
+        use schema::api_tokens::dsl::*;
+
+        let token = api_tokens
+            .filter(token_hash.eq(util::sha256_hex(secret.as_bytes())))
+            .first::<ApiToken>(conn)
+            .optional()?;
+        match token {
+            Some(token) => {
+                diesel::update(api_tokens.filter(id.eq(token.id)))
+                    .set(last_used_on.eq(Some(UTC::now())))
+                    .execute(conn)?;
+                let user = super::user::find_by_id(token.user_id, conn)?;
+                Ok(Some((token, user)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes a token of `owner`. Returns false if there was none.
+    pub fn revoke(token_id: i32, owner: i32, conn: &PgConnection) -> Result<bool> {
+        use schema::api_tokens::dsl::*;
+
+        let deleted = diesel::delete(api_tokens.filter(id.eq(token_id).and(user_id.eq(owner))))
+            .execute(conn)?;
+        Ok(deleted > 0)
+    }
+}
+
+pub mod activitypub {
+    use std::cmp;
+
+    use chrono::{Duration, UTC};
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use http_signature;
+    use model::{ActorKey, Delivery, Follower, NewActorKey, NewDelivery, NewFollower};
+
+    /// Returns the key pair of a user, generating it on first use.
+    pub fn keys(owner: i32, conn: &PgConnection) -> Result<ActorKey> {
+        use schema::actor_keys::dsl::*;
+
+        let existing = actor_keys
+            .filter(user_id.eq(owner))
+            .first::<ActorKey>(conn)
+            .optional()?;
+        if let Some(key) = existing {
+            return Ok(key);
+        }
+        let (public, private) = http_signature::generate_keypair()?;
+        let new_key = NewActorKey {
+            user_id: owner,
+            public_key_pem: public,
+            private_key_pem: private,
+        };
+        diesel::insert(&new_key)
+            .into(actor_keys)
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    /// Adds a follower, or updates the inbox of an existing one.
+    pub fn add_follower(follower: &NewFollower, conn: &PgConnection) -> Result<()> {
+        use schema::followers::dsl::*;
+
+        conn.transaction::<_, Error, _>(|| {
+            let updated = diesel::update(followers.filter(user_id.eq(follower.user_id)
+                                                              .and(actor.eq(&follower.actor))))
+                .set(inbox.eq(&follower.inbox))
+                .execute(conn)?;
+            if updated == 0 {
+                diesel::insert(follower)
+                    .into(followers)
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove_follower(owner: i32, follower: &str, conn: &PgConnection) -> Result<()> {
+        use schema::followers::dsl::*;
+
+        diesel::delete(followers.filter(user_id.eq(owner).and(actor.eq(follower)))).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn find_followers(owner: i32, conn: &PgConnection) -> Result<Vec<Follower>> {
+        use schema::followers::dsl::*;
+
+        followers
+            .filter(user_id.eq(owner))
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    pub fn count_followers(owner: i32, conn: &PgConnection) -> Result<i64> {
+        use schema::followers::dsl::*;
+
+        followers
+            .filter(user_id.eq(owner))
+            .count()
+            .get_result(conn)
+            .map_err(From::from)
+    }
+
+    /// Queues an activity by `owner` for delivery to each of `inboxes`.
+    pub fn enqueue(owner: i32, inboxes: &[String], activity_json: &str, conn: &PgConnection) -> Result<usize> {
+        use schema::deliveries::dsl::*;
+
+        let new: Vec<NewDelivery> = inboxes
+            .iter()
+            .map(|target| {
+                     NewDelivery {
+                         user_id: owner,
+                         inbox: target.clone(),
+                         activity: activity_json.to_string(),
+                     }
+                 })
+            .collect();
+        if new.is_empty() {
+            return Ok(0);
+        }
+        diesel::insert(&new)
+            .into(deliveries)
+            .execute(conn)
+            .map_err(From::from)
+    }
+
+    /// Returns deliveries that are due to be sent.
+    pub fn find_due(limit: i64, conn: &PgConnection) -> Result<Vec<Delivery>> {
+        use schema::deliveries::dsl::*;
+
+        deliveries
+            .filter(status.eq("pending").and(next_attempt_on.le(UTC::now())))
+            .order(next_attempt_on.asc())
+            .limit(limit)
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    pub fn mark_delivered(delivery_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::deliveries::dsl::*;
+
+        diesel::update(deliveries.filter(id.eq(delivery_id)))
+            .set((status.eq("delivered"), attempts.eq(attempts + 1), last_error.eq(None::<String>)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Records a failed attempt. The delivery is retried with exponential
+    /// backoff until `max_attempts` is reached, then given up.
+    pub fn mark_failed(delivery: &Delivery, error: &str, max_attempts: i32, conn: &PgConnection) -> Result<()> {
+        use schema::deliveries::dsl::*;
+
+        let tries = delivery.attempts + 1;
+        let new_status = if tries >= max_attempts { "failed" } else { "pending" };
+        let retry_on = UTC::now() + Duration::minutes(2i64.pow(cmp::min(tries, 12) as u32));
+        diesel::update(deliveries.filter(id.eq(delivery.id)))
+            .set((status.eq(new_status),
+                  attempts.eq(tries),
+                  last_error.eq(Some(error.to_string())),
+                  next_attempt_on.eq(retry_on)))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+pub mod media {
+    use errors::*;
+    use diesel::prelude::*;
+    use diesel;
+    use diesel::pg::PgConnection;
+
+    use model::{MediaFile, MediaVariant, NewMediaFile};
+
+    /// Adds a file to its owner's library, along with the variants of the
+    /// stored file. If the owner already uploaded the same content, the
+    /// existing entry is returned.
+    pub fn add(file: &NewMediaFile, new_variants: &[MediaVariant], conn: &PgConnection) -> Result<MediaFile> {
+        use schema::media::dsl::*;
+        use schema::media_variants;
+
+        conn.transaction::<_, Error, _>(|| {
+            for variant in new_variants {
+                let exists = media_variants::table
+                    .filter(media_variants::name.eq(&variant.name))
+                    .count()
+                    .get_result::<i64>(conn)? > 0;
+                if !exists {
+                    diesel::insert(variant)
+                        .into(media_variants::table)
+                        .execute(conn)?;
+                }
+            }
+            let existing = media
+                .filter(owner_id.eq(file.owner_id).and(checksum.eq(&file.checksum)))
+                .first::<MediaFile>(conn)
+                .optional()?;
+            match existing {
+                Some(existing) => Ok(existing),
+                None => {
+                    diesel::insert(file)
+                        .into(media)
+                        .get_result(conn)
+                        .map_err(From::from)
+                }
+            }
+        })
+    }
+
+    /// Returns the library of a user, newest first.
+    pub fn find_for_owner(owner: i32, conn: &PgConnection) -> Result<Vec<MediaFile>> {
+        use schema::media::dsl::*;
+
+        media
+            .filter(owner_id.eq(owner))
+            .order(created_on.desc())
+            .load(conn)
+            .map_err(From::from)
+    }
+
+    pub fn find_owned(file_id: i32, owner: i32, conn: &PgConnection) -> Result<Option<MediaFile>> {
+        use schema::media::dsl::*;
+
+        media
+            .filter(id.eq(file_id).and(owner_id.eq(owner)))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Finds a library entry for the file stored as `stored_name`.
+    pub fn find_by_name(stored_name: &str, conn: &PgConnection) -> Result<Option<MediaFile>> {
+        use schema::media::dsl::*;
+
+        media
+            .filter(name.eq(stored_name))
+            .first(conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Returns the variants of a stored file, smallest first.
+    pub fn find_variants(stored_name: &str, conn: &PgConnection) -> Result<Vec<MediaVariant>> {
+        use schema::media_variants::dsl::*;
+
+        media_variants
+            .filter(original.eq(stored_name))
+            .order(width.asc())
             .load(conn)
             .map_err(From::from)
     }
+
+    pub fn delete_variants(stored_name: &str, conn: &PgConnection) -> Result<()> {
+        use schema::media_variants::dsl::*;
+
+        diesel::delete(media_variants.filter(original.eq(stored_name))).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(file_id: i32, conn: &PgConnection) -> Result<()> {
+        use schema::media::dsl::*;
+
+        diesel::delete(media.filter(id.eq(file_id))).execute(conn)?;
+        Ok(())
+    }
+
+    /// Returns true if any library still contains the file stored as
+    /// `stored_name`.
+    pub fn is_referenced(stored_name: &str, conn: &PgConnection) -> Result<bool> {
+        use schema::media::dsl::*;
+
+        let count: i64 = media
+            .filter(name.eq(stored_name))
+            .count()
+            .get_result(conn)?;
+        Ok(count > 0)
+    }
 }
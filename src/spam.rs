@@ -0,0 +1,399 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, TimeZone, UTC};
+use diesel::prelude::*;
+use diesel;
+use diesel::expression::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
+use diesel::types::Integer;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::{OsRng, Rng};
+use regex::Regex;
+
+use errors::*;
+use model::NewSpamToken;
+use util;
+
+/// What kind of form a submission came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmissionKind {
+    Comment,
+    Registration,
+}
+
+/// A form submission to be checked for spam.
+#[derive(Debug, Clone)]
+pub struct Submission<'a> {
+    pub kind: SubmissionKind,
+    pub author: &'a str,
+    pub email: Option<&'a str>,
+    pub body: &'a str,
+    /// The value of the hidden honeypot field.
+    pub honeypot: Option<&'a str>,
+    /// When the form was rendered, from its stamp. See `parse_stamp`.
+    pub rendered_at: Option<DateTime<UTC>>,
+}
+
+lazy_static! {
+    /// Signs the render time of forms. It lives as long as the process, so
+    /// forms rendered before a restart count as unstamped.
+    static ref STAMP_KEY: Vec<u8> = {
+        let mut key = vec![0u8; 32];
+        OsRng::new().expect("Failed to open the OS random number generator").fill_bytes(&mut key);
+        key
+    };
+}
+
+fn stamp_mac(seconds: i64) -> Result<Vec<u8>> {
+    let key = PKey::hmac(&STAMP_KEY)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(seconds.to_string().as_bytes())?;
+    Ok(signer.finish()?)
+}
+
+/// A stamp for the hidden `rendered_at` field of a form, the render time
+/// and its MAC, so that clients can't claim a form was rendered earlier
+/// than it was.
+pub fn stamp(rendered_at: DateTime<UTC>) -> Result<String> {
+    let seconds = rendered_at.timestamp();
+    Ok(format!("{}.{}", seconds, util::hex(&stamp_mac(seconds)?)))
+}
+
+/// Returns the render time of a stamp made by `stamp`, or `None` if the
+/// stamp is malformed, forged or out of range.
+pub fn parse_stamp(value: &str) -> Option<DateTime<UTC>> {
+    let mut parts = value.splitn(2, '.');
+    let seconds: i64 = parts.next()?.parse().ok()?;
+    let mac = parts.next()?;
+    let expected = util::hex(&stamp_mac(seconds).ok()?);
+    if mac.len() != expected.len() || !memcmp::eq(mac.as_bytes(), expected.as_bytes()) {
+        return None;
+    }
+    UTC.timestamp_opt(seconds, 0).single()
+}
+
+/// A single spam heuristic.
+pub trait SpamCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Scores a submission from 0 (certainly legitimate, or no opinion) to
+    /// 1 (certainly spam).
+    fn score(&self, submission: &Submission, conn: &PgConnection) -> Result<f64>;
+}
+
+/// Bots fill in every field, including one that is hidden from humans.
+pub struct Honeypot;
+
+impl SpamCheck for Honeypot {
+    fn name(&self) -> &'static str {
+        "honeypot"
+    }
+
+    fn score(&self, submission: &Submission, _: &PgConnection) -> Result<f64> {
+        match submission.honeypot {
+            Some(value) if !value.trim().is_empty() => Ok(1.0),
+            _ => Ok(0.0),
+        }
+    }
+}
+
+/// Humans take a while to fill in a form, bots submit right away or replay
+/// old forms without rendering them.
+pub struct TimeToSubmit {
+    pub min: Duration,
+}
+
+impl SpamCheck for TimeToSubmit {
+    fn name(&self) -> &'static str {
+        "time_to_submit"
+    }
+
+    fn score(&self, submission: &Submission, _: &PgConnection) -> Result<f64> {
+        let now = UTC::now();
+        match submission.rendered_at {
+            None => Ok(0.7),
+            Some(rendered) if rendered > now => Ok(0.7),
+            Some(rendered) if now - rendered < self.min => Ok(0.95),
+            Some(_) => Ok(0.0),
+        }
+    }
+}
+
+fn count_links(text: &str) -> usize {
+    lazy_static! {
+        static ref LINK_REGEX: Regex = Regex::new(r"(?i)https?://|www\.|<a\s").unwrap();
+    }
+    LINK_REGEX.find_iter(text).count()
+}
+
+/// Spam is usually there for the links.
+pub struct LinkCount {
+    /// Number of links that is still considered normal.
+    pub max_links: usize,
+}
+
+impl SpamCheck for LinkCount {
+    fn name(&self) -> &'static str {
+        "link_count"
+    }
+
+    fn score(&self, submission: &Submission, _: &PgConnection) -> Result<f64> {
+        if count_links(submission.author) > 0 {
+            return Ok(1.0);
+        }
+        let links = count_links(submission.body);
+        if links <= self.max_links {
+            Ok(0.0)
+        } else if links <= self.max_links * 2 {
+            Ok(0.6)
+        } else {
+            Ok(0.95)
+        }
+    }
+}
+
+/// Splits text into the distinct lowercase words and link hosts the
+/// classifier works on.
+fn tokenize(text: &str) -> HashSet<String> {
+    lazy_static! {
+        static ref WORD_REGEX: Regex = Regex::new(r"[\w'$€-]{3,30}").unwrap();
+        static ref HOST_REGEX: Regex = Regex::new(r"(?i)https?://([^/\s)]+)").unwrap();
+    }
+    let mut tokens: HashSet<String> = WORD_REGEX
+        .find_iter(text)
+        .map(|m| m.as_str().to_lowercase())
+        .collect();
+    for caps in HOST_REGEX.captures_iter(text) {
+        tokens.insert(format!("host:{}", caps[1].to_lowercase()));
+    }
+    tokens
+}
+
+/// Naive Bayes classifier with its word counts stored in the `spam_tokens`
+/// and `spam_corpus` tables. Trained from moderation decisions.
+#[derive(Debug, Clone)]
+pub struct BayesClassifier {
+    /// Below this many trained documents per label the classifier abstains.
+    pub min_documents: i32,
+    /// How many of the most telling tokens are combined.
+    pub interesting_tokens: usize,
+}
+
+impl BayesClassifier {
+    fn corpus_sizes(&self, conn: &PgConnection) -> Result<(i32, i32)> {
+        use schema::spam_corpus::dsl::*;
+
+        let sizes: HashMap<String, i32> = spam_corpus.load::<(String, i32)>(conn)?.into_iter().collect();
+        Ok((sizes.get("spam").cloned().unwrap_or(0), sizes.get("ham").cloned().unwrap_or(0)))
+    }
+
+    fn update(&self, text: &str, is_spam: bool, delta: i32, conn: &PgConnection) -> Result<()> {
+        use schema::spam_corpus::dsl::*;
+        use schema::spam_tokens::dsl::*;
+
+        let added = cmp::max(delta, 0);
+        let rows: Vec<NewSpamToken> = tokenize(text)
+            .iter()
+            .map(|t| {
+                     NewSpamToken {
+                         token: t.clone(),
+                         spam_count: if is_spam { added } else { 0 },
+                         ham_count: if is_spam { 0 } else { added },
+                     }
+                 })
+            .collect();
+        // Counts never drop below zero, untraining a text the classifier
+        // was not trained with must not break the statistics.
+        let adjusted = |column: &str| sql::<Integer>(&format!("greatest(spam_tokens.{} + {}, 0)", column, delta));
+        conn.transaction::<_, Error, _>(|| {
+            if !rows.is_empty() {
+                if is_spam {
+                    diesel::insert(&rows.on_conflict(token, do_update().set(spam_count.eq(adjusted("spam_count")))))
+                        .into(spam_tokens)
+                        .execute(conn)?;
+                } else {
+                    diesel::insert(&rows.on_conflict(token, do_update().set(ham_count.eq(adjusted("ham_count")))))
+                        .into(spam_tokens)
+                        .execute(conn)?;
+                }
+            }
+            diesel::update(spam_corpus.filter(label.eq(if is_spam { "spam" } else { "ham" })))
+                .set(documents.eq(sql::<Integer>(&format!("greatest(documents + {}, 0)", delta))))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn train(&self, text: &str, is_spam: bool, conn: &PgConnection) -> Result<()> {
+        self.update(text, is_spam, 1, conn)
+    }
+
+    /// Reverts an earlier call to `train` with the same arguments.
+    pub fn untrain(&self, text: &str, is_spam: bool, conn: &PgConnection) -> Result<()> {
+        self.update(text, is_spam, -1, conn)
+    }
+}
+
+impl SpamCheck for BayesClassifier {
+    fn name(&self) -> &'static str {
+        "bayes"
+    }
+
+    fn score(&self, submission: &Submission, conn: &PgConnection) -> Result<f64> {
+        use schema::spam_tokens::dsl::*;
+
+        let (spam_docs, ham_docs) = self.corpus_sizes(conn)?;
+        if spam_docs < self.min_documents || ham_docs < self.min_documents {
+            return Ok(0.0);
+        }
+        let words: Vec<String> = tokenize(submission.body).into_iter().collect();
+        if words.is_empty() {
+            return Ok(0.0);
+        }
+        let counts = spam_tokens
+            .filter(token.eq_any(words))
+            .load::<(String, i32, i32)>(conn)?;
+
+        // Per-token spam probabilities, pulled towards 0.5 for rarely seen
+        // tokens (Robinson's method).
+        let mut probabilities: Vec<f64> = counts
+            .iter()
+            .filter(|&&(_, spam, ham)| spam + ham > 0)
+            .map(|&(_, spam, ham)| {
+                let spam_freq = spam as f64 / spam_docs as f64;
+                let ham_freq = ham as f64 / ham_docs as f64;
+                let p = spam_freq / (spam_freq + ham_freq);
+                let n = (spam + ham) as f64;
+                let p = (0.5 + n * p) / (1.0 + n);
+                p.max(0.01).min(0.99)
+            })
+            .collect();
+        if probabilities.is_empty() {
+            return Ok(0.0);
+        }
+        probabilities.sort_by(|a, b| {
+            (b - 0.5).abs().partial_cmp(&(a - 0.5).abs()).unwrap()
+        });
+        probabilities.truncate(self.interesting_tokens);
+
+        let log_spam: f64 = probabilities.iter().map(|p| p.ln()).sum();
+        let log_ham: f64 = probabilities.iter().map(|p| (1.0 - p).ln()).sum();
+        Ok(1.0 / (1.0 + (log_ham - log_spam).exp()))
+    }
+}
+
+/// What to do with a checked submission.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Needs to be looked at by a human.
+    Suspicious(f64),
+    Spam(f64),
+}
+
+impl Verdict {
+    pub fn score(&self) -> f64 {
+        match *self {
+            Verdict::Suspicious(score) | Verdict::Spam(score) => score,
+        }
+    }
+
+    pub fn is_spam(&self) -> bool {
+        match *self {
+            Verdict::Spam(_) => true,
+            Verdict::Suspicious(_) => false,
+        }
+    }
+}
+
+/// Runs all checks and combines them. Managed as Rocket state.
+pub struct SpamFilter {
+    checks: Vec<Box<SpamCheck>>,
+    classifier: BayesClassifier,
+    /// Submissions scoring at least this are treated as spam.
+    pub threshold: f64,
+}
+
+impl SpamFilter {
+    pub fn new(threshold: f64) -> SpamFilter {
+        let classifier = BayesClassifier {
+            min_documents: 10,
+            interesting_tokens: 15,
+        };
+        SpamFilter {
+            checks: vec![Box::new(Honeypot),
+                         Box::new(TimeToSubmit { min: Duration::seconds(3) }),
+                         Box::new(LinkCount { max_links: 2 }),
+                         Box::new(classifier.clone())],
+            classifier,
+            threshold,
+        }
+    }
+
+    pub fn add_check<C: SpamCheck + 'static>(&mut self, check: C) {
+        self.checks.push(Box::new(check));
+    }
+
+    /// Scores the submission with every check and keeps the highest score.
+    /// A check that fails is logged and ignored.
+    pub fn check(&self, submission: &Submission, conn: &PgConnection) -> Verdict {
+        let score = self.checks
+            .iter()
+            .filter_map(|check| match check.score(submission, conn) {
+                            Ok(score) => {
+                                debug!("Spam check {} scored {}", check.name(), score);
+                                Some(score)
+                            }
+                            Err(e) => {
+                                warn!("Spam check {} failed: {}", check.name(), e);
+                                None
+                            }
+                        })
+            .fold(0.0, f64::max);
+        if score >= self.threshold {
+            Verdict::Spam(score)
+        } else {
+            Verdict::Suspicious(score)
+        }
+    }
+
+    /// Feeds a moderation decision back into the classifier. `previous` is
+    /// the label the text was trained with before, which is reverted.
+    pub fn learn(&self,
+                 text: &str,
+                 is_spam: bool,
+                 previous: Option<bool>,
+                 conn: &PgConnection)
+                 -> Result<()> {
+        match previous {
+            Some(was_spam) if was_spam == is_spam => return Ok(()),
+            Some(was_spam) => self.classifier.untrain(text, was_spam, conn)?,
+            None => {}
+        }
+        self.classifier.train(text, is_spam, conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamp_round_trips() {
+        let rendered_at = UTC.timestamp(1500000000, 0);
+        assert_eq!(parse_stamp(&stamp(rendered_at).unwrap()), Some(rendered_at));
+    }
+
+    #[test]
+    fn forged_stamps_are_ignored() {
+        let stamp = stamp(UTC.timestamp(1500000000, 0)).unwrap();
+        let mac = stamp.splitn(2, '.').nth(1).unwrap();
+        assert_eq!(parse_stamp(&format!("1400000000.{}", mac)), None);
+        assert_eq!(parse_stamp("1400000000"), None);
+        assert_eq!(parse_stamp(&format!("{}.{}", i64::max_value(), mac)), None);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, TimeZone, UTC};
+use rocket::Outcome;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+use errors::*;
+use util;
+
+/// Cache-Control for files whose name changes with their content.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Cache-Control for all other files: they may be cached, but have to be
+/// revalidated.
+const REVALIDATE: &str = "public, no-cache";
+
+/// Precompressed variants, in order of preference, with their file suffix.
+const ENCODINGS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Returns true for file names that contain a fingerprint, a part of at
+/// least eight hex digits between dots, as in `app.1a2b3c4d.js`.
+pub fn is_fingerprinted(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let parts: Vec<&str> = name.split('.').collect();
+    parts.len() >= 3 &&
+    parts[1..parts.len() - 1]
+        .iter()
+        .any(|p| p.len() >= 8 && p.chars().all(|c| c.is_digit(16)))
+}
+
+/// The request's `Accept-Encoding` header. Never fails.
+pub struct AcceptEncoding(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for AcceptEncoding {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AcceptEncoding, ()> {
+        Outcome::Success(AcceptEncoding(request.headers().get_one("Accept-Encoding").map(String::from)))
+    }
+}
+
+/// The content hashes of served files, so that files are only hashed
+/// again when they change.
+struct HashCache(RwLock<HashMap<PathBuf, (SystemTime, u64, String)>>);
+
+impl HashCache {
+    fn etag(&self, path: &Path, modified: SystemTime, len: u64) -> Result<String> {
+        if let Some(&(cached_modified, cached_len, ref hash)) = self.0.read().unwrap().get(path) {
+            if cached_modified == modified && cached_len == len {
+                return Ok(hash.clone());
+            }
+        }
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let hash = util::sha256_hex(&data)[..32].to_string();
+        self.0
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, len, hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// Serves the files below a directory. Managed as Rocket state.
+pub struct StaticFiles {
+    root: PathBuf,
+    hashes: HashCache,
+    /// Fingerprinted names of assets, with the files they stand for.
+    aliases: HashMap<PathBuf, PathBuf>,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        Ok(StaticFiles {
+               root: root.as_ref().canonicalize()?,
+               hashes: HashCache(RwLock::new(HashMap::new())),
+               aliases: HashMap::new(),
+           })
+    }
+
+    /// Serves the file at `path` under the name `alias` as well, both
+    /// relative to the root.
+    pub fn alias<P: Into<PathBuf>>(&mut self, alias: P, path: P) {
+        self.aliases.insert(alias.into(), path.into());
+    }
+
+    /// Resolves a relative path to a file below the root. Paths that leave
+    /// the root, including through symlinks, are refused.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let full = self.root.join(path).canonicalize().ok()?;
+        if full.starts_with(&self.root) && full.is_file() {
+            Some(full)
+        } else {
+            None
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<(SystemTime, u64, String)> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        let etag = self.hashes.etag(path, modified, metadata.len())?;
+        Ok((modified, metadata.len(), etag))
+    }
+
+    /// Opens the file at `path`, relative to the root. `accept_encoding` is
+    /// the request's `Accept-Encoding` header, a precompressed `.br` or
+    /// `.gz` file next to the requested one is served if it allows that.
+    pub fn open(&self, path: &Path, accept_encoding: Option<&str>) -> Result<Option<StaticFile>> {
+        let target = self.aliases.get(path).map(|p| p.as_path()).unwrap_or(path);
+        let original = match self.resolve(target) {
+            Some(original) => original,
+            None => return Ok(None),
+        };
+        let content_type = original
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ContentType::from_extension);
+        let (modified, _, etag) = self.metadata(&original)?;
+
+        let mut compressed = vec![];
+        for &(encoding, suffix) in ENCODINGS {
+            let mut name = original.file_name().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(suffix);
+            if let Some(variant) = self.resolve(&original.with_file_name(name)) {
+                compressed.push((encoding, variant));
+            }
+        }
+        let accepted = compressed
+            .iter()
+            .find(|&&(encoding, _)| accept_encoding.map(|h| util::accepts_encoding(h, encoding)).unwrap_or(false))
+            .cloned();
+        let (file, encoding, etag) = match accepted {
+            Some((encoding, variant)) => {
+                let (_, _, variant_etag) = self.metadata(&variant)?;
+                (variant, Some(encoding), format!("\"{}-{}\"", variant_etag, encoding))
+            }
+            None => (original.clone(), None, format!("\"{}\"", etag)),
+        };
+        let seconds = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Ok(Some(StaticFile {
+                    file,
+                    content_type,
+                    encoding,
+                    etag,
+                    modified: UTC.timestamp(seconds, 0),
+                    immutable: is_fingerprinted(path),
+                    vary: !compressed.is_empty(),
+                }))
+    }
+}
+
+/// A static file, answered with `304 Not Modified` if the client's copy is
+/// still current.
+pub struct StaticFile {
+    file: PathBuf,
+    content_type: Option<ContentType>,
+    encoding: Option<&'static str>,
+    /// A strong ETag including the quotes.
+    etag: String,
+    modified: DateTime<UTC>,
+    immutable: bool,
+    /// Whether the response depends on `Accept-Encoding`.
+    vary: bool,
+}
+
+impl StaticFile {
+    /// `If-None-Match` takes precedence over `If-Modified-Since`, and uses
+    /// the weak comparison.
+    fn is_not_modified(&self, request: &Request) -> bool {
+        if let Some(header) = request.headers().get_one("If-None-Match") {
+            return header.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_left_matches("W/") == self.etag
+            });
+        }
+        request
+            .headers()
+            .get_one("If-Modified-Since")
+            .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+            .map(|since| self.modified <= since.with_timezone(&UTC))
+            .unwrap_or(false)
+    }
+}
+
+impl<'r> Responder<'r> for StaticFile {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response
+            .raw_header("ETag", self.etag.clone())
+            .raw_header("Last-Modified", self.modified.format(HTTP_DATE).to_string())
+            .raw_header("Cache-Control", if self.immutable { IMMUTABLE } else { REVALIDATE });
+        if self.vary {
+            response.raw_header("Vary", "Accept-Encoding");
+        }
+        if self.is_not_modified(request) {
+            return response.status(Status::NotModified).ok();
+        }
+        if let Some(content_type) = self.content_type {
+            response.header(content_type);
+        }
+        if let Some(encoding) = self.encoding {
+            response.raw_header("Content-Encoding", encoding);
+        }
+        let file = File::open(&self.file).map_err(|e| {
+                                                       warn!("Error opening {}: {}", self.file.display(), e);
+                                                       Status::InternalServerError
+                                                   })?;
+        response.sized_body(file).ok()
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A canned response: status line, extra headers and body.
+#[derive(Debug, Clone)]
+pub struct Canned {
+    pub status: &'static str,
+    pub headers: Vec<String>,
+    pub body: String,
+}
+
+impl Canned {
+    pub fn new(status: &'static str, body: &str) -> Canned {
+        Canned {
+            status,
+            headers: vec![],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(mut self, header: &str) -> Canned {
+        self.headers.push(header.to_string());
+        self
+    }
+}
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// A small HTTP server on a local port that stands in for other sites in
+/// tests. It answers each path with its canned response, or 404.
+pub struct TestServer {
+    pub port: u16,
+    routes: Arc<Mutex<HashMap<String, Canned>>>,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let received = Arc::new(Mutex::new(vec![]));
+        let (r, rec) = (routes.clone(), received.clone());
+        thread::spawn(move || for stream in listener.incoming() {
+                          if let Ok(stream) = stream {
+                              handle(stream, &r, &rec);
+                          }
+                      });
+        TestServer {
+            port,
+            routes,
+            received,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn route(&self, path: &str, response: Canned) {
+        self.routes.lock().unwrap().insert(path.to_string(), response);
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+fn handle(stream: TcpStream, routes: &Mutex<HashMap<String, Canned>>, received: &Mutex<Vec<Received>>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    // Connections that are closed without a request, such as the checks
+    // of `HttpClient`, are ignored.
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("").to_string();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()));
+        }
+    }
+    let length = headers
+        .iter()
+        .find(|&&(ref name, _)| name == "content-length")
+        .and_then(|&(_, ref value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    let _ = reader.read_exact(&mut body);
+    let canned = routes
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .unwrap_or_else(|| Canned::new("404 Not Found", "not found"));
+    received
+        .lock()
+        .unwrap()
+        .push(Received {
+                  method,
+                  path,
+                  headers,
+                  body: String::from_utf8_lossy(&body).into_owned(),
+              });
+    let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                               canned.status,
+                               canned.body.len());
+    for header in &canned.headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(&canned.body);
+    let _ = reader.get_mut().write_all(response.as_bytes());
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::cmp;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, UTC};
+use diesel::prelude::*;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::*;
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+
+use db_util::Pool;
+use errors::*;
+use model::LoginAttempt;
+use service::audit;
+
+/// The most keys that aren't locked out `MemoryStore` keeps. When there
+/// are more, the least recently failed one is dropped.
+const MAX_MEMORY_KEYS: usize = 100000;
+
+/// Storage backend for failed login attempts, keyed by IP address or
+/// user name.
+pub trait AttemptStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<LoginAttempt>>;
+
+    /// Counts a failed attempt for `key` at `now` and returns the updated
+    /// attempt, see `Policy::next_attempt`. Concurrent failures must all be
+    /// counted.
+    fn add_failure(&self, key: &str, now: DateTime<UTC>, policy: &Policy) -> Result<LoginAttempt>;
+
+    fn clear(&self, key: &str) -> Result<()>;
+}
+
+/// Keeps attempts in process memory. Lost on restart and not shared
+/// between instances. Holds at most `MAX_MEMORY_KEYS` keys that aren't
+/// locked out. Locked out keys are kept until their lockout ends, so that
+/// failures for other keys can't push them out.
+#[derive(Default)]
+pub struct MemoryStore {
+    attempts: Mutex<MemoryAttempts>,
+}
+
+#[derive(Default)]
+struct MemoryAttempts {
+    by_key: HashMap<String, MemoryEntry>,
+    /// The keys that aren't locked out, least recently failed first.
+    recent: BTreeMap<u64, String>,
+    /// The locked out keys by the end of their lockout.
+    locked: BTreeSet<(DateTime<UTC>, String)>,
+    /// Orders `recent`.
+    counter: u64,
+}
+
+struct MemoryEntry {
+    attempt: LoginAttempt,
+    /// The entry's key in `recent`, `None` while it is locked out.
+    recent: Option<u64>,
+}
+
+impl MemoryAttempts {
+    fn remove(&mut self, key: &str) -> Option<LoginAttempt> {
+        let entry = self.by_key.remove(key)?;
+        match (entry.recent, entry.attempt.locked_until) {
+            (Some(n), _) => {
+                self.recent.remove(&n);
+            }
+            (None, Some(until)) => {
+                self.locked.remove(&(until, key.to_string()));
+            }
+            (None, None) => {}
+        }
+        Some(entry.attempt)
+    }
+
+    fn insert(&mut self, attempt: LoginAttempt, now: DateTime<UTC>) {
+        let recent = match attempt.locked_until {
+            Some(until) if until > now => {
+                self.locked.insert((until, attempt.key.clone()));
+                None
+            }
+            _ => {
+                self.counter += 1;
+                self.recent.insert(self.counter, attempt.key.clone());
+                Some(self.counter)
+            }
+        };
+        self.by_key.insert(attempt.key.clone(), MemoryEntry { attempt, recent });
+    }
+
+    /// Drops the keys whose lockout has ended.
+    fn purge_lockouts(&mut self, now: DateTime<UTC>) {
+        let ended: Vec<String> = self.locked
+            .iter()
+            .take_while(|&&(until, _)| until <= now)
+            .map(|&(_, ref key)| key.clone())
+            .collect();
+        for key in ended {
+            self.remove(&key);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.recent.len() > MAX_MEMORY_KEYS {
+            let oldest = match self.recent.values().next() {
+                Some(key) => key.clone(),
+                None => return,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+impl AttemptStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<LoginAttempt>> {
+        let attempts = self.attempts.lock().unwrap();
+        Ok(attempts.by_key.get(key).map(|e| e.attempt.clone()))
+    }
+
+    fn add_failure(&self, key: &str, now: DateTime<UTC>, policy: &Policy) -> Result<LoginAttempt> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.purge_lockouts(now);
+        let previous = attempts.remove(key);
+        let attempt = policy.next_attempt(previous.as_ref(), key, now);
+        attempts.insert(attempt.clone(), now);
+        attempts.evict();
+        Ok(attempt)
+    }
+
+    fn clear(&self, key: &str) -> Result<()> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.remove(key);
+        Ok(())
+    }
+}
+
+/// Where failed login attempts are kept, see `MemoryStore` and `PgStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleStore {
+    Memory,
+    Postgres,
+}
+
+impl FromStr for ThrottleStore {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ThrottleStore> {
+        match s {
+            "memory" => Ok(ThrottleStore::Memory),
+            "postgres" => Ok(ThrottleStore::Postgres),
+            other => Err(format!("Unknown login throttle store: {}", other).into()),
+        }
+    }
+}
+
+/// Keeps attempts in the `login_attempts` table.
+pub struct PgStore {
+    pool: Pool,
+}
+
+impl PgStore {
+    pub fn new(pool: Pool) -> PgStore {
+        PgStore { pool }
+    }
+}
+
+impl AttemptStore for PgStore {
+    fn get(&self, attempt_key: &str) -> Result<Option<LoginAttempt>> {
+        use schema::login_attempts::dsl::*;
+
+        let conn = self.pool.get()?;
+        login_attempts
+            .filter(key.eq(attempt_key))
+            .first(&*conn)
+            .optional()
+            .map_err(From::from)
+    }
+
+    /// Locks the key's row for the update, so that concurrent failures
+    /// from other instances are not lost.
+    fn add_failure(&self, attempt_key: &str, now: DateTime<UTC>, policy: &Policy) -> Result<LoginAttempt> {
+        use schema::login_attempts::dsl::*;
+
+        let conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|| {
+            // There has to be a row to lock. Two failures for a new key
+            // would otherwise both try to insert one.
+            let new = LoginAttempt {
+                key: attempt_key.into(),
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            };
+            diesel::insert(&new.on_conflict_do_nothing())
+                .into(login_attempts)
+                .execute(&*conn)?;
+            // Updating the row without changing it locks it, like
+            // `SELECT ... FOR UPDATE`.
+            let previous = diesel::update(login_attempts.filter(key.eq(attempt_key)))
+                .set(failures.eq(failures))
+                .get_result::<LoginAttempt>(&*conn)?;
+            let attempt = policy.next_attempt(Some(&previous), attempt_key, now);
+            diesel::update(login_attempts.filter(key.eq(attempt_key)))
+                .set((failures.eq(attempt.failures),
+                      last_failure.eq(attempt.last_failure),
+                      locked_until.eq(attempt.locked_until)))
+                .execute(&*conn)?;
+            Ok(attempt)
+        })
+    }
+
+    fn clear(&self, attempt_key: &str) -> Result<()> {
+        use schema::login_attempts::dsl::*;
+
+        let conn = self.pool.get()?;
+        diesel::delete(login_attempts.filter(key.eq(attempt_key))).execute(&*conn)?;
+        Ok(())
+    }
+}
+
+/// How aggressively failed logins are throttled.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Number of consecutive failures after which the key is locked out.
+    pub max_failures: i32,
+    /// Delay after the first failure, doubled with every further failure.
+    pub base_delay: Duration,
+    /// Upper bound for the exponential backoff delay.
+    pub max_delay: Duration,
+    /// How long a key stays locked out once `max_failures` is reached.
+    pub lockout: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            max_failures: 5,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::minutes(1),
+            lockout: Duration::minutes(15),
+        }
+    }
+}
+
+impl Policy {
+    /// Whether an attempt no longer matters: its lockout is over, or it
+    /// was not locked out and its last failure is older than `lockout`.
+    fn is_expired(&self, attempt: &LoginAttempt, now: DateTime<UTC>) -> bool {
+        match attempt.locked_until {
+            Some(until) => until <= now,
+            None => attempt.last_failure + self.lockout <= now,
+        }
+    }
+
+    /// Counts a failure on top of `previous`. An expired attempt starts the
+    /// count from scratch. Locks the key out once `max_failures` is reached.
+    fn next_attempt(&self, previous: Option<&LoginAttempt>, key: &str, now: DateTime<UTC>) -> LoginAttempt {
+        let mut attempt = match previous {
+            Some(a) if !self.is_expired(a, now) => a.clone(),
+            _ => {
+                LoginAttempt {
+                    key: key.into(),
+                    failures: 0,
+                    last_failure: now,
+                    locked_until: None,
+                }
+            }
+        };
+        attempt.failures += 1;
+        attempt.last_failure = now;
+        if attempt.failures >= self.max_failures && attempt.locked_until.is_none() {
+            attempt.locked_until = Some(now + self.lockout);
+        }
+        attempt
+    }
+
+    fn backoff(&self, failures: i32) -> Duration {
+        if failures <= 0 {
+            return Duration::zero();
+        }
+        let exponent = cmp::min(failures - 1, 16) as u32;
+        cmp::min(self.base_delay * 2i32.pow(exponent), self.max_delay)
+    }
+}
+
+/// Per-IP and per-user name throttling of login attempts. Managed as
+/// Rocket state.
+pub struct LoginThrottle {
+    store: Box<AttemptStore>,
+    policy: Policy,
+}
+
+impl LoginThrottle {
+    pub fn new<S: AttemptStore + 'static>(store: S, policy: Policy) -> LoginThrottle {
+        LoginThrottle {
+            store: Box::new(store),
+            policy,
+        }
+    }
+
+    /// A throttle with the default policy, keeping attempts in `store`.
+    pub fn with_store(store: ThrottleStore, pool: &Pool) -> LoginThrottle {
+        match store {
+            ThrottleStore::Postgres => LoginThrottle::new(PgStore::new(pool.clone()), Policy::default()),
+            ThrottleStore::Memory => LoginThrottle::new(MemoryStore::default(), Policy::default()),
+        }
+    }
+
+    /// Returns the point in time until which the key may not attempt
+    /// another login, if any.
+    pub fn blocked_until(&self, key: &str) -> Result<Option<DateTime<UTC>>> {
+        let now = UTC::now();
+        let attempt = match self.store.get(key)? {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        if let Some(until) = attempt.locked_until {
+            if until > now {
+                return Ok(Some(until));
+            }
+        }
+        let until = attempt.last_failure + self.policy.backoff(attempt.failures);
+        if until > now { Ok(Some(until)) } else { Ok(None) }
+    }
+
+    pub fn record_failure(&self, key: &str, conn: &PgConnection) -> Result<()> {
+        let now = UTC::now();
+        let attempt = self.store.add_failure(key, now, &self.policy)?;
+        // The count starts from scratch after a lockout, so only the failure
+        // that caused it has exactly `max_failures`.
+        match attempt.locked_until {
+            Some(until) if attempt.failures == self.policy.max_failures => {
+                warn!("Locking out {} until {} after {} failed logins", key, until, attempt.failures);
+                audit::record("login_lockout",
+                              key,
+                              &format!("{} failed attempts, locked until {}", attempt.failures, until),
+                              conn)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self, key: &str) -> Result<()> {
+        self.store.clear(key)
+    }
+}
+
+/// Request guard for the login route. Fails with `429 Too Many Requests`
+/// while the client's IP address is throttled, the user name is checked
+/// by the handler once the form has been parsed.
+pub struct Throttle<'r> {
+    throttle: State<'r, LoginThrottle>,
+    ip: Option<IpAddr>,
+}
+
+/// IPv6 addresses are throttled by their /64 network, which usually
+/// belongs to a single client that can pick any address in it.
+fn ip_key(ip: &IpAddr) -> String {
+    match *ip {
+        IpAddr::V4(ref v4) => format!("ip:{}", v4),
+        IpAddr::V6(ref v6) => {
+            let s = v6.segments();
+            // IPv4-mapped, ::ffff:a.b.c.d.
+            if s[..5] == [0; 5] && s[5] == 0xffff {
+                format!("ip:{}.{}.{}.{}", s[6] >> 8, s[6] & 0xff, s[7] >> 8, s[7] & 0xff)
+            } else {
+                format!("ip:{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+            }
+        }
+    }
+}
+
+fn user_key(name: &str) -> String {
+    format!("user:{}", name.to_lowercase())
+}
+
+impl<'r> Throttle<'r> {
+    /// Returns the time until which a login as `name` is blocked.
+    pub fn blocked_until(&self, name: &str) -> Result<Option<DateTime<UTC>>> {
+        self.throttle.blocked_until(&user_key(name))
+    }
+
+    pub fn failure(&self, name: &str, conn: &PgConnection) -> Result<()> {
+        if let Some(ref ip) = self.ip {
+            self.throttle.record_failure(&ip_key(ip), conn)?;
+        }
+        self.throttle.record_failure(&user_key(name), conn)
+    }
+
+    pub fn success(&self, name: &str) -> Result<()> {
+        if let Some(ref ip) = self.ip {
+            self.throttle.record_success(&ip_key(ip))?;
+        }
+        self.throttle.record_success(&user_key(name))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Throttle<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Throttle<'r>, ()> {
+        let throttle = match <State<LoginThrottle> as FromRequest>::from_request(request) {
+            Outcome::Success(throttle) => throttle,
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(_) => return Outcome::Forward(()),
+        };
+        let ip = request.remote().map(|addr| addr.ip());
+        if let Some(ref ip) = ip {
+            match throttle.blocked_until(&ip_key(ip)) {
+                Ok(Some(_)) => return Outcome::Failure((Status::TooManyRequests, ())),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Error checking login throttle: {}", e);
+                    return Outcome::Failure((Status::ServiceUnavailable, ()));
+                }
+            }
+        }
+        Outcome::Success(Throttle { throttle, ip })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_lock_out_and_expire() {
+        let policy = Policy::default();
+        let store = MemoryStore::default();
+        let now = UTC::now();
+        for _ in 0..policy.max_failures - 1 {
+            assert!(store.add_failure("user:alice", now, &policy).unwrap().locked_until.is_none());
+        }
+        let locked = store.add_failure("user:alice", now, &policy).unwrap();
+        assert_eq!(locked.locked_until, Some(now + policy.lockout));
+        let later = now + policy.lockout;
+        assert_eq!(store.add_failure("user:alice", later, &policy).unwrap().failures, 1);
+    }
+
+    #[test]
+    fn memory_store_is_bounded() {
+        let policy = Policy::default();
+        let store = MemoryStore::default();
+        let now = UTC::now();
+        for i in 0..MAX_MEMORY_KEYS + 10 {
+            store
+                .add_failure(&format!("ip:{}", i), now + Duration::milliseconds(i as i64), &policy)
+                .unwrap();
+        }
+        assert_eq!(store.attempts.lock().unwrap().by_key.len(), MAX_MEMORY_KEYS);
+        assert!(store.get("ip:0").unwrap().is_none());
+        assert!(store.get(&format!("ip:{}", MAX_MEMORY_KEYS + 9)).unwrap().is_some());
+    }
+
+    #[test]
+    fn memory_store_keeps_lockouts_when_full() {
+        let policy = Policy::default();
+        let store = MemoryStore::default();
+        let now = UTC::now();
+        for _ in 0..policy.max_failures {
+            store.add_failure("user:victim", now, &policy).unwrap();
+        }
+        store.add_failure("user:other", now, &policy).unwrap();
+        for i in 0..MAX_MEMORY_KEYS + 10 {
+            store
+                .add_failure(&format!("ip:{}", i), now + Duration::milliseconds(i as i64), &policy)
+                .unwrap();
+        }
+        assert!(store.get("user:other").unwrap().is_none());
+        let victim = store.get("user:victim").unwrap().unwrap();
+        assert_eq!(victim.locked_until, Some(now + policy.lockout));
+
+        // The lockout is dropped once it has ended.
+        store.add_failure("ip:later", now + policy.lockout, &policy).unwrap();
+        assert!(store.get("user:victim").unwrap().is_none());
+    }
+
+    #[test]
+    fn ipv6_addresses_are_keyed_by_network() {
+        let key = |ip: &str| ip_key(&ip.parse().unwrap());
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), "ip:2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2:ffff::1"), key("2001:db8:1:2::9"));
+        assert_eq!(key("::ffff:192.0.2.1"), "ip:192.0.2.1");
+        assert_eq!(key("192.0.2.1"), "ip:192.0.2.1");
+    }
+}
@@ -1,12 +1,18 @@
 use std::ops::Deref;
 use std::time::Duration;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::fmt;
 
+use regex::Regex;
+use reqwest::Url;
 use reqwest::header::{ContentType, UserAgent};
 use reqwest::mime::Mime;
+use rocket::http::uri::URI;
+use rocket_contrib::handlebars::{Handlebars, Helper, JsonRender, RenderContext, RenderError};
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
+use sha2::{Digest, Sha256};
+use config::{self, MarkdownBackend};
 use errors::Result;
 use reqwest;
 
@@ -66,7 +72,11 @@ fn convert_markdown_plain(content: &str) -> String {
     ::markdown::to_html(content)
 }
 
+/// Renders Markdown with the backend from the settings.
 pub fn markdown_to_html(input: &str) -> String {
+    if config::current().site.markdown == MarkdownBackend::Plain {
+        return convert_markdown_plain(input);
+    }
     match convert_markdown_github(input) {
         Ok(s) => s,
         Err(why) => {
@@ -76,6 +86,143 @@ pub fn markdown_to_html(input: &str) -> String {
     }
 }
 
+/// Escapes the characters that are significant in HTML text and attributes.
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Registers the general template helpers. `{{url_segment tag}}` renders
+/// a value percent-encoded for use as a path segment of a link.
+pub fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("url_segment",
+                               Box::new(|h: &Helper, _: &Handlebars, rc: &mut RenderContext| {
+                                            let value = h.param(0)
+                                                .map(|p| p.value().render())
+                                                .ok_or_else(|| RenderError::new("url_segment: missing value"))?;
+                                            let encoded = escape_html(&URI::percent_encode(&value));
+                                            rc.writer.write_all(encoded.as_bytes())?;
+                                            Ok(())
+                                        }));
+}
+
+/// One line of a line-level diff between two texts.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffLine {
+    /// One of `added`, `removed` or `unchanged`.
+    pub kind: &'static str,
+    /// `+`, `-` or a blank, as in a unified diff.
+    pub marker: &'static str,
+    pub text: String,
+}
+
+impl DiffLine {
+    fn new(kind: &'static str, marker: &'static str, text: &str) -> DiffLine {
+        DiffLine {
+            kind,
+            marker,
+            text: text.into(),
+        }
+    }
+}
+
+pub fn line_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    ::diff::lines(old, new)
+        .into_iter()
+        .map(|line| match line {
+                 ::diff::Result::Left(text) => DiffLine::new("removed", "-", text),
+                 ::diff::Result::Right(text) => DiffLine::new("added", "+", text),
+                 ::diff::Result::Both(text, _) => DiffLine::new("unchanged", " ", text),
+             })
+        .collect()
+}
+
+/// Renders untrusted Markdown, such as comments, locally and strips any
+/// HTML that could be used for scripting or to break the page layout.
+pub fn markdown_to_safe_html(input: &str) -> String {
+    ::ammonia::clean(&convert_markdown_plain(input))
+}
+
+/// Lowercase hexadecimal encoding.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+/// The SHA-256 digest of `data`, hex encoded.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&sha256(data))
+}
+
+/// Returns true if an `Accept-Encoding` header value allows `encoding`:
+/// if it lists it without `q=0`, or else allows `*`.
+pub fn accepts_encoding(header: &str, encoding: &str) -> bool {
+    let mut wildcard = false;
+    for item in header.split(',') {
+        let mut parts = item.split(';').map(|p| p.trim());
+        let name = parts.next().unwrap_or("");
+        let allowed = !parts.any(|p| {
+            p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q <= 0.0).unwrap_or(false)
+        });
+        if name.eq_ignore_ascii_case(encoding) {
+            return allowed;
+        }
+        if name == "*" {
+            wildcard = allowed;
+        }
+    }
+    wildcard
+}
+
+/// The absolute URL of a post, as used by other sites to refer to it.
+pub fn post_url(post_id: i32) -> String {
+    format!("{}/post/{}", config::base_url(), post_id)
+}
+
+/// The absolute URL of a file in the media library.
+pub fn media_url(name: &str) -> String {
+    format!("{}/media/{}", config::base_url(), name)
+}
+
+/// Returns the id of the post an absolute URL points to, if it is a post
+/// on this blog.
+pub fn post_id_from_url(url: &str) -> Option<i32> {
+    lazy_static! {
+        static ref POST_PATH_REGEX: Regex = Regex::new(r"^/post/(\d+)/?$").unwrap();
+    }
+    if !url.starts_with(&format!("{}/", config::base_url())) {
+        return None;
+    }
+    let url = Url::parse(url).ok()?;
+    POST_PATH_REGEX
+        .captures(url.path())
+        .and_then(|caps| caps[1].parse().ok())
+}
+
+/// The number of rows before page `page_num`, or `None` if the page is
+/// negative or so far out that the offset overflows.
+pub fn page_offset(page_num: i64, page_size: i64) -> Option<i64> {
+    if page_num < 0 {
+        return None;
+    }
+    page_num.checked_mul(page_size)
+}
+
 pub struct Page<T> {
     pub data: Vec<T>,
     pub current_page: i64,
@@ -0,0 +1,444 @@
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use regex::{Captures, Regex};
+use reqwest::{Response, Url};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::response::status;
+
+use config;
+use db_util::{Connection, Pool};
+use errors::*;
+use http_client::HttpClient;
+use model::{NewWebmention, Post, WebmentionRequest};
+use service;
+use util;
+
+/// Sending or verifying a mention is given up after this many failed
+/// attempts.
+const MAX_ATTEMPTS: i32 = 6;
+
+/// Only this much of a fetched page is looked at.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// How many mentions of each kind are processed per run.
+const BATCH_SIZE: i64 = 20;
+
+lazy_static! {
+    static ref TAG_REGEX: Regex = Regex::new(r"(?is)<(a|link)\b[^>]*>").unwrap();
+    static ref HREF_REGEX: Regex =
+        Regex::new(r#"(?i)\shref\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+    static ref REL_REGEX: Regex =
+        Regex::new(r#"(?i)\brel\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>;,]+))"#).unwrap();
+    static ref LINK_HEADER_REGEX: Regex = Regex::new(r"<([^>]*)>([^<]*)").unwrap();
+    static ref ELEMENT_REGEX: Regex = Regex::new(r"(?s)<[a-zA-Z][^>]*>").unwrap();
+    static ref URL_ATTRIBUTE_REGEX: Regex =
+        Regex::new(r#"(?i)\s(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+    static ref TITLE_REGEX: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+}
+
+/// Returns the first group that matched, for regexes with alternative
+/// groups such as the quoting styles of attributes.
+fn first_group(caps: &Captures) -> Option<String> {
+    caps.iter()
+        .skip(1)
+        .filter_map(|m| m)
+        .next()
+        .map(|m| m.as_str().to_string())
+}
+
+/// Returns the first group of `regex` that matched in `text`.
+fn capture(regex: &Regex, text: &str) -> Option<String> {
+    regex.captures(text).and_then(|caps| first_group(&caps))
+}
+
+/// Decodes the few entities that show up in attribute values and titles.
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn is_webmention_rel(rel: &str) -> bool {
+    rel.split_whitespace().any(|r| r.eq_ignore_ascii_case("webmention"))
+}
+
+fn is_http_url(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| u.scheme() == "http" || u.scheme() == "https")
+        .unwrap_or(false)
+}
+
+/// Returns the distinct absolute `http` and `https` links of an HTML
+/// fragment, in order of appearance.
+pub fn extract_links(html: &str) -> Vec<String> {
+    let mut links: Vec<String> = vec![];
+    for caps in TAG_REGEX.captures_iter(html) {
+        if !caps[1].eq_ignore_ascii_case("a") {
+            continue;
+        }
+        if let Some(href) = capture(&HREF_REGEX, &caps[0]) {
+            let href = decode_entities(href.trim());
+            if is_http_url(&href) && !links.contains(&href) {
+                links.push(href);
+            }
+        }
+    }
+    links
+}
+
+/// Whether an `href` or `src` attribute of `html`, resolved against
+/// `base`, is `target`. Fragments are ignored, a link to a post's comments
+/// mentions the post.
+fn links_to(html: &str, base: &Url, target: &str) -> bool {
+    let without_fragment = |mut url: Url| {
+        url.set_fragment(None);
+        url
+    };
+    let target = match Url::parse(target) {
+        Ok(target) => without_fragment(target),
+        Err(_) => return false,
+    };
+    ELEMENT_REGEX.find_iter(html).any(|element| {
+        URL_ATTRIBUTE_REGEX
+            .captures_iter(element.as_str())
+            .filter_map(|caps| first_group(&caps))
+            .filter_map(|value| base.join(&decode_entities(value.trim())).ok())
+            .any(|url| without_fragment(url) == target)
+    })
+}
+
+/// Finds the endpoint in a `Link` header value such as
+/// `<https://example.com/webmention>; rel="webmention"`.
+fn endpoint_from_link_header(value: &str) -> Option<String> {
+    LINK_HEADER_REGEX
+        .captures_iter(value)
+        .find(|caps| capture(&REL_REGEX, &caps[2]).map(|rel| is_webmention_rel(&rel)).unwrap_or(false))
+        .map(|caps| caps[1].trim().to_string())
+}
+
+/// Finds the endpoint in the first `<link>` or `<a>` element with
+/// `rel="webmention"`.
+fn endpoint_from_html(html: &str) -> Option<String> {
+    TAG_REGEX
+        .find_iter(html)
+        .map(|tag| tag.as_str())
+        .find(|tag| capture(&REL_REGEX, tag).map(|rel| is_webmention_rel(&rel)).unwrap_or(false))
+        .and_then(|tag| capture(&HREF_REGEX, tag))
+        .map(|href| decode_entities(href.trim()))
+}
+
+fn read_body(res: &mut Response) -> Result<String> {
+    let mut body = vec![];
+    res.take(MAX_BODY_SIZE).read_to_end(&mut body)?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Looks up the webmention endpoint of `target`, first in its `Link`
+/// headers, then in its HTML. Relative endpoints are resolved against the
+/// URL the target was finally fetched from.
+pub fn discover_endpoint(client: &HttpClient, target: &str) -> Result<Option<Url>> {
+    let (base, mut res) = client.get(target, |req| req)?;
+    if !res.status().is_success() {
+        return Err(format!("{} answered with {}", target, res.status()).into());
+    }
+    let from_header = res.headers()
+        .iter()
+        .filter(|header| header.name().eq_ignore_ascii_case("link"))
+        .filter_map(|header| endpoint_from_link_header(&header.value_string()))
+        .next();
+    let endpoint = match from_header {
+        Some(endpoint) => Some(endpoint),
+        None => endpoint_from_html(&read_body(&mut res)?),
+    };
+    Ok(endpoint.and_then(|e| base.join(&e).ok()))
+}
+
+/// Notifies `target` that `source` links to it. Returns false if the
+/// target does not accept webmentions.
+pub fn send(client: &HttpClient, source: &str, target: &str) -> Result<bool> {
+    let endpoint = match discover_endpoint(client, target)? {
+        Some(endpoint) => endpoint,
+        None => return Ok(false),
+    };
+    let res = client.post(endpoint.as_str(), |req| req.form(&[("source", source), ("target", target)]))?;
+    if res.status().is_success() {
+        Ok(true)
+    } else {
+        Err(format!("{} answered with {}", endpoint, res.status()).into())
+    }
+}
+
+/// The outcome of fetching the source of a received webmention.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    /// The source links to the target. Carries the source's title.
+    Valid(Option<String>),
+    Invalid,
+    /// The source was deleted, so the mention should be too.
+    Gone,
+}
+
+pub fn verify(client: &HttpClient, source: &str, target: &str) -> Result<Verification> {
+    let (base, mut res) = client.get(source, |req| req)?;
+    if res.status().to_u16() == 410 {
+        return Ok(Verification::Gone);
+    }
+    if !res.status().is_success() {
+        return Ok(Verification::Invalid);
+    }
+    let body = read_body(&mut res)?;
+    if !links_to(&body, &base, target) {
+        return Ok(Verification::Invalid);
+    }
+    let title = capture(&TITLE_REGEX, &body)
+        .map(|t| decode_entities(t.trim()).chars().take(200).collect::<String>())
+        .and_then(|t| if t.is_empty() { None } else { Some(t) });
+    Ok(Verification::Valid(title))
+}
+
+/// Queues webmentions for the external links of a published post.
+/// `previous_content` is the post's HTML before an update: links that were
+/// removed are notified too, so their sites can drop the mention.
+pub fn queue_for_post(post: &Post, previous_content: Option<&str>, conn: &PgConnection) -> Result<()> {
+    if !post.published {
+        return Ok(());
+    }
+    let base = config::base_url();
+    let own_prefix = format!("{}/", base);
+    let mut targets = extract_links(&post.content);
+    if let Some(previous) = previous_content {
+        for link in extract_links(previous) {
+            if !targets.contains(&link) {
+                targets.push(link);
+            }
+        }
+    }
+    targets.retain(|t| *t != base && !t.starts_with(&own_prefix));
+    let queued = service::webmention::enqueue(post.id, &util::post_url(post.id), &targets, conn)?;
+    if queued > 0 {
+        info!("Queued {} webmentions for post {}", queued, post.id);
+    }
+    Ok(())
+}
+
+/// Verifies received mentions and sends queued ones.
+fn process(conn: &PgConnection) -> Result<()> {
+    let client = HttpClient::new()?;
+    for mention in service::webmention::find_pending(BATCH_SIZE, conn)? {
+        match verify(&client, &mention.source, &mention.target) {
+            Ok(Verification::Valid(title)) => {
+                info!("Verified webmention from {} for post {}", mention.source, mention.post_id);
+                service::webmention::set_verified(mention.id, title, conn)?;
+            }
+            Ok(Verification::Invalid) => {
+                info!("Rejected webmention from {}: no link to {}", mention.source, mention.target);
+                service::webmention::set_rejected(mention.id, conn)?;
+            }
+            Ok(Verification::Gone) => {
+                info!("Deleting webmention from {}, the source is gone", mention.source);
+                service::webmention::delete(mention.id, conn)?;
+            }
+            Err(Error(ErrorKind::ForbiddenAddress(_), _)) => {
+                info!("Rejected webmention from {}: not a public address", mention.source);
+                service::webmention::set_rejected(mention.id, conn)?;
+            }
+            Err(e) => {
+                warn!("Could not verify webmention from {}: {}", mention.source, e);
+                service::webmention::retry_verification(&mention, &e.to_string(), MAX_ATTEMPTS, conn)?;
+            }
+        }
+    }
+    for mention in service::webmention::find_due(BATCH_SIZE, conn)? {
+        match send(&client, &mention.source, &mention.target) {
+            Ok(true) => {
+                info!("Sent webmention for {} to {}", mention.source, mention.target);
+                service::webmention::finish(mention.id, "sent", conn)?;
+            }
+            Ok(false) => {
+                debug!("{} does not accept webmentions", mention.target);
+                service::webmention::finish(mention.id, "unsupported", conn)?;
+            }
+            Err(e) => {
+                warn!("Error sending webmention to {}: {}", mention.target, e);
+                service::webmention::mark_failed(&mention, &e.to_string(), MAX_ATTEMPTS, conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts a background thread that verifies received webmentions and sends
+/// queued ones every `interval`, so neither side of a webmention has to
+/// wait for the other's server during a request.
+pub fn start(pool: Pool, interval: Duration) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("webmention".into())
+        .spawn(move || loop {
+                   match pool.get() {
+                       Ok(conn) => {
+                           if let Err(e) = process(&conn) {
+                               warn!("Error processing webmentions: {}", e);
+                           }
+                       }
+                       Err(e) => warn!("Webmention worker could not get a database connection: {}", e),
+                   }
+                   thread::sleep(interval);
+               })
+        .expect("Failed to start the webmention thread")
+}
+
+fn bad_request(message: &'static str) -> Result<status::Custom<&'static str>> {
+    Ok(status::Custom(Status::BadRequest, message))
+}
+
+/// The webmention endpoint. Only checks the request, the source is fetched
+/// later by the background thread.
+#[post("/webmention", data = "<data>")]
+pub fn receive(data: Form<WebmentionRequest>, conn: Connection) -> Result<status::Custom<&'static str>> {
+    let request = data.into_inner();
+    if !is_http_url(&request.source) || !is_http_url(&request.target) {
+        return bad_request("Source and target must be http or https URLs.");
+    }
+    if request.source == request.target {
+        return bad_request("Source and target must be different.");
+    }
+    let post_id = match util::post_id_from_url(&request.target) {
+        Some(id) => id,
+        None => return bad_request("The target is not a post on this site."),
+    };
+    match service::post::find_one(post_id, &conn)? {
+        Some(ref post) if post.published => {}
+        _ => return bad_request("The target is not a post on this site."),
+    }
+    let mention = NewWebmention {
+        post_id,
+        source: request.source,
+        target: request.target,
+    };
+    service::webmention::receive(&mention, &conn)?;
+    Ok(status::Custom(Status::Accepted, "Accepted, the source will be verified shortly."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{Canned, TestServer};
+
+    const TARGET: &str = "https://blog.example.com/posts/1";
+
+    fn client() -> HttpClient {
+        HttpClient::allowing_private().unwrap()
+    }
+
+    #[test]
+    fn verify_finds_link_and_title() {
+        let server = TestServer::start();
+        server.route("/source",
+                     Canned::new("200 OK",
+                                 &format!("<html><title>A reply &amp; more</title>\
+                                           <a href=\"{}\">post</a></html>",
+                                          TARGET)));
+        let result = verify(&client(), &server.url("/source"), TARGET).unwrap();
+        assert_eq!(result, Verification::Valid(Some("A reply & more".to_string())));
+    }
+
+    #[test]
+    fn verify_rejects_source_without_link() {
+        let server = TestServer::start();
+        server.route("/source", Canned::new("200 OK", "<p>Nothing to see</p>"));
+        let result = verify(&client(), &server.url("/source"), TARGET).unwrap();
+        assert_eq!(result, Verification::Invalid);
+    }
+
+    #[test]
+    fn verify_rejects_links_that_only_start_with_the_target() {
+        let server = TestServer::start();
+        server.route("/source",
+                     Canned::new("200 OK",
+                                 &format!("<p>{}</p><a href=\"{}0\">other post</a>", TARGET, TARGET)));
+        let result = verify(&client(), &server.url("/source"), TARGET).unwrap();
+        assert_eq!(result, Verification::Invalid);
+    }
+
+    #[test]
+    fn verify_resolves_relative_links() {
+        let server = TestServer::start();
+        server.route("/replies/1",
+                     Canned::new("200 OK", "<img src='../posts/1'><a href=\"/posts/1#comments\">post</a>"));
+        let target = server.url("/posts/1");
+        let result = verify(&client(), &server.url("/replies/1"), &target).unwrap();
+        assert_eq!(result, Verification::Valid(None));
+        let result = verify(&client(), &server.url("/replies/1"), &server.url("/posts")).unwrap();
+        assert_eq!(result, Verification::Invalid);
+    }
+
+    #[test]
+    fn verify_reports_deleted_source() {
+        let server = TestServer::start();
+        server.route("/source", Canned::new("410 Gone", ""));
+        let result = verify(&client(), &server.url("/source"), TARGET).unwrap();
+        assert_eq!(result, Verification::Gone);
+    }
+
+    #[test]
+    fn verify_refuses_private_addresses() {
+        let server = TestServer::start();
+        server.route("/source", Canned::new("200 OK", TARGET));
+        let client = HttpClient::new().unwrap();
+        match verify(&client, &server.url("/source"), TARGET) {
+            Err(Error(ErrorKind::ForbiddenAddress(_), _)) => {}
+            other => panic!("expected ForbiddenAddress, got {:?}", other),
+        }
+        assert!(server.received().is_empty());
+    }
+
+    #[test]
+    fn endpoint_is_discovered_from_link_header() {
+        let server = TestServer::start();
+        server.route("/post",
+                     Canned::new("200 OK", "<link rel=\"webmention\" href=\"/ignored\">")
+                         .header("Link: </mentions?from=header>; rel=\"webmention\""));
+        let endpoint = discover_endpoint(&client(), &server.url("/post")).unwrap();
+        assert_eq!(endpoint.map(|e| e.to_string()),
+                   Some(server.url("/mentions?from=header")));
+    }
+
+    #[test]
+    fn endpoint_is_discovered_from_html() {
+        let server = TestServer::start();
+        server.route("/post",
+                     Canned::new("200 OK", "<head><link href=\"mentions\" rel=\"webmention\"></head>"));
+        let endpoint = discover_endpoint(&client(), &server.url("/post")).unwrap();
+        assert_eq!(endpoint.map(|e| e.to_string()), Some(server.url("/mentions")));
+    }
+
+    #[test]
+    fn send_posts_source_and_target() {
+        let server = TestServer::start();
+        let target = server.url("/post");
+        server.route("/post",
+                     Canned::new("200 OK", "").header("Link: </mentions>; rel=webmention"));
+        server.route("/mentions", Canned::new("202 Accepted", ""));
+        assert!(send(&client(), "https://blog.example.com/posts/1", &target).unwrap());
+        let sent = server
+            .received()
+            .into_iter()
+            .find(|r| r.path == "/mentions")
+            .unwrap();
+        assert_eq!(sent.method, "POST");
+        assert!(sent.body.contains("source=https%3A%2F%2Fblog.example.com%2Fposts%2F1"));
+    }
+
+    #[test]
+    fn send_without_endpoint_is_unsupported() {
+        let server = TestServer::start();
+        server.route("/post", Canned::new("200 OK", "<p>No endpoint</p>"));
+        assert!(!send(&client(), "https://blog.example.com/posts/1", &server.url("/post")).unwrap());
+    }
+}
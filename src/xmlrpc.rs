@@ -0,0 +1,675 @@
+use std::cmp;
+use std::io::Read;
+
+use base64;
+use chrono::{DateTime, NaiveDateTime, UTC};
+use diesel::pg::PgConnection;
+use rocket::{Data, State};
+use rocket::response::content;
+use xml::reader::{EventReader, XmlEvent};
+
+use activitypub;
+use config;
+use db_util::Connection;
+use errors::*;
+use media::{self, MediaLibrary, Upload};
+use model::{self, CreatePostRequest, Post, User, MAX_TITLE_LENGTH};
+use publisher;
+use service;
+use throttle::Throttle;
+use util;
+
+/// Larger requests are rejected. Leaves room for base64 encoded uploads.
+const MAX_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Values nested deeper than this are rejected before they are parsed,
+/// parsing them recurses and could overflow the stack.
+const MAX_VALUE_DEPTH: usize = 32;
+
+/// The element depth that allows: `methodCall`, `params` and `param`, three
+/// elements per value, as in `value`, `array`, `data`, and the innermost
+/// value's type.
+const MAX_DEPTH: usize = 3 + 3 * MAX_VALUE_DEPTH + 1;
+
+/// `getRecentPosts` returns at most this many posts.
+const MAX_RECENT_POSTS: usize = 100;
+
+/// An XML-RPC value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Bool(bool),
+    String(String),
+    Double(f64),
+    DateTime(DateTime<UTC>),
+    Base64(Vec<u8>),
+    Struct(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Nil,
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Integers are also accepted as strings, which is how most clients
+    /// send post ids.
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            Value::Int(i) => Some(i),
+            Value::String(ref s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            Value::Int(i) => Some(i != 0),
+            _ => None,
+        }
+    }
+
+    /// Looks up a member of a struct.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Struct(ref members) => members.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v),
+            _ => None,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push_str("<value>");
+        match *self {
+            Value::Int(i) => out.push_str(&format!("<int>{}</int>", i)),
+            Value::Bool(b) => out.push_str(if b { "<boolean>1</boolean>" } else { "<boolean>0</boolean>" }),
+            Value::String(ref s) => out.push_str(&format!("<string>{}</string>", util::escape_html(s))),
+            Value::Double(d) => out.push_str(&format!("<double>{}</double>", d)),
+            Value::DateTime(ref date) => {
+                out.push_str(&format!("<dateTime.iso8601>{}</dateTime.iso8601>",
+                                      date.format("%Y%m%dT%H:%M:%S")))
+            }
+            Value::Base64(ref data) => out.push_str(&format!("<base64>{}</base64>", base64::encode(data))),
+            Value::Struct(ref members) => {
+                out.push_str("<struct>");
+                for &(ref name, ref value) in members {
+                    out.push_str(&format!("<member><name>{}</name>", util::escape_html(name)));
+                    value.write(out);
+                    out.push_str("</member>");
+                }
+                out.push_str("</struct>");
+            }
+            Value::Array(ref values) => {
+                out.push_str("<array><data>");
+                for value in values {
+                    value.write(out);
+                }
+                out.push_str("</data></array>");
+            }
+            Value::Nil => out.push_str("<nil/>"),
+        }
+        out.push_str("</value>");
+    }
+}
+
+/// An error reported to the client as an XML-RPC fault.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Fault {
+    fn new<S: Into<String>>(code: i32, message: S) -> Fault {
+        Fault {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params<S: Into<String>>(message: S) -> Fault {
+        Fault::new(-32602, message)
+    }
+}
+
+type CallResult = ::std::result::Result<Value, Fault>;
+
+/// A parsed XML element, with its text content. Mixed content is not used
+/// by XML-RPC, so text and child elements are kept apart.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+}
+
+fn parse_document(body: &[u8]) -> Result<Element> {
+    let mut stack: Vec<Element> = vec![];
+    for event in EventReader::new(body) {
+        match event.map_err(|e| Error::from(e.to_string()))? {
+            XmlEvent::StartElement { name, .. } => {
+                if stack.len() >= MAX_DEPTH {
+                    return Err("The call is nested too deeply".into());
+                }
+                stack.push(Element {
+                               name: name.local_name,
+                               text: String::new(),
+                               children: vec![],
+                           })
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().ok_or("Unbalanced XML")?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            XmlEvent::Characters(text) |
+            XmlEvent::CData(text) |
+            XmlEvent::Whitespace(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("Unexpected end of XML".into())
+}
+
+/// Accepts the basic ISO 8601 format from the spec as well as the extended
+/// formats some clients send instead.
+fn parse_date(text: &str) -> Option<DateTime<UTC>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(date.with_timezone(&UTC));
+    }
+    let text = text.trim_right_matches('Z');
+    ["%Y%m%dT%H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y%m%dT%H%M%S"]
+        .iter()
+        .filter_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .next()
+        .map(|naive| DateTime::from_utc(naive, UTC))
+}
+
+fn parse_value(element: &Element) -> Result<Value> {
+    // A value without a type element is a string.
+    let typed = match element.children.first() {
+        Some(typed) => typed,
+        None => return Ok(Value::String(element.text.clone())),
+    };
+    let text = typed.text.trim();
+    match typed.name.as_str() {
+        "i4" | "int" => {
+            text.parse()
+                .map(Value::Int)
+                .map_err(|_| format!("Invalid integer {:?}", text).into())
+        }
+        "boolean" => {
+            match text {
+                "1" => Ok(Value::Bool(true)),
+                "0" => Ok(Value::Bool(false)),
+                _ => Err(format!("Invalid boolean {:?}", text).into()),
+            }
+        }
+        "string" => Ok(Value::String(typed.text.clone())),
+        "double" => {
+            text.parse()
+                .map(Value::Double)
+                .map_err(|_| format!("Invalid double {:?}", text).into())
+        }
+        "dateTime.iso8601" => {
+            parse_date(text)
+                .map(Value::DateTime)
+                .ok_or_else(|| format!("Invalid date {:?}", text).into())
+        }
+        "base64" => {
+            let encoded: String = text.split_whitespace().collect();
+            base64::decode(&encoded)
+                .map(Value::Base64)
+                .map_err(|_| "Invalid base64 data".into())
+        }
+        "struct" => {
+            let mut members = vec![];
+            for member in typed.children.iter().filter(|c| c.name == "member") {
+                let name = member.child("name").ok_or("A struct member has no name")?;
+                let value = member.child("value").ok_or("A struct member has no value")?;
+                members.push((name.text.trim().to_string(), parse_value(value)?));
+            }
+            Ok(Value::Struct(members))
+        }
+        "array" => {
+            let mut values = vec![];
+            if let Some(data) = typed.child("data") {
+                for value in data.children.iter().filter(|c| c.name == "value") {
+                    values.push(parse_value(value)?);
+                }
+            }
+            Ok(Value::Array(values))
+        }
+        "nil" => Ok(Value::Nil),
+        other => Err(format!("Unknown type {}", other).into()),
+    }
+}
+
+/// Parses a `methodCall` into the method name and its parameters.
+pub fn parse_call(body: &[u8]) -> Result<(String, Vec<Value>)> {
+    let root = parse_document(body)?;
+    if root.name != "methodCall" {
+        return Err("Expected a methodCall".into());
+    }
+    let method = root.child("methodName")
+        .map(|m| m.text.trim().to_string())
+        .ok_or("The call has no methodName")?;
+    let mut params = vec![];
+    if let Some(list) = root.child("params") {
+        for param in list.children.iter().filter(|c| c.name == "param") {
+            let value = param.child("value").ok_or("A param has no value")?;
+            params.push(parse_value(value)?);
+        }
+    }
+    Ok((method, params))
+}
+
+pub fn response(value: &Value) -> String {
+    let mut out = String::from("<?xml version=\"1.0\"?>\n<methodResponse><params><param>");
+    value.write(&mut out);
+    out.push_str("</param></params></methodResponse>\n");
+    out
+}
+
+pub fn fault(fault: &Fault) -> String {
+    let mut out = String::from("<?xml version=\"1.0\"?>\n<methodResponse><fault>");
+    Value::Struct(vec![("faultCode".into(), Value::Int(fault.code)),
+                       ("faultString".into(), Value::String(fault.message.clone()))])
+            .write(&mut out);
+    out.push_str("</fault></methodResponse>\n");
+    out
+}
+
+fn param(params: &[Value], index: usize) -> ::std::result::Result<&Value, Fault> {
+    params.get(index).ok_or_else(|| Fault::invalid_params(format!("Parameter {} is missing.", index + 1)))
+}
+
+fn string_param(params: &[Value], index: usize) -> ::std::result::Result<&str, Fault> {
+    param(params, index)?
+        .as_str()
+        .ok_or_else(|| Fault::invalid_params(format!("Parameter {} must be a string.", index + 1)))
+}
+
+fn post_id_param(params: &[Value], index: usize) -> ::std::result::Result<i32, Fault> {
+    param(params, index)?
+        .as_i32()
+        .ok_or_else(|| Fault::invalid_params("Invalid post id."))
+}
+
+/// Checks the user name and password at `params[index]` and
+/// `params[index + 1]`. Failures count towards the login throttle.
+fn login(params: &[Value],
+         index: usize,
+         throttle: &Throttle,
+         conn: &PgConnection)
+         -> Result<::std::result::Result<User, Fault>> {
+    let (name, password) = match (string_param(params, index), string_param(params, index + 1)) {
+        (Ok(name), Ok(password)) => (name, password),
+        (Err(fault), _) | (_, Err(fault)) => return Ok(Err(fault)),
+    };
+    if throttle.blocked_until(name)?.is_some() {
+        return Ok(Err(Fault::new(429, "Too many failed logins, try again later.")));
+    }
+    match service::user::find_by_name(name, conn)? {
+        Some(ref user) if user.verify_password(password) => {
+            throttle.success(name)?;
+            Ok(Ok(user.clone()))
+        }
+        _ => {
+            throttle.failure(name, conn)?;
+            Ok(Err(Fault::new(403, "Invalid username/password.")))
+        }
+    }
+}
+
+/// Builds a post from a MetaWeblog post struct. Fields the struct leaves
+/// out are taken from `existing`, if given.
+fn post_request(content: &Value,
+                publish: bool,
+                existing: Option<&Post>)
+                -> ::std::result::Result<CreatePostRequest, Fault> {
+    let field = |name: &str| content.get(name).and_then(Value::as_str);
+    let title = match field("title") {
+        Some(title) => title.trim().to_string(),
+        None => existing.map(|p| p.title.clone()).unwrap_or_default(),
+    };
+    let mut markdown_content = match field("description") {
+        Some(description) => description.to_string(),
+        None => existing.map(|p| p.markdown_content.clone()).unwrap_or_default(),
+    };
+    if let Some(more) = field("mt_text_more") {
+        if !more.trim().is_empty() {
+            markdown_content = format!("{}\n\n{}", markdown_content, more);
+        }
+    }
+    if title.is_empty() {
+        return Err(Fault::invalid_params("The title must not be empty."));
+    }
+    if title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(Fault::invalid_params(format!("The title must not be longer than {} characters.",
+                                                 MAX_TITLE_LENGTH)));
+    }
+    if markdown_content.trim().is_empty() {
+        return Err(Fault::invalid_params("The post must have some content."));
+    }
+
+    // Categories can be several words, but tags are single words.
+    let mut categories: Vec<String> = vec![];
+    if let Some(&Value::Array(ref values)) = content.get("categories") {
+        categories.extend(values.iter().filter_map(Value::as_str).map(String::from));
+    }
+    if let Some(keywords) = field("mt_keywords") {
+        categories.extend(keywords.split(',').map(String::from));
+    }
+    let tags = if content.get("categories").is_some() || content.get("mt_keywords").is_some() {
+        let words: Vec<String> = categories
+            .iter()
+            .map(|c| c.split_whitespace().collect::<Vec<_>>().join("-"))
+            .collect();
+        model::normalize_tags(&words.join(" "))
+    } else {
+        existing.map(|p| p.tags.clone()).unwrap_or_default()
+    };
+    let created_on = match content.get("dateCreated") {
+        Some(&Value::DateTime(date)) => date,
+        _ => existing.map(|p| p.created_on).unwrap_or_else(UTC::now),
+    };
+    Ok(CreatePostRequest {
+           title,
+           markdown_content,
+           content: None,
+           tags,
+           created_on,
+           published: publish,
+           publish_at: if publish { None } else { existing.and_then(|p| p.publish_at) },
+           language: existing.map(|p| p.language.clone()).unwrap_or_else(model::default_language),
+       })
+}
+
+/// A post as a MetaWeblog post struct. The description is the Markdown
+/// source, so that editing a post round-trips.
+fn post_struct(post: &Post) -> Value {
+    Value::Struct(vec![("postid".into(), Value::String(post.id.to_string())),
+                       ("userid".into(), Value::String(post.owner_id.to_string())),
+                       ("title".into(), Value::String(post.title.clone())),
+                       ("description".into(), Value::String(post.markdown_content.clone())),
+                       ("categories".into(),
+                        Value::Array(post.tags.iter().cloned().map(Value::String).collect())),
+                       ("mt_keywords".into(), Value::String(post.tags.join(", "))),
+                       ("dateCreated".into(), Value::DateTime(post.created_on)),
+                       ("link".into(), Value::String(util::post_url(post.id))),
+                       ("permaLink".into(), Value::String(util::post_url(post.id))),
+                       ("post_status".into(), Value::String(post.status().into()))])
+}
+
+fn find_post(post_id: i32, user: &User, conn: &PgConnection) -> Result<::std::result::Result<Post, Fault>> {
+    match service::post::find_owned(post_id, user.id, conn)? {
+        Some(post) => Ok(Ok(post)),
+        None => Ok(Err(Fault::new(404, "There is no such post."))),
+    }
+}
+
+/// Unwraps the `Ok` of a nested result, or returns the fault as the result
+/// of the call.
+macro_rules! try_fault {
+    ($e:expr) => (match $e {
+        Ok(value) => value,
+        Err(fault) => return Ok(Err(fault)),
+    })
+}
+
+/// `blogger.getUsersBlogs(appkey, username, password)`. Every user has one
+/// blog, identified by their user id.
+fn get_users_blogs(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    Ok(Ok(Value::Array(vec![Value::Struct(vec![("blogid".into(), Value::String(user.id.to_string())),
+                                               ("blogName".into(), Value::String(user.name.clone())),
+                                               ("url".into(),
+                                                Value::String(format!("{}/user/{}",
+                                                                      config::base_url(),
+                                                                      user.id))),
+                                               ("isAdmin".into(), Value::Bool(user.is_admin))])])))
+}
+
+/// `metaWeblog.newPost(blogid, username, password, struct, publish)`.
+fn new_post(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    let content = try_fault!(param(params, 3));
+    let publish = params.get(4).and_then(Value::as_bool).unwrap_or(true);
+    let mut request = try_fault!(post_request(content, publish, None));
+    request.convert_markdown();
+    let post = service::post::insert_post(request, &user, conn)?;
+    publisher::announce(&post, None, conn)?;
+    info!("Created post {} via XML-RPC", post.id);
+    Ok(Ok(Value::String(post.id.to_string())))
+}
+
+/// `metaWeblog.editPost(postid, username, password, struct, publish)`.
+fn edit_post(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    let post_id = try_fault!(post_id_param(params, 0));
+    let mut post = try_fault!(find_post(post_id, &user, conn)?);
+    let content = try_fault!(param(params, 3));
+    let publish = params.get(4).and_then(Value::as_bool).unwrap_or(post.published);
+    let mut changes = try_fault!(post_request(content, publish, Some(&post)));
+    changes.convert_markdown();
+    let previous_content = if post.published { Some(post.content.clone()) } else { None };
+    changes.apply_to(&mut post);
+    let post = service::post::update_post(&post, user.id, conn)?;
+    publisher::announce(&post, previous_content.as_ref().map(|s| s.as_str()), conn)?;
+    info!("Updated post {} via XML-RPC", post.id);
+    Ok(Ok(Value::Bool(true)))
+}
+
+/// `metaWeblog.getPost(postid, username, password)`.
+fn get_post(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    let post_id = try_fault!(post_id_param(params, 0));
+    let post = try_fault!(find_post(post_id, &user, conn)?);
+    Ok(Ok(post_struct(&post)))
+}
+
+/// `metaWeblog.getRecentPosts(blogid, username, password, numberOfPosts)`.
+/// Includes drafts and scheduled posts, newest first.
+fn get_recent_posts(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    let count = params.get(3).and_then(Value::as_i32).unwrap_or(10);
+    let count = cmp::min(cmp::max(count, 0) as usize, MAX_RECENT_POSTS);
+    let posts = service::post::find_all_by_owner(user.id, conn)?;
+    Ok(Ok(Value::Array(posts.iter().take(count).map(post_struct).collect())))
+}
+
+/// `metaWeblog.newMediaObject(blogid, username, password, struct)`, where
+/// the struct has the file's `name`, `type` and `bits`.
+fn new_media_object(params: &[Value],
+                    throttle: &Throttle,
+                    library: &MediaLibrary,
+                    conn: &PgConnection)
+                    -> Result<CallResult> {
+    let user = try_fault!(login(params, 1, throttle, conn)?);
+    let file = try_fault!(param(params, 3));
+    let data = match file.get("bits") {
+        Some(&Value::Base64(ref data)) => data.clone(),
+        _ => return Ok(Err(Fault::invalid_params("The file has no bits."))),
+    };
+    if data.len() as u64 > media::MAX_UPLOAD_SIZE {
+        return Ok(Err(Fault::invalid_params(format!("Uploads are limited to {} MB.",
+                                                    media::MAX_UPLOAD_SIZE / 1024 / 1024))));
+    }
+    if let Some(message) = media::upload_error(&data) {
+        return Ok(Err(Fault::invalid_params(message)));
+    }
+    let upload = Upload {
+        filename: file.get("name").and_then(Value::as_str).map(String::from),
+        data,
+    };
+    let file = library.store(user.id, &upload, conn)?;
+    info!("User {} uploaded {} via XML-RPC", user.id, file.name);
+    Ok(Ok(Value::Struct(vec![("id".into(), Value::String(file.id.to_string())),
+                             ("file".into(), Value::String(file.name.clone())),
+                             ("url".into(), Value::String(util::media_url(&file.name))),
+                             ("type".into(), Value::String(file.content_type.clone()))])))
+}
+
+/// `blogger.deletePost(appkey, postid, username, password, publish)`.
+fn delete_post(params: &[Value], throttle: &Throttle, conn: &PgConnection) -> Result<CallResult> {
+    let user = try_fault!(login(params, 2, throttle, conn)?);
+    let post_id = try_fault!(post_id_param(params, 1));
+    let post = try_fault!(find_post(post_id, &user, conn)?);
+    activitypub::queue_delete(&post, conn)?;
+    service::post::delete(post.id, conn)?;
+    service::audit::record("post_deleted", &user.name, &format!("{} via XML-RPC", post.id), conn)?;
+    Ok(Ok(Value::Bool(true)))
+}
+
+fn call(method: &str,
+        params: &[Value],
+        throttle: &Throttle,
+        library: &MediaLibrary,
+        conn: &PgConnection)
+        -> Result<CallResult> {
+    match method {
+        "blogger.getUsersBlogs" => get_users_blogs(params, throttle, conn),
+        "metaWeblog.newPost" => new_post(params, throttle, conn),
+        "metaWeblog.editPost" => edit_post(params, throttle, conn),
+        "metaWeblog.getPost" => get_post(params, throttle, conn),
+        "metaWeblog.getRecentPosts" => get_recent_posts(params, throttle, conn),
+        "metaWeblog.newMediaObject" => new_media_object(params, throttle, library, conn),
+        "blogger.deletePost" => delete_post(params, throttle, conn),
+        _ => Ok(Err(Fault::new(-32601, format!("Unknown method {}.", method)))),
+    }
+}
+
+/// The XML-RPC endpoint for desktop blogging clients. Errors are reported
+/// as faults, so this always answers with `200 OK`.
+#[post("/xmlrpc", data = "<data>")]
+pub fn endpoint(data: Data,
+                throttle: Throttle,
+                library: State<MediaLibrary>,
+                conn: Connection)
+                -> content::Xml<String> {
+    let mut body = vec![];
+    if let Err(e) = data.open().take(MAX_REQUEST_SIZE).read_to_end(&mut body) {
+        warn!("Error reading XML-RPC request: {}", e);
+        return content::Xml(fault(&Fault::new(-32700, "Could not read the request.")));
+    }
+    let (method, params) = match parse_call(&body) {
+        Ok(call) => call,
+        Err(e) => return content::Xml(fault(&Fault::new(-32700, format!("Invalid request: {}", e)))),
+    };
+    debug!("XML-RPC call to {}", method);
+    match call(&method, &params, &throttle, &library, &conn) {
+        Ok(Ok(value)) => content::Xml(response(&value)),
+        Ok(Err(f)) => content::Xml(fault(&f)),
+        Err(e) => {
+            warn!("Error handling XML-RPC call to {}: {}", method, e);
+            content::Xml(fault(&Fault::new(500, "Internal error.")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn call_with(value: &str) -> String {
+        format!("<?xml version=\"1.0\"?><methodCall><methodName>test.echo</methodName>\
+                 <params><param><value>{}</value></param></params></methodCall>",
+                value)
+    }
+
+    /// `depth` values, each an array holding the next, around an int.
+    fn nested(depth: usize) -> String {
+        let mut value = "<int>1</int>".to_string();
+        for _ in 1..depth {
+            value = format!("<array><data><value>{}</value></data></array>", value);
+        }
+        call_with(&value)
+    }
+
+    #[test]
+    fn parses_method_and_params() {
+        let body = "<methodCall><methodName> metaWeblog.getPost </methodName><params>\
+                    <param><value><string>12</string></value></param>\
+                    <param><value>plain</value></param>\
+                    <param><value><boolean>1</boolean></value></param>\
+                    </params></methodCall>";
+        let (method, params) = parse_call(body.as_bytes()).unwrap();
+        assert_eq!(method, "metaWeblog.getPost");
+        assert_eq!(params,
+                   vec![Value::String("12".into()), Value::String("plain".into()), Value::Bool(true)]);
+        assert_eq!(params[0].as_i32(), Some(12));
+    }
+
+    #[test]
+    fn parses_structs_and_arrays() {
+        let body = call_with("<struct><member><name>title</name><value><string>Hi</string></value></member>\
+                              <member><name>categories</name><value><array><data>\
+                              <value><string>rust</string></value><value><i4>3</i4></value>\
+                              </data></array></value></member></struct>");
+        let (_, params) = parse_call(body.as_bytes()).unwrap();
+        assert_eq!(params[0].get("title"), Some(&Value::String("Hi".into())));
+        assert_eq!(params[0].get("categories"),
+                   Some(&Value::Array(vec![Value::String("rust".into()), Value::Int(3)])));
+    }
+
+    #[test]
+    fn parses_dates_and_base64() {
+        let body = call_with("<array><data><value><dateTime.iso8601>20171001T10:20:30</dateTime.iso8601></value>\
+                              <value><base64>aGVs\nbG8=</base64></value></data></array>");
+        let (_, params) = parse_call(body.as_bytes()).unwrap();
+        assert_eq!(params[0],
+                   Value::Array(vec![Value::DateTime(UTC.ymd(2017, 10, 1).and_hms(10, 20, 30)),
+                                     Value::Base64(b"hello".to_vec())]));
+    }
+
+    #[test]
+    fn rejects_invalid_calls() {
+        assert!(parse_call(b"<methodCall><methodName>x</methodName>").is_err());
+        assert!(parse_call(b"<methodResponse></methodResponse>").is_err());
+        assert!(parse_call(b"<methodCall></methodCall>").is_err());
+        assert!(parse_call(call_with("<int>one</int>").as_bytes()).is_err());
+        assert!(parse_call(call_with("<struct><member><value>1</value></member></struct>").as_bytes()).is_err());
+        assert!(parse_call(b"not xml").is_err());
+    }
+
+    #[test]
+    fn accepts_values_nested_up_to_the_limit() {
+        assert!(parse_call(nested(MAX_VALUE_DEPTH).as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn rejects_values_nested_too_deeply() {
+        assert!(parse_call(nested(MAX_VALUE_DEPTH + 1).as_bytes()).is_err());
+        // Deep enough to overflow the stack if it were parsed recursively.
+        assert!(parse_call(nested(100000).as_bytes()).is_err());
+        let elements = format!("{}{}", "<a>".repeat(100000), "</a>".repeat(100000));
+        assert!(parse_call(elements.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn responses_escape_strings() {
+        let out = response(&Value::Struct(vec![("a<b".into(), Value::String("x & y".into()))]));
+        assert!(out.contains("<member><name>a&lt;b</name><value><string>x &amp; y</string></value></member>"));
+        let out = fault(&Fault::new(403, "No"));
+        assert!(out.contains("<name>faultCode</name><value><int>403</int></value>"));
+    }
+}